@@ -1,3 +1,14 @@
+use std::{
+    fmt::Write as _,
+    fs,
+    io::Write as _,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use flate2::{write::GzEncoder, Compression};
+
 #[toml_cfg::toml_config]
 pub struct Config {
     #[default("")]
@@ -7,9 +18,17 @@ pub struct Config {
 }
 
 fn main() -> anyhow::Result<()> {
- // Check if the `cfg.toml` file exists and has been filled out.
+    embed_static()?;
+    embed_build_info()?;
+
+    // The host backend has no Wi-Fi and doesn't link against ESP-IDF.
+    if std::env::var_os("CARGO_FEATURE_ESP").is_none() {
+        return Ok(());
+    }
+
+    // Without a `cfg.toml`, Wi-Fi is set up at runtime through the provisioning access point.
     if !std::path::Path::new("cfg.toml").exists() {
-        anyhow::bail!("You need to create a `cfg.toml` file with your Wi-Fi credentials! Use `cfg.toml.example` as a template.");
+        println!("cargo:warning=No `cfg.toml` found, the device will start in Wi-Fi provisioning mode. Use `cfg.toml.example` as a template to bake in credentials.");
     }
 
     // The constant `CONFIG` is auto-generated by `toml_config`.
@@ -22,3 +41,153 @@ fn main() -> anyhow::Result<()> {
     embuild::build::CfgArgs::output_propagated("ESP_IDF")?;
     embuild::build::LinkArgs::output_propagated("ESP_IDF")
 }
+
+/// Embeds the files in `static/` by generating `$OUT_DIR/assets.rs`, a table of
+/// `crate::assets::Asset`s. Each file is also gzipped, and the compressed copy
+/// is embedded too if it is any smaller.
+fn embed_static() -> anyhow::Result<()> {
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR")?);
+    println!("cargo:rerun-if-changed=static");
+    println!("cargo:rerun-if-changed=cfg.toml");
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let mut files = Vec::new();
+    if Path::new("static").exists() {
+        collect_files(Path::new("static"), &mut files)?;
+    }
+    files.sort();
+
+    let mut table = String::from("&[\n");
+    for (i, file) in files.iter().enumerate() {
+        let path = file
+            .strip_prefix("static")?
+            .iter()
+            .map(|part| part.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        let contents = fs::read(file)?;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(&contents)?;
+        let compressed = encoder.finish()?;
+        let gzip = if compressed.len() < contents.len() {
+            let gz_path = out_dir.join(format!("asset-{i}.gz"));
+            fs::write(&gz_path, compressed)?;
+            format!("Some(include_bytes!({:?}))", gz_path)
+        } else {
+            "None".to_owned()
+        };
+
+        writeln!(
+            table,
+            "    crate::assets::Asset {{ path: {:?}, content_type: {:?}, hash: \"{:016x}\", body: include_bytes!({:?}), gzip: {} }},",
+            path,
+            content_type(&path),
+            fnv1a(&contents),
+            fs::canonicalize(file)?,
+            gzip,
+        )?;
+    }
+    table.push(']');
+
+    fs::write(out_dir.join("assets.rs"), table)?;
+    Ok(())
+}
+
+/// Sets `GIT_HASH` to the commit being built, with `-dirty` if there are
+/// uncommitted changes, and `BUILD_TIME` to now, or to `SOURCE_DATE_EPOCH` for
+/// reproducible builds.
+fn embed_build_info() -> anyhow::Result<()> {
+    let git = |args: &[&str]| {
+        let output = Command::new("git").args(args).output().ok()?;
+        let stdout = String::from_utf8(output.stdout).ok()?;
+        output.status.success().then(|| stdout.trim().to_owned())
+    };
+
+    let hash = match git(&["rev-parse", "--short=10", "HEAD"]) {
+        Some(hash) if git(&["status", "--porcelain"]).is_some_and(|s| !s.is_empty()) => {
+            format!("{hash}-dirty")
+        }
+        Some(hash) => hash,
+        None => "unknown".to_owned(),
+    };
+    println!("cargo:rustc-env=GIT_HASH={hash}");
+    // Run again after a commit or checkout.
+    for path in ["HEAD", "index"] {
+        if let Some(path) = git(&["rev-parse", "--git-path", path]) {
+            println!("cargo:rerun-if-changed={path}");
+        }
+    }
+    if let Some(path) = git(&["symbolic-ref", "-q", "HEAD"])
+        .and_then(|branch| git(&["rev-parse", "--git-path", &branch]))
+    {
+        println!("cargo:rerun-if-changed={path}");
+    }
+
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let secs = match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(secs) => secs.parse()?,
+        Err(_) => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    };
+    println!("cargo:rustc-env=BUILD_TIME={}", rfc3339(secs));
+    Ok(())
+}
+
+/// Formats seconds since the Unix epoch as an RFC 3339 UTC timestamp.
+fn rfc3339(secs: u64) -> String {
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // From Howard Hinnant's `civil_from_days`.
+    let z = days + 719468;
+    let (era, doe) = (z / 146097, z % 146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn content_type(path: &str) -> &'static str {
+    match path.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("css") => "text/css; charset=utf-8",
+        Some("html") => "text/html; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff2") => "font/woff2",
+        Some("wasm") => "application/wasm",
+        _ => "application/octet-stream",
+    }
+}
+
+/// 64-bit FNV-1a, which is plenty to tell versions of an asset apart.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
@@ -1,21 +1,19 @@
 use anyhow::Result;
 use core::str;
 use embedded_svc::{http::Method, io::Write};
-use esp_idf_hal::{
-    i2c::{I2cConfig, I2cDriver},
-    prelude::*,
-};
+use esp_idf_hal::prelude::*;
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
     http::server::{Configuration, EspHttpServer},
 };
-use shtcx::{self, shtc3, PowerMode};
-use std::{
-    sync::{Arc, Mutex},
-    thread::sleep,
-    time::Duration,
+use http_server::{
+    esp::{I2cBus, Shtc3Sensor},
+    json,
+    live::LiveReadings,
+    sampler::Sampler,
 };
-use wifi::wifi;
+use std::{sync::Arc, thread::sleep, time::Duration};
+use wifi::{WifiBuilder, WifiMode};
 // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
 use esp_idf_sys as _;
 
@@ -25,7 +23,16 @@ pub struct Config {
     wifi_ssid: &'static str,
     #[default("")]
     wifi_psk: &'static str,
+    #[default(10)]
+    sensor_interval_secs: u32,
+    #[default(10)]
+    sensor_sda: i32,
+    #[default(8)]
+    sensor_scl: i32,
+    #[default(100)]
+    sensor_i2c_khz: u32,
 }
+
 fn main() -> Result<()> {
     esp_idf_sys::link_patches();
     esp_idf_svc::log::EspLogger::initialize_default();
@@ -37,29 +44,33 @@ fn main() -> Result<()> {
     let app_config = CONFIG;
 
     // Connect to the Wi-Fi network
-    let _wifi = wifi(
+    let _wifi = WifiBuilder::new(WifiMode::station(
         app_config.wifi_ssid,
         app_config.wifi_psk,
-        peripherals.modem,
-        sysloop,
-    )?;
+    ))
+    .start(peripherals.modem, sysloop)?;
 
     // Initialize temperature sensor
-    let sda = peripherals.pins.gpio10;
-    let scl = peripherals.pins.gpio8;
-    let i2c = peripherals.i2c0;
-    let config = I2cConfig::new().baudrate(100.kHz().into());
-    let i2c = I2cDriver::new(i2c, sda, scl, &config)?;
-    let temp_sensor_main = Arc::new(Mutex::new(shtc3(i2c)));
-    let temp_sensor = temp_sensor_main.clone();
-    temp_sensor
-        .lock()
-        .unwrap()
-        .start_measurement(PowerMode::NormalMode)
-        .unwrap();
+    let bus = I2cBus {
+        sda: app_config.sensor_sda,
+        scl: app_config.sensor_scl,
+        baudrate_khz: app_config.sensor_i2c_khz,
+    };
+    let temp_sensor = Shtc3Sensor::new(peripherals.i2c0, bus)?;
+
+    // The sampler thread owns the sensor from here on, the handlers only see
+    // its latest reading. ws://<sta ip>/ws pushes every new one, leaving two
+    // of the four sockets for plain requests
+    let live = Arc::new(LiveReadings::new(2));
+    let interval = Duration::from_secs(app_config.sensor_interval_secs.into());
+    let sampler = Sampler::start(temp_sensor, move || interval, {
+        let live = live.clone();
+        move |reading| live.publish(reading)
+    })?;
 
     // Set the HTTP server
     let mut server = EspHttpServer::new(&Configuration::default())?;
+    http_server::live::register(&mut server, live.clone())?;
     // http://<sta ip>/ handler
     server.fn_handler("/", Method::Get, |request| {
         let html = index_html();
@@ -69,15 +80,17 @@ fn main() -> Result<()> {
     })?;
 
     // http://<sta ip>/temperature handler
+    let snapshot = sampler.snapshot();
     server.fn_handler("/temperature", Method::Get, move |request| {
-        let temp_val = temp_sensor
-            .lock()
-            .unwrap()
-            .get_measurement_result()
-            .unwrap()
-            .temperature
-            .as_degrees_celsius();
-        let html = temperature(temp_val);
+        let Some(reading) = snapshot.reading() else {
+            let mut response = request.into_status_response(503)?;
+            response.write_all(templated("No temperature reading yet.").as_bytes())?;
+            return Ok(());
+        };
+        if json::accepts_json(&request) {
+            return json::respond(request, 200, &reading);
+        }
+        let html = temperature(reading.temperature_celsius);
         let mut response = request.into_ok_response()?;
         response.write_all(html.as_bytes())?;
         Ok(())
@@ -87,11 +100,6 @@ fn main() -> Result<()> {
 
     // Prevent program from exiting
     loop {
-        temp_sensor_main
-            .lock()
-            .unwrap()
-            .start_measurement(PowerMode::NormalMode)
-            .unwrap();
         sleep(Duration::from_millis(1000));
     }
 }
@@ -0,0 +1,304 @@
+//! Alerts on sensor readings crossing a threshold, on `/api/v1/alerts`.
+//!
+//! A rule becomes active once its channel has been beyond the threshold for
+//! its minimum duration, and is cleared again once the channel is back by
+//! more than the hysteresis, so a reading hovering around the threshold
+//! doesn't set it off over and over. Both transitions are passed to the
+//! notification sinks.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use embedded_svc::http::{
+    server::{Connection, HandlerResult, Request},
+    Method,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{SharedConfig, Storage},
+    form, json,
+    router::Router,
+    sensor::{Channel, Reading},
+    server::Server,
+};
+
+/// Most rules that can be configured.
+pub const MAX_RULES: usize = 8;
+
+/// Notifications kept for `/api/v1/alerts`.
+const RECENT_LEN: usize = 16;
+
+/// Largest accepted request body.
+const MAX_BODY_LEN: usize = 2048;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    Above,
+    Below,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    pub channel: Channel,
+    pub condition: Condition,
+    pub threshold: f32,
+    /// How far back past the threshold the channel has to go to clear the
+    /// alert.
+    #[serde(default)]
+    pub hysteresis: f32,
+    /// How long the channel has to stay past the threshold to raise the
+    /// alert.
+    #[serde(default)]
+    pub min_duration_secs: u32,
+}
+
+impl Rule {
+    fn triggered(&self, value: f32) -> bool {
+        match self.condition {
+            Condition::Above => value > self.threshold,
+            Condition::Below => value < self.threshold,
+        }
+    }
+
+    fn cleared(&self, value: f32) -> bool {
+        match self.condition {
+            Condition::Above => value <= self.threshold - self.hysteresis,
+            Condition::Below => value >= self.threshold + self.hysteresis,
+        }
+    }
+}
+
+/// Checks a set of rules to be stored, see [`MAX_RULES`].
+pub fn validate_rules(rules: &[Rule]) -> Result<(), &'static str> {
+    if rules.len() > MAX_RULES {
+        return Err("There can be at most 8 alert rules.");
+    }
+    for (i, rule) in rules.iter().enumerate() {
+        if rule.name.is_empty() || rule.name.len() > 32 {
+            return Err("Alert names must be 1 to 32 characters long.");
+        }
+        if rules[..i].iter().any(|other| other.name == rule.name) {
+            return Err("Alert names must be unique.");
+        }
+        if !rule.threshold.is_finite() {
+            return Err("Alert thresholds must be numbers.");
+        }
+        if !(rule.hysteresis.is_finite() && rule.hysteresis >= 0.0) {
+            return Err("Alert hysteresis must not be negative.");
+        }
+    }
+    Ok(())
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "state")]
+pub enum AlertState {
+    Clear,
+    /// Past the threshold since `since`, but not for long enough yet.
+    Pending {
+        since: u32,
+    },
+    Active {
+        since: u32,
+    },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transition {
+    Activated,
+    Cleared,
+}
+
+/// A rule becoming active or clear, with the value that made it so.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Notification {
+    pub rule: String,
+    pub transition: Transition,
+    pub channel: Channel,
+    pub value: f32,
+    pub threshold: f32,
+    /// In seconds since the Unix epoch.
+    pub time: u32,
+}
+
+/// Where notifications go, e.g. the event stream or the log. Sinks are called
+/// while the [`Alerts`] are locked, so they must not lock them themselves.
+pub trait NotificationSink: Send + 'static {
+    fn notify(&mut self, notification: &Notification);
+}
+
+impl<F: FnMut(&Notification) + Send + 'static> NotificationSink for F {
+    fn notify(&mut self, notification: &Notification) {
+        self(notification)
+    }
+}
+
+/// A rule with its state, as served.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RuleStatus {
+    #[serde(flatten)]
+    pub rule: Rule,
+    #[serde(flatten)]
+    pub state: AlertState,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct AlertsBody {
+    pub rules: Vec<RuleStatus>,
+    /// The latest notifications, oldest first.
+    pub recent: Vec<Notification>,
+}
+
+pub struct Alerts {
+    rules: Vec<RuleStatus>,
+    sinks: Vec<Box<dyn NotificationSink>>,
+    recent: VecDeque<Notification>,
+}
+
+/// [`Alerts`] shared between the sampler and the HTTP handlers.
+pub type SharedAlerts = Arc<Mutex<Alerts>>;
+
+impl Alerts {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        let mut alerts = Self {
+            rules: Vec::new(),
+            sinks: Vec::new(),
+            recent: VecDeque::with_capacity(RECENT_LEN),
+        };
+        alerts.set_rules(rules);
+        alerts
+    }
+
+    pub fn add_sink(&mut self, sink: impl NotificationSink) {
+        self.sinks.push(Box::new(sink));
+    }
+
+    /// Replaces the rules. Those that didn't change keep their state, the
+    /// others start out clear, without notifying anyone.
+    pub fn set_rules(&mut self, rules: Vec<Rule>) {
+        let mut old = std::mem::take(&mut self.rules);
+        self.rules = rules
+            .into_iter()
+            .map(|rule| {
+                let state = old
+                    .iter()
+                    .position(|status| status.rule == rule)
+                    .map_or(AlertState::Clear, |i| old.swap_remove(i).state);
+                RuleStatus { rule, state }
+            })
+            .collect();
+    }
+
+    /// Checks every rule against `reading`, taken at `time` seconds since the
+    /// Unix epoch.
+    pub fn evaluate(&mut self, time: u32, reading: &Reading) {
+        for i in 0..self.rules.len() {
+            let RuleStatus { rule, state } = &mut self.rules[i];
+            let value = reading.value(rule.channel);
+
+            let (next, transition) = match *state {
+                AlertState::Clear | AlertState::Pending { .. } if !rule.triggered(value) => {
+                    (AlertState::Clear, None)
+                }
+                AlertState::Clear => (AlertState::Pending { since: time }, None),
+                AlertState::Pending { since } => (AlertState::Pending { since }, None),
+                AlertState::Active { .. } if rule.cleared(value) => {
+                    (AlertState::Clear, Some(Transition::Cleared))
+                }
+                active @ AlertState::Active { .. } => (active, None),
+            };
+            // Without a minimum duration, pending is over right away.
+            let (next, transition) = match next {
+                AlertState::Pending { since }
+                    if time.saturating_sub(since) >= rule.min_duration_secs =>
+                {
+                    (
+                        AlertState::Active { since: time },
+                        Some(Transition::Activated),
+                    )
+                }
+                next => (next, transition),
+            };
+            *state = next;
+
+            if let Some(transition) = transition {
+                let notification = Notification {
+                    rule: rule.name.clone(),
+                    transition,
+                    channel: rule.channel,
+                    value,
+                    threshold: rule.threshold,
+                    time,
+                };
+                self.notify(notification);
+            }
+        }
+    }
+
+    fn notify(&mut self, notification: Notification) {
+        for sink in &mut self.sinks {
+            sink.notify(&notification);
+        }
+        if self.recent.len() == RECENT_LEN {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(notification);
+    }
+
+    pub fn body(&self) -> AlertsBody {
+        AlertsBody {
+            rules: self.rules.clone(),
+            recent: self.recent.iter().cloned().collect(),
+        }
+    }
+}
+
+pub fn register<Srv: Server + 'static, S: Storage>(
+    router: &mut Router<Srv>,
+    alerts: SharedAlerts,
+    config: SharedConfig<S>,
+) {
+    {
+        let alerts = alerts.clone();
+        router.get("/api/v1/alerts", move |request, _| {
+            json::respond(request, 200, &alerts.lock().unwrap().body())
+        });
+    }
+    router.route("/api/v1/alerts", Method::Put, move |request, _| {
+        put_rules(request, &alerts, &config)
+    });
+}
+
+/// Replaces the rules with those in the body, a JSON array.
+pub fn put_rules<C: Connection, S: Storage>(
+    mut request: Request<C>,
+    alerts: &SharedAlerts,
+    config: &SharedConfig<S>,
+) -> HandlerResult {
+    let body = match form::read_body(&mut request, MAX_BODY_LEN) {
+        Ok(body) => body,
+        Err(err) => return json::error(request, err.status(), &err.to_string()),
+    };
+    let rules: Vec<Rule> = match serde_json::from_str(&body) {
+        Ok(rules) => rules,
+        Err(err) => return json::error(request, 400, &err.to_string()),
+    };
+    if let Err(error) = validate_rules(&rules) {
+        return json::error(request, 400, error);
+    }
+
+    config.lock().unwrap().set_alert_rules(&rules)?;
+    let body = {
+        let mut alerts = alerts.lock().unwrap();
+        alerts.set_rules(rules);
+        alerts.body()
+    };
+    json::respond(request, 200, &body)
+}
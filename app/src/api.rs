@@ -0,0 +1,308 @@
+//! JSON API under `/api/v1`, plus the `/temperature` page, which serves JSON
+//! to clients that ask for it.
+
+use std::{net::Ipv4Addr, sync::Arc, time::Duration};
+
+use embedded_svc::http::{
+    server::{Connection, HandlerResult, Request},
+    Method,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{self, Network, SharedConfig, Storage},
+    form, json,
+    pages::{self, TemperaturePage},
+    router::Router,
+    sensor::Reading,
+    server::Server,
+    system::Platform,
+};
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DeviceInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub hostname: String,
+    pub uptime_secs: u64,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WifiMode {
+    Station,
+    AccessPoint,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct WifiStatus {
+    pub mode: WifiMode,
+    pub ssid: String,
+    /// For a station, whether it has a DHCP lease; an access point is always
+    /// connected.
+    pub connected: bool,
+    pub ip: Option<Ipv4Addr>,
+    /// Signal strength of the access point the station is associated with,
+    /// in dBm.
+    pub rssi: Option<i8>,
+    /// Of the access point the station is associated with, or our own.
+    pub channel: Option<u8>,
+}
+
+/// The platform side of the API.
+pub trait Device: Send + Sync + 'static {
+    fn uptime(&self) -> Duration;
+
+    fn wifi_status(&self) -> anyhow::Result<WifiStatus>;
+
+    /// The most recent sensor reading, if there is one yet.
+    fn reading(&self) -> Option<Reading>;
+
+    /// The chip, its memory and network interfaces, for `/api/v1/system`.
+    fn platform(&self) -> anyhow::Result<Platform>;
+}
+
+/// The runtime configuration as served, without the Wi-Fi password.
+#[derive(Clone, Debug, Serialize)]
+pub struct ConfigBody {
+    pub version: u32,
+    pub wifi_ap: bool,
+    pub wifi_ssid: String,
+    pub wifi_psk_set: bool,
+    pub hostname: String,
+    pub sensor_interval_secs: u64,
+}
+
+/// A network as served by `GET /api/v1/wifi/networks`, without its password.
+#[derive(Clone, Debug, Serialize)]
+pub struct NetworkBody {
+    pub ssid: String,
+    pub psk_set: bool,
+    pub priority: u8,
+}
+
+impl From<Network> for NetworkBody {
+    fn from(network: Network) -> Self {
+        Self {
+            ssid: network.ssid,
+            psk_set: !network.psk.is_empty(),
+            priority: network.priority,
+        }
+    }
+}
+
+/// Fields to change with `PATCH /api/v1/config`, the others are kept. An
+/// empty `wifi_psk` makes the network open.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigPatch {
+    pub wifi_ap: Option<bool>,
+    pub wifi_ssid: Option<String>,
+    pub wifi_psk: Option<String>,
+    pub hostname: Option<String>,
+    pub sensor_interval_secs: Option<u32>,
+}
+
+/// Largest accepted request body.
+const MAX_BODY_LEN: usize = 512;
+
+/// Largest accepted list of networks, which can have a password each.
+const MAX_NETWORKS_BODY_LEN: usize = 2048;
+
+pub fn register<Srv: Server + 'static, D: Device, S: Storage>(
+    router: &mut Router<Srv>,
+    device: Arc<D>,
+    config: SharedConfig<S>,
+) {
+    {
+        let (device, config) = (device.clone(), config.clone());
+        router.get("/api/v1/device", move |request, _| {
+            device_info(request, &*device, &config)
+        });
+    }
+    {
+        let device = device.clone();
+        router.get("/api/v1/wifi", move |request, _| wifi(request, &*device));
+    }
+    {
+        let config = config.clone();
+        router.get("/api/v1/wifi/networks", move |request, _| {
+            json::respond(request, 200, &networks_body(&config)?)
+        });
+    }
+    {
+        let config = config.clone();
+        router.route("/api/v1/wifi/networks", Method::Put, move |request, _| {
+            put_networks(request, &config)
+        });
+    }
+    {
+        let device = device.clone();
+        router.get("/api/v1/sensor", move |request, _| {
+            sensor(request, &*device)
+        });
+    }
+    {
+        let config = config.clone();
+        router.get("/api/v1/config", move |request, _| {
+            json::respond(request, 200, &config_body(&config)?)
+        });
+    }
+    router.route("/api/v1/config", Method::Patch, move |request, _| {
+        patch_config(request, &config)
+    });
+    router.get("/temperature", move |request, _| {
+        temperature(request, &*device)
+    });
+}
+
+pub fn device_info<C: Connection, S: Storage>(
+    request: Request<C>,
+    device: &impl Device,
+    config: &SharedConfig<S>,
+) -> HandlerResult {
+    let info = DeviceInfo {
+        name: env!("CARGO_PKG_NAME"),
+        version: env!("CARGO_PKG_VERSION"),
+        hostname: config.lock().unwrap().hostname()?,
+        uptime_secs: device.uptime().as_secs(),
+    };
+    json::respond(request, 200, &info)
+}
+
+pub fn wifi<C: Connection>(request: Request<C>, device: &impl Device) -> HandlerResult {
+    json::respond(request, 200, &device.wifi_status()?)
+}
+
+pub fn sensor<C: Connection>(request: Request<C>, device: &impl Device) -> HandlerResult {
+    match device.reading() {
+        Some(reading) => json::respond(request, 200, &reading),
+        None => json::error(request, 503, "No sensor reading available yet."),
+    }
+}
+
+/// The current reading as a sentence in a page, or as JSON if the client
+/// accepts that.
+pub fn temperature<C: Connection>(request: Request<C>, device: &impl Device) -> HandlerResult {
+    if json::accepts_json(&request) {
+        return sensor(request, device);
+    }
+
+    let reading = device.reading();
+    let page = TemperaturePage {
+        reading: reading.as_ref(),
+    };
+    pages::respond(request, 200, &page)
+}
+
+pub fn patch_config<C: Connection, S: Storage>(
+    mut request: Request<C>,
+    config: &SharedConfig<S>,
+) -> HandlerResult {
+    let body = match form::read_body(&mut request, MAX_BODY_LEN) {
+        Ok(body) => body,
+        Err(err) => return json::error(request, err.status(), &err.to_string()),
+    };
+    let patch: ConfigPatch = match serde_json::from_str(&body) {
+        Ok(patch) => patch,
+        Err(err) => return json::error(request, 400, &err.to_string()),
+    };
+
+    {
+        let mut config = config.lock().unwrap();
+        // The password has to suit the mode, whichever of them changes.
+        let wifi_ap = patch.wifi_ap.map_or_else(|| config.wifi_ap(), Ok)?;
+        let wifi_psk = patch
+            .wifi_psk
+            .clone()
+            .map_or_else(|| config.wifi_psk(), Ok)?;
+        let sensor_interval = match validate(&patch, wifi_ap, &wifi_psk) {
+            Ok(sensor_interval) => sensor_interval,
+            Err(error) => return json::error(request, 400, error),
+        };
+
+        if let Some(ap) = patch.wifi_ap {
+            config.set_wifi_ap(ap)?;
+        }
+        if let Some(ssid) = &patch.wifi_ssid {
+            config.set_wifi_ssid(ssid)?;
+        }
+        if let Some(psk) = &patch.wifi_psk {
+            config.set_wifi_psk(psk)?;
+        }
+        if let Some(hostname) = &patch.hostname {
+            config.set_hostname(hostname)?;
+        }
+        if let Some(interval) = sensor_interval {
+            config.set_sensor_interval(interval)?;
+        }
+    }
+
+    json::respond(request, 200, &config_body(config)?)
+}
+
+/// Checks the fields to change against the configuration they end up in,
+/// returning the parsed sensor interval.
+fn validate(
+    patch: &ConfigPatch,
+    wifi_ap: bool,
+    wifi_psk: &str,
+) -> Result<Option<Duration>, &'static str> {
+    if let Some(ssid) = &patch.wifi_ssid {
+        config::validate_ssid(ssid)?;
+    }
+    if !wifi_psk.is_empty() {
+        config::validate_psk(wifi_psk, wifi_ap)?;
+    }
+    if let Some(hostname) = &patch.hostname {
+        config::validate_hostname(hostname)?;
+    }
+
+    patch
+        .sensor_interval_secs
+        .map(config::validate_sensor_interval)
+        .transpose()
+}
+
+/// The networks to join besides `wifi_ssid`, as served by
+/// `GET /api/v1/wifi/networks`.
+pub fn networks_body<S: Storage>(config: &SharedConfig<S>) -> anyhow::Result<Vec<NetworkBody>> {
+    let networks = config.lock().unwrap().wifi_networks()?;
+    Ok(networks.into_iter().map(NetworkBody::from).collect())
+}
+
+/// Replaces the networks to join besides `wifi_ssid` with those in the body,
+/// a JSON array. They are tried from the next start on.
+pub fn put_networks<C: Connection, S: Storage>(
+    mut request: Request<C>,
+    config: &SharedConfig<S>,
+) -> HandlerResult {
+    let body = match form::read_body(&mut request, MAX_NETWORKS_BODY_LEN) {
+        Ok(body) => body,
+        Err(err) => return json::error(request, err.status(), &err.to_string()),
+    };
+    let networks: Vec<Network> = match serde_json::from_str(&body) {
+        Ok(networks) => networks,
+        Err(err) => return json::error(request, 400, &err.to_string()),
+    };
+    if let Err(error) = config::validate_networks(&networks) {
+        return json::error(request, 400, error);
+    }
+
+    config.lock().unwrap().set_wifi_networks(&networks)?;
+    json::respond(request, 200, &networks_body(config)?)
+}
+
+/// The runtime configuration as served by `GET /api/v1/config`.
+pub fn config_body<S: Storage>(config: &SharedConfig<S>) -> anyhow::Result<ConfigBody> {
+    let config = config.lock().unwrap();
+
+    Ok(ConfigBody {
+        version: config.version()?.unwrap_or(config::VERSION),
+        wifi_ap: config.wifi_ap()?,
+        wifi_ssid: config.wifi_ssid()?,
+        wifi_psk_set: !config.wifi_psk()?.is_empty(),
+        hostname: config.hostname()?,
+        sensor_interval_secs: config.sensor_interval()?.as_secs(),
+    })
+}
@@ -0,0 +1,144 @@
+//! Files from `static/`, embedded into the binary by `build.rs` and served
+//! under `/static/`.
+//!
+//! Responses carry an `ETag`, so browsers can revalidate with a `304`. Links
+//! made with [`url`] include the content hash and are cached for good; other
+//! requests have to revalidate every time.
+
+use embedded_svc::{
+    http::server::{Connection, HandlerResult, Request},
+    io::Write,
+};
+use serde::Deserialize;
+
+use crate::{
+    router::{reject, Router},
+    server::Server,
+};
+
+pub struct Asset {
+    /// Path below `static/`, with `/` separators.
+    pub path: &'static str,
+    pub content_type: &'static str,
+    /// Hash of `body`, in hex.
+    pub hash: &'static str,
+    pub body: &'static [u8],
+    /// `body` gzipped, if that is any smaller.
+    pub gzip: Option<&'static [u8]>,
+}
+
+pub static ASSETS: &[Asset] = include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+
+const CACHE_FOREVER: &str = "public, max-age=31536000, immutable";
+const REVALIDATE: &str = "no-cache";
+
+#[derive(Deserialize)]
+struct VersionQuery {
+    v: Option<String>,
+}
+
+pub fn find(path: &str) -> Option<&'static Asset> {
+    ASSETS.iter().find(|asset| asset.path == path)
+}
+
+/// The URL of the asset at `path`, versioned with its hash.
+pub fn url(path: &str) -> String {
+    match find(path) {
+        Some(asset) => format!("/static/{}?v={}", asset.path, asset.hash),
+        None => format!("/static/{path}"),
+    }
+}
+
+pub fn register<S: Server + 'static>(router: &mut Router<S>) {
+    router.get("/static/{*path}", |request, params| {
+        let version = params
+            .query::<VersionQuery>()
+            .ok()
+            .and_then(|query| query.v);
+        serve(
+            request,
+            params.get("path").unwrap_or_default(),
+            version.as_deref(),
+        )
+    });
+}
+
+/// Serves the asset at `path`, which can be cached for good if `version` is
+/// its current hash.
+pub fn serve<C: Connection>(
+    request: Request<C>,
+    path: &str,
+    version: Option<&str>,
+) -> HandlerResult {
+    let Some(asset) = find(path) else {
+        return reject(request, 404, "Not Found");
+    };
+    let cache_control = if version == Some(asset.hash) {
+        CACHE_FOREVER
+    } else {
+        REVALIDATE
+    };
+
+    // The compressed body is a different representation, so it gets its own tag.
+    let gzip = asset.gzip.filter(|_| accepts_gzip(&request));
+    let etag = match gzip {
+        Some(_) => format!("\"{}-gzip\"", asset.hash),
+        None => format!("\"{}\"", asset.hash),
+    };
+
+    if request
+        .header("If-None-Match")
+        .is_some_and(|tags| etag_matches(tags, &etag))
+    {
+        request.into_response(
+            304,
+            Some("Not Modified"),
+            &[
+                ("ETag", &etag),
+                ("Cache-Control", cache_control),
+                ("Vary", "Accept-Encoding"),
+            ],
+        )?;
+        return Ok(());
+    }
+
+    let mut headers = vec![
+        ("Content-Type", asset.content_type),
+        ("ETag", &etag),
+        ("Cache-Control", cache_control),
+        ("Vary", "Accept-Encoding"),
+    ];
+    if gzip.is_some() {
+        headers.push(("Content-Encoding", "gzip"));
+    }
+    let mut response = request.into_response(200, Some("OK"), &headers)?;
+    Ok(response.write_all(gzip.unwrap_or(asset.body))?)
+}
+
+fn accepts_gzip<C: Connection>(request: &Request<C>) -> bool {
+    let Some(accept) = request.header("Accept-Encoding") else {
+        return false;
+    };
+
+    accept.split(',').any(|coding| {
+        let mut parts = coding.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        let refused = parts.any(|param| {
+            param
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+        (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+    })
+}
+
+/// Whether the `If-None-Match` header value `tags` includes `etag`, comparing
+/// weakly as RFC 9110 asks.
+fn etag_matches(tags: &str, etag: &str) -> bool {
+    tags.trim() == "*"
+        || tags
+            .split(',')
+            .map(|tag| tag.trim())
+            .any(|tag| tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
@@ -0,0 +1,700 @@
+//! Authentication for routes that change the device, see
+//! [`Router::protect`](crate::router::Router::protect).
+//!
+//! Requests carry either a user's password with HTTP Basic or an API token as
+//! a bearer token. Both are kept in the runtime configuration, passwords as
+//! salted PBKDF2 hashes and tokens as SHA-256 hashes, and managed on
+//! `/api/v1/auth`. Until there is at least one of either, routes guarded by
+//! [`Auth`] are open, but creating the first user or API token takes the
+//! [setup code](Auth::setup_code) as a bearer token, which is only printed to
+//! the console; routes guarded by [`Strict`] stay closed.
+//!
+//! After [`Lockout::max_failures`] wrong attempts in a row, a user or API token
+//! is turned away for a while even with the right credentials. Tokens are told
+//! apart by their first characters, which are stored as they are. Attempts for
+//! users or tokens that don't exist can't succeed, so they aren't counted.
+
+use core::fmt;
+use std::{
+    hint,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use embedded_svc::{
+    http::{
+        server::{Connection, HandlerResult, Request},
+        Method,
+    },
+    io::Write,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::{SharedConfig, Storage},
+    form,
+    json::{self, ErrorBody},
+    router::{self, reason_phrase, Router},
+    server::Server,
+};
+
+/// Realm of the challenges, shown by browsers when asking for a password.
+const REALM: &str = "esp-rs";
+
+/// PBKDF2 rounds for new password hashes. Few by desktop standards, but the
+/// ESP32 hashes in software, and this already takes a fraction of a second.
+const PBKDF2_ROUNDS: u32 = 4096;
+
+const SALT_LEN: usize = 16;
+const TOKEN_LEN: usize = 32;
+const SETUP_CODE_LEN: usize = 8;
+
+/// Hex digits at the start of an API token kept in the clear, to tell which
+/// token a failed attempt was for.
+const TOKEN_PREFIX_LEN: usize = 8;
+
+/// Most users and most API tokens that can be stored each.
+pub const MAX_CREDENTIALS: usize = 8;
+
+/// Successful `Authorization` headers remembered, so a browser sending the
+/// same password with every request doesn't pay for PBKDF2 every time.
+const VERIFIED_LEN: usize = 4;
+
+/// Largest accepted request body.
+const MAX_BODY_LEN: usize = 256;
+
+/// Users and API tokens, as stored in the configuration.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Credentials {
+    #[serde(default)]
+    pub users: Vec<User>,
+    #[serde(default)]
+    pub tokens: Vec<ApiToken>,
+}
+
+impl Credentials {
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.tokens.is_empty()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct User {
+    pub name: String,
+    /// Hex.
+    pub salt: String,
+    pub rounds: u32,
+    /// Hex PBKDF2-HMAC-SHA256 of the password.
+    pub hash: String,
+}
+
+impl User {
+    /// With a new random salt.
+    pub fn new(name: &str, password: &str) -> anyhow::Result<Self> {
+        let mut salt = [0; SALT_LEN];
+        getrandom::getrandom(&mut salt)?;
+        Ok(Self {
+            name: name.into(),
+            salt: hex(&salt),
+            rounds: PBKDF2_ROUNDS,
+            hash: hex(&hash_password(password, &salt, PBKDF2_ROUNDS)),
+        })
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        let Some(salt) = unhex(&self.salt) else {
+            return false;
+        };
+        let hash = hash_password(password, &salt, self.rounds);
+        unhex(&self.hash).is_some_and(|expected| constant_time_eq(&hash, &expected))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub name: String,
+    /// The first hex digits of the token.
+    pub prefix: String,
+    /// Hex SHA-256 of the token.
+    pub hash: String,
+}
+
+fn hash_password(password: &str, salt: &[u8], rounds: u32) -> [u8; 32] {
+    pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(password.as_bytes(), salt, rounds)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => u8::from_str_radix(core::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Compares all of both, so the time taken doesn't give away how much of
+/// them matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// When to turn a user away after failed attempts.
+#[derive(Copy, Clone, Debug)]
+pub struct Lockout {
+    /// Failures in a row before the user is locked out.
+    pub max_failures: u32,
+    pub duration: Duration,
+}
+
+impl Default for Lockout {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            duration: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Why a request to a protected route is turned away.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Denied {
+    /// Without credentials, or with wrong ones.
+    Unauthorized,
+    /// After too many failures.
+    LockedOut { retry_after: Duration },
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unauthorized => write!(f, "A valid password or API token is required."),
+            Self::LockedOut { retry_after } => write!(
+                f,
+                "Too many failed attempts, try again in {} seconds.",
+                whole_secs(*retry_after)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Denied {}
+
+/// Rounded up, so a client waiting as long is no longer turned away.
+fn whole_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+/// Decides whether a request may reach a protected route.
+pub trait Guard: Send + Sync + 'static {
+    /// `authorization` is the `Authorization` header of the request.
+    fn check(&self, authorization: Option<&str>) -> Result<(), Denied>;
+}
+
+/// Answers a request that was [`Denied`], with a challenge for either kind of
+/// credentials.
+pub fn challenge<C: Connection>(request: Request<C>, denied: &Denied) -> HandlerResult {
+    let message = denied.to_string();
+    let challenges = [
+        format!(r#"Basic realm="{REALM}", charset="UTF-8""#),
+        format!(r#"Bearer realm="{REALM}""#),
+    ];
+    let retry_after;
+    let (status, headers) = match denied {
+        Denied::Unauthorized => (
+            401,
+            vec![
+                ("WWW-Authenticate", challenges[0].as_str()),
+                ("WWW-Authenticate", challenges[1].as_str()),
+            ],
+        ),
+        Denied::LockedOut { retry_after: after } => {
+            retry_after = whole_secs(*after).to_string();
+            (429, vec![("Retry-After", retry_after.as_str())])
+        }
+    };
+
+    if router::wants_json(&request) {
+        let body = ErrorBody::new(status, &message);
+        return json::respond_with(request, status, &headers, &body);
+    }
+    let mut headers = headers;
+    headers.push(("Content-Type", "text/plain; charset=utf-8"));
+    let mut response = request.into_response(status, reason_phrase(status), &headers)?;
+    Ok(response.write_all(message.as_bytes())?)
+}
+
+/// What failed attempts count towards.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Account {
+    User(String),
+    /// By name.
+    Token(String),
+    Setup,
+}
+
+/// Failed attempts for one account in a row.
+struct Failures {
+    account: Account,
+    count: u32,
+    last: Instant,
+}
+
+struct State {
+    /// [`ConfigStore::changes`](crate::config::ConfigStore::changes) when the
+    /// credentials were loaded.
+    loaded: Option<u64>,
+    credentials: Credentials,
+    /// SHA-256 of `Authorization` headers that passed.
+    verified: Vec<[u8; 32]>,
+    /// Only for accounts that exist, so at most one for each of the
+    /// credentials and one for the setup code.
+    failures: Vec<Failures>,
+}
+
+/// Checks requests against the credentials in the configuration.
+pub struct Auth<S> {
+    config: SharedConfig<S>,
+    lockout: Lockout,
+    /// Hex, new on every start.
+    setup_code: String,
+    state: Mutex<State>,
+}
+
+impl<S: Storage> Auth<S> {
+    pub fn new(config: SharedConfig<S>, lockout: Lockout) -> anyhow::Result<Self> {
+        let mut setup_code = [0; SETUP_CODE_LEN];
+        getrandom::getrandom(&mut setup_code)?;
+        let auth = Self {
+            config,
+            lockout,
+            setup_code: hex(&setup_code),
+            state: Mutex::new(State {
+                loaded: None,
+                credentials: Credentials::default(),
+                verified: Vec::new(),
+                failures: Vec::new(),
+            }),
+        };
+        auth.refresh(&mut auth.state.lock().unwrap())?;
+        Ok(auth)
+    }
+
+    /// While there are no credentials yet, the code to create the first ones
+    /// with, meant to be shown only to whoever has the device at hand. It is
+    /// printed to the console whenever the last credentials are gone.
+    pub fn setup_code(&self) -> anyhow::Result<Option<&str>> {
+        let mut state = self.state.lock().unwrap();
+        self.refresh(&mut state)?;
+        Ok(state
+            .credentials
+            .is_empty()
+            .then_some(self.setup_code.as_str()))
+    }
+
+    /// Whether a request with `authorization` may create credentials: any
+    /// that passed the guard may once there are some, before that only one
+    /// with the setup code.
+    pub fn check_setup(&self, authorization: Option<&str>) -> Result<(), Denied> {
+        let mut state = self.load()?;
+        if !state.credentials.is_empty() {
+            return Ok(());
+        }
+
+        let authorization = authorization.ok_or(Denied::Unauthorized)?;
+        let (scheme, value) = authorization.split_once(' ').ok_or(Denied::Unauthorized)?;
+        if !scheme.eq_ignore_ascii_case("Bearer") {
+            return Err(Denied::Unauthorized);
+        }
+        let digest = Sha256::digest(authorization).into();
+        self.attempt(&mut state, Account::Setup, digest, || {
+            constant_time_eq(value.trim().as_bytes(), self.setup_code.as_bytes())
+        })
+    }
+
+    /// The state with the credentials as last stored.
+    fn load(&self) -> Result<MutexGuard<'_, State>, Denied> {
+        let mut state = self.state.lock().unwrap();
+        if let Err(err) = self.refresh(&mut state) {
+            eprintln!("Could not load the credentials: {err:#}");
+            return Err(Denied::Unauthorized);
+        }
+        Ok(state)
+    }
+
+    /// The credentials as last stored, reloaded if the configuration changed.
+    fn refresh(&self, state: &mut State) -> anyhow::Result<()> {
+        let (changes, credentials) = {
+            let config = self.config.lock().unwrap();
+            if state.loaded == Some(config.changes()) {
+                return Ok(());
+            }
+            (config.changes(), config.credentials()?)
+        };
+        if credentials.is_empty() && (state.loaded.is_none() || !state.credentials.is_empty()) {
+            println!(
+                "No users yet, create the first with the setup code {}",
+                self.setup_code
+            );
+        }
+        state.failures.retain(|failures| match &failures.account {
+            Account::User(name) => credentials.users.iter().any(|user| &user.name == name),
+            Account::Token(name) => credentials.tokens.iter().any(|token| &token.name == name),
+            Account::Setup => credentials.is_empty(),
+        });
+        state.loaded = Some(changes);
+        state.credentials = credentials;
+        state.verified.clear();
+        Ok(())
+    }
+
+    fn authenticate(&self, state: &mut State, authorization: &str) -> Result<(), Denied> {
+        let (scheme, value) = authorization.split_once(' ').ok_or(Denied::Unauthorized)?;
+        let value = value.trim();
+        let digest = Sha256::digest(authorization).into();
+        if scheme.eq_ignore_ascii_case("Basic") {
+            let decoded = BASE64
+                .decode(value)
+                .ok()
+                .and_then(|decoded| String::from_utf8(decoded).ok())
+                .ok_or(Denied::Unauthorized)?;
+            let (name, password) = decoded.split_once(':').ok_or(Denied::Unauthorized)?;
+            let user = state
+                .credentials
+                .users
+                .iter()
+                .find(|user| user.name == name);
+            let Some(user) = user.cloned() else {
+                // Nothing to lock out, but take as long as for a user that
+                // exists, so the time taken doesn't tell whether one does;
+                // kept from being optimized away, as the hash goes unused.
+                hint::black_box(hash_password(password, &[0; SALT_LEN], PBKDF2_ROUNDS));
+                return Err(Denied::Unauthorized);
+            };
+            let account = Account::User(user.name.clone());
+            self.attempt(state, account, digest, || user.verify(password))
+        } else if scheme.eq_ignore_ascii_case("Bearer") {
+            let token = state.credentials.tokens.iter().find(|token| {
+                token.prefix.len() == TOKEN_PREFIX_LEN && value.starts_with(&token.prefix)
+            });
+            let Some(token) = token.cloned() else {
+                return Err(Denied::Unauthorized);
+            };
+            let account = Account::Token(token.name.clone());
+            self.attempt(state, account, digest, || {
+                let hash = Sha256::digest(value.as_bytes());
+                unhex(&token.hash).is_some_and(|expected| constant_time_eq(&hash, &expected))
+            })
+        } else {
+            Err(Denied::Unauthorized)
+        }
+    }
+
+    /// Runs `verify` unless `account` is locked out, and counts a failure
+    /// against it. `digest` is of the `Authorization` header, remembered once
+    /// it passed.
+    fn attempt(
+        &self,
+        state: &mut State,
+        account: Account,
+        digest: [u8; 32],
+        verify: impl FnOnce() -> bool,
+    ) -> Result<(), Denied> {
+        if let Some(retry_after) = self.locked_out(state, &account) {
+            return Err(Denied::LockedOut { retry_after });
+        }
+
+        if state.verified.contains(&digest) || verify() {
+            state
+                .failures
+                .retain(|failures| failures.account != account);
+            if !state.verified.contains(&digest) {
+                if state.verified.len() == VERIFIED_LEN {
+                    state.verified.remove(0);
+                }
+                state.verified.push(digest);
+            }
+            Ok(())
+        } else {
+            self.record_failure(state, account);
+            Err(Denied::Unauthorized)
+        }
+    }
+
+    /// How much longer `account` is locked out, if it is.
+    fn locked_out(&self, state: &mut State, account: &Account) -> Option<Duration> {
+        let failures = state.failures.iter_mut().find(|f| &f.account == account)?;
+        if failures.count < self.lockout.max_failures {
+            return None;
+        }
+        let elapsed = failures.last.elapsed();
+        if elapsed >= self.lockout.duration {
+            // Served its time, another round of attempts.
+            failures.count = 0;
+            return None;
+        }
+        Some(self.lockout.duration - elapsed)
+    }
+
+    fn record_failure(&self, state: &mut State, account: Account) {
+        let now = Instant::now();
+        match state.failures.iter_mut().find(|f| f.account == account) {
+            Some(failures) => {
+                failures.count += 1;
+                failures.last = now;
+            }
+            None => state.failures.push(Failures {
+                account,
+                count: 1,
+                last: now,
+            }),
+        }
+    }
+
+    /// The names of the users and of the API tokens.
+    pub fn body(&self) -> anyhow::Result<AuthBody> {
+        let credentials = self.config.lock().unwrap().credentials()?;
+        Ok(AuthBody {
+            users: credentials
+                .users
+                .into_iter()
+                .map(|user| user.name)
+                .collect(),
+            tokens: credentials
+                .tokens
+                .into_iter()
+                .map(|token| token.name)
+                .collect(),
+        })
+    }
+
+    /// Adds the user `name`, or changes their password.
+    pub fn set_password(&self, name: &str, password: &str) -> anyhow::Result<()> {
+        let user = User::new(name, password)?;
+        self.update(|credentials| {
+            match credentials.users.iter_mut().find(|user| user.name == name) {
+                Some(existing) => *existing = user,
+                None => credentials.users.push(user),
+            }
+        })
+    }
+
+    /// Whether there was a user `name` to remove.
+    pub fn remove_user(&self, name: &str) -> anyhow::Result<bool> {
+        self.update(|credentials| {
+            let len = credentials.users.len();
+            credentials.users.retain(|user| user.name != name);
+            credentials.users.len() != len
+        })
+    }
+
+    /// Adds a new random API token called `name`, replacing any of the same
+    /// name, and returns it. Only its hash is kept.
+    pub fn create_token(&self, name: &str) -> anyhow::Result<String> {
+        let mut token = [0; TOKEN_LEN];
+        getrandom::getrandom(&mut token)?;
+        let token = hex(&token);
+        let stored = ApiToken {
+            name: name.into(),
+            prefix: token[..TOKEN_PREFIX_LEN].into(),
+            hash: hex(&Sha256::digest(token.as_bytes())),
+        };
+        self.update(|credentials| {
+            credentials.tokens.retain(|token| token.name != name);
+            credentials.tokens.push(stored);
+        })?;
+        Ok(token)
+    }
+
+    /// Whether there was an API token `name` to remove.
+    pub fn remove_token(&self, name: &str) -> anyhow::Result<bool> {
+        self.update(|credentials| {
+            let len = credentials.tokens.len();
+            credentials.tokens.retain(|token| token.name != name);
+            credentials.tokens.len() != len
+        })
+    }
+
+    fn update<T>(&self, change: impl FnOnce(&mut Credentials) -> T) -> anyhow::Result<T> {
+        let result = {
+            let mut config = self.config.lock().unwrap();
+            let mut credentials = config.credentials()?;
+            let result = change(&mut credentials);
+            config.set_credentials(&credentials)?;
+            result
+        };
+        // Right away rather than on the next request, so the setup code is
+        // shown as soon as the last credentials are removed.
+        self.refresh(&mut self.state.lock().unwrap())?;
+        Ok(result)
+    }
+}
+
+impl<S: Storage> Guard for Auth<S> {
+    fn check(&self, authorization: Option<&str>) -> Result<(), Denied> {
+        let mut state = self.load()?;
+        if state.credentials.is_empty() {
+            return Ok(());
+        }
+
+        let authorization = authorization.ok_or(Denied::Unauthorized)?;
+        self.authenticate(&mut state, authorization)
+    }
+}
+
+/// Like [`Auth`], but turns every request away until there are credentials,
+/// for routes too dangerous to leave open meanwhile.
+pub struct Strict<S>(pub Arc<Auth<S>>);
+
+impl<S: Storage> Guard for Strict<S> {
+    fn check(&self, authorization: Option<&str>) -> Result<(), Denied> {
+        let mut state = self.0.load()?;
+        let authorization = authorization.ok_or(Denied::Unauthorized)?;
+        self.0.authenticate(&mut state, authorization)
+    }
+}
+
+/// Served on `GET /api/v1/auth`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct AuthBody {
+    pub users: Vec<String>,
+    pub tokens: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PasswordBody {
+    password: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TokenRequest {
+    name: String,
+}
+
+#[derive(Serialize)]
+struct TokenBody<'a> {
+    name: &'a str,
+    token: &'a str,
+}
+
+/// Checks the name of a new user or API token; user names end up in
+/// `user:password`, so they can't have a colon.
+pub fn validate_name(name: &str) -> Result<(), &'static str> {
+    if name.is_empty() || name.len() > 32 || name.contains(':') || name.contains(char::is_control) {
+        return Err("Names must be 1 to 32 characters long, without colons.");
+    }
+    Ok(())
+}
+
+pub fn validate_password(password: &str) -> Result<(), &'static str> {
+    if !(8..=64).contains(&password.len()) {
+        return Err("Passwords must be between 8 and 64 bytes long.");
+    }
+    Ok(())
+}
+
+/// Serves the management of users and API tokens on `/api/v1/auth`. The
+/// routes are open like any other until protected with `auth` too, except
+/// that the first user or API token takes the setup code.
+pub fn register<Srv: Server + 'static, S: Storage>(router: &mut Router<Srv>, auth: Arc<Auth<S>>) {
+    {
+        let auth = auth.clone();
+        router.get("/api/v1/auth", move |request, _| {
+            json::respond(request, 200, &auth.body()?)
+        });
+    }
+    {
+        let auth = auth.clone();
+        router.route(
+            "/api/v1/auth/users/{name}",
+            Method::Put,
+            move |mut request, params| {
+                let name = params.get("name").unwrap_or_default();
+                let body = match form::read_body(&mut request, MAX_BODY_LEN) {
+                    Ok(body) => body,
+                    Err(err) => return json::error(request, err.status(), &err.to_string()),
+                };
+                let body: PasswordBody = match serde_json::from_str(&body) {
+                    Ok(body) => body,
+                    Err(err) => return json::error(request, 400, &err.to_string()),
+                };
+                if let Err(error) = validate_name(name).and(validate_password(&body.password)) {
+                    return json::error(request, 400, error);
+                }
+                if let Err(denied) = auth.check_setup(request.header("Authorization")) {
+                    return challenge(request, &denied);
+                }
+                let users = auth.body()?.users;
+                if users.len() == MAX_CREDENTIALS && !users.iter().any(|user| user == name) {
+                    let message = format!("There can be at most {MAX_CREDENTIALS} users.");
+                    return json::error(request, 400, &message);
+                }
+
+                auth.set_password(name, &body.password)?;
+                json::respond(request, 200, &auth.body()?)
+            },
+        );
+    }
+    {
+        let auth = auth.clone();
+        router.route(
+            "/api/v1/auth/users/{name}",
+            Method::Delete,
+            move |request, params| {
+                if !auth.remove_user(params.get("name").unwrap_or_default())? {
+                    return json::error(request, 404, "There is no such user.");
+                }
+                json::respond(request, 200, &auth.body()?)
+            },
+        );
+    }
+    {
+        let auth = auth.clone();
+        router.post("/api/v1/auth/tokens", move |mut request, _| {
+            let body = match form::read_body(&mut request, MAX_BODY_LEN) {
+                Ok(body) => body,
+                Err(err) => return json::error(request, err.status(), &err.to_string()),
+            };
+            let body: TokenRequest = match serde_json::from_str(&body) {
+                Ok(body) => body,
+                Err(err) => return json::error(request, 400, &err.to_string()),
+            };
+            if let Err(error) = validate_name(&body.name) {
+                return json::error(request, 400, error);
+            }
+            if let Err(denied) = auth.check_setup(request.header("Authorization")) {
+                return challenge(request, &denied);
+            }
+            let names = auth.body()?.tokens;
+            if names.len() == MAX_CREDENTIALS && !names.contains(&body.name) {
+                let message = format!("There can be at most {MAX_CREDENTIALS} API tokens.");
+                return json::error(request, 400, &message);
+            }
+
+            let token = auth.create_token(&body.name)?;
+            let body = TokenBody {
+                name: &body.name,
+                token: &token,
+            };
+            json::respond(request, 200, &body)
+        });
+    }
+    router.route(
+        "/api/v1/auth/tokens/{name}",
+        Method::Delete,
+        move |request, params| {
+            if !auth.remove_token(params.get("name").unwrap_or_default())? {
+                return json::error(request, 404, "There is no such API token.");
+            }
+            json::respond(request, 200, &auth.body()?)
+        },
+    );
+}
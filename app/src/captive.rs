@@ -0,0 +1,178 @@
+//! Captive portal for the access point: a DNS responder that resolves every
+//! name to the device, and redirects for the URLs operating systems probe to
+//! detect one, so phones pop up the portal on their own.
+
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use embedded_svc::http::server::{Connection, HandlerResult, Request};
+
+use crate::{router::Router, server::Server};
+
+/// Probed by Android, iOS/macOS, Windows and Firefox respectively.
+pub const CONNECTIVITY_CHECKS: &[&str] = &[
+    "/generate_204",
+    "/gen_204",
+    "/hotspot-detect.html",
+    "/library/test/success.html",
+    "/connecttest.txt",
+    "/ncsi.txt",
+    "/redirect",
+    "/canonical.html",
+    "/success.txt",
+];
+
+/// Redirects the connectivity checks to the index page at `ip`.
+pub fn register<S: Server + 'static>(router: &mut Router<S>, ip: Ipv4Addr) {
+    for uri in CONNECTIVITY_CHECKS {
+        router.get(uri, move |request, _| redirect(request, ip));
+    }
+}
+
+pub fn redirect<C: Connection>(request: Request<C>, ip: Ipv4Addr) -> HandlerResult {
+    let location = format!("http://{ip}/");
+    request.into_response(302, Some("Found"), &[("Location", &location)])?;
+    Ok(())
+}
+
+/// Answers every `A` query with a fixed address, until dropped.
+pub struct CaptiveDns {
+    local_addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl CaptiveDns {
+    /// Listens on the standard DNS port.
+    pub fn start(ip: Ipv4Addr) -> io::Result<Self> {
+        Self::start_on((Ipv4Addr::UNSPECIFIED, 53).into(), ip)
+    }
+
+    pub fn start_on(addr: SocketAddr, ip: Ipv4Addr) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        let local_addr = socket.local_addr()?;
+        // Wake up regularly to notice when to stop.
+        socket.set_read_timeout(Some(Duration::from_millis(500)))?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            thread::Builder::new()
+                .name("captive-dns".into())
+                .stack_size(4096)
+                .spawn(move || serve(&socket, ip, &stop))?
+        };
+
+        Ok(Self {
+            local_addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for CaptiveDns {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn serve(socket: &UdpSocket, ip: Ipv4Addr, stop: &AtomicBool) {
+    let mut buf = [0; 512];
+
+    while !stop.load(Ordering::Relaxed) {
+        let (len, peer) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            Err(err) => {
+                eprintln!("Captive DNS receive failed: {err}");
+                continue;
+            }
+        };
+
+        if let Some(response) = answer(&buf[..len], ip) {
+            if let Err(err) = socket.send_to(&response, peer) {
+                eprintln!("Captive DNS send to {peer} failed: {err}");
+            }
+        }
+    }
+}
+
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+const TTL: u32 = 60;
+
+/// Builds the response to the DNS `query`: the `A` record for `ip` if an
+/// IPv4 address was asked for, no records otherwise. Anything but a standard
+/// query with a single question is ignored.
+pub fn answer(query: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    let header = query.get(..12)?;
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    let is_response = flags & 0x8000 != 0;
+    let opcode = (flags >> 11) & 0xf;
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    if is_response || opcode != 0 || questions != 1 {
+        return None;
+    }
+
+    // The question name is a sequence of length-prefixed labels; queries
+    // don't use compression.
+    let mut end = 12;
+    loop {
+        let len = usize::from(*query.get(end)?);
+        end += 1 + len;
+        if len == 0 {
+            break;
+        }
+        if len > 63 {
+            return None;
+        }
+    }
+    let qtype = u16::from_be_bytes([*query.get(end)?, *query.get(end + 1)?]);
+    let qclass = u16::from_be_bytes([*query.get(end + 2)?, *query.get(end + 3)?]);
+    let question = &query[12..end + 4];
+    let answers = u16::from(qtype == TYPE_A && qclass == CLASS_IN);
+
+    let mut response = Vec::with_capacity(12 + question.len() + 16);
+    response.extend_from_slice(&header[..2]);
+    // Response, authoritative, keeping "recursion desired", recursion available.
+    response.extend_from_slice(&(0x8480 | (flags & 0x0100)).to_be_bytes());
+    response.extend_from_slice(&1u16.to_be_bytes());
+    response.extend_from_slice(&answers.to_be_bytes());
+    response.extend_from_slice(&[0, 0, 0, 0]);
+    response.extend_from_slice(question);
+
+    if answers > 0 {
+        // Pointer to the name in the question.
+        response.extend_from_slice(&0xc00cu16.to_be_bytes());
+        response.extend_from_slice(&TYPE_A.to_be_bytes());
+        response.extend_from_slice(&CLASS_IN.to_be_bytes());
+        response.extend_from_slice(&TTL.to_be_bytes());
+        response.extend_from_slice(&4u16.to_be_bytes());
+        response.extend_from_slice(&ip.octets());
+    }
+
+    Some(response)
+}
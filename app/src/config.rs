@@ -0,0 +1,323 @@
+//! Runtime configuration. Values set at runtime are persisted in a key-value
+//! [`Storage`] and take precedence over the compile-time defaults from
+//! `cfg.toml`, so the device can be reconfigured without reflashing.
+
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::{alerts::Rule, auth::Credentials};
+
+/// Schema version of the stored keys. Bump it together with adding a step to
+/// [`MIGRATIONS`] whenever keys are renamed or their format changes.
+pub const VERSION: u32 = 1;
+
+/// Upgrades the stored keys from version `i + 1` to `i + 2`.
+const MIGRATIONS: [fn(&mut dyn Storage) -> anyhow::Result<()>; VERSION as usize - 1] = [];
+
+const VERSION_KEY: &str = "version";
+const WIFI_SSID_KEY: &str = "wifi_ssid";
+const WIFI_PSK_KEY: &str = "wifi_psk";
+const WIFI_AP_KEY: &str = "wifi_ap";
+const WIFI_NETWORKS_KEY: &str = "wifi_networks";
+const HOSTNAME_KEY: &str = "hostname";
+const SENSOR_INTERVAL_KEY: &str = "sensor_interval";
+const ALERT_RULES_KEY: &str = "alert_rules";
+const CREDENTIALS_KEY: &str = "credentials";
+
+/// Every key other than the version, for [`ConfigStore::reset`].
+const KEYS: &[&str] = &[
+    WIFI_SSID_KEY,
+    WIFI_PSK_KEY,
+    WIFI_AP_KEY,
+    WIFI_NETWORKS_KEY,
+    HOSTNAME_KEY,
+    SENSOR_INTERVAL_KEY,
+    ALERT_RULES_KEY,
+    CREDENTIALS_KEY,
+];
+
+/// Key-value storage for the configuration. Keys are at most 15 bytes, the
+/// NVS limit.
+pub trait Storage: Send + 'static {
+    fn get(&self, key: &str) -> anyhow::Result<Option<String>>;
+
+    fn set(&mut self, key: &str, value: &str) -> anyhow::Result<()>;
+
+    /// Removing a key that isn't set is not an error.
+    fn remove(&mut self, key: &str) -> anyhow::Result<()>;
+}
+
+/// Most networks that can be stored besides `wifi_ssid`.
+pub const MAX_NETWORKS: usize = 8;
+
+/// A network the station may join, see [`ConfigStore::known_networks`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Network {
+    pub ssid: String,
+    /// Empty for an open network.
+    #[serde(default)]
+    pub psk: String,
+    /// Networks in range with a higher priority are preferred, regardless of
+    /// signal strength.
+    #[serde(default)]
+    pub priority: u8,
+}
+
+/// Values used for keys that were never set at runtime, usually `CONFIG`.
+#[derive(Clone, Debug)]
+pub struct Defaults {
+    pub wifi_ssid: &'static str,
+    pub wifi_psk: &'static str,
+    pub wifi_ap: bool,
+    pub hostname: &'static str,
+    pub sensor_interval_secs: u32,
+}
+
+/// A [`ConfigStore`] shared between the HTTP handlers and the rest of the app.
+pub type SharedConfig<S> = Arc<Mutex<ConfigStore<S>>>;
+
+pub struct ConfigStore<S> {
+    storage: S,
+    defaults: Defaults,
+    changes: u64,
+}
+
+impl<S: Storage> ConfigStore<S> {
+    /// Opens the configuration in `storage`, migrating it to the current
+    /// [`VERSION`] first. A configuration that can't be migrated, e.g. after
+    /// flashing older firmware, is reset to `defaults`.
+    pub fn open(storage: S, defaults: Defaults) -> anyhow::Result<Self> {
+        let mut store = Self {
+            storage,
+            defaults,
+            changes: 0,
+        };
+
+        match store.version()? {
+            Some(VERSION) => return Ok(store),
+            Some(version) if (1..VERSION).contains(&version) => {
+                for migrate in &MIGRATIONS[version as usize - 1..] {
+                    migrate(&mut store.storage)?;
+                }
+            }
+            Some(version) => {
+                eprintln!("Unknown configuration version {version}, resetting to defaults");
+                store.reset()?;
+            }
+            None => {}
+        }
+
+        store.storage.set(VERSION_KEY, &VERSION.to_string())?;
+        Ok(store)
+    }
+
+    /// Schema version of the stored configuration, `None` if nothing was
+    /// ever stored.
+    pub fn version(&self) -> anyhow::Result<Option<u32>> {
+        self.storage
+            .get(VERSION_KEY)?
+            .map(|version| version.parse().context("invalid configuration version"))
+            .transpose()
+    }
+
+    /// Forgets every value set at runtime.
+    pub fn reset(&mut self) -> anyhow::Result<()> {
+        for key in KEYS {
+            self.storage.remove(key)?;
+        }
+        self.changes += 1;
+
+        Ok(())
+    }
+
+    pub fn defaults(&self) -> &Defaults {
+        &self.defaults
+    }
+
+    /// Counts the changes made since the store was opened, so they can be
+    /// noticed by polling.
+    pub fn changes(&self) -> u64 {
+        self.changes
+    }
+
+    pub fn wifi_ssid(&self) -> anyhow::Result<String> {
+        Ok(self
+            .storage
+            .get(WIFI_SSID_KEY)?
+            .unwrap_or_else(|| self.defaults.wifi_ssid.into()))
+    }
+
+    pub fn set_wifi_ssid(&mut self, ssid: &str) -> anyhow::Result<()> {
+        self.set(WIFI_SSID_KEY, ssid)
+    }
+
+    pub fn wifi_psk(&self) -> anyhow::Result<String> {
+        Ok(self
+            .storage
+            .get(WIFI_PSK_KEY)?
+            .unwrap_or_else(|| self.defaults.wifi_psk.into()))
+    }
+
+    pub fn set_wifi_psk(&mut self, psk: &str) -> anyhow::Result<()> {
+        self.set(WIFI_PSK_KEY, psk)
+    }
+
+    /// Networks to join besides `wifi_ssid`, stored as JSON. None by default.
+    pub fn wifi_networks(&self) -> anyhow::Result<Vec<Network>> {
+        match self.storage.get(WIFI_NETWORKS_KEY)? {
+            Some(networks) => serde_json::from_str(&networks).context("invalid Wi-Fi networks"),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn set_wifi_networks(&mut self, networks: &[Network]) -> anyhow::Result<()> {
+        self.set(WIFI_NETWORKS_KEY, &serde_json::to_string(networks)?)
+    }
+
+    /// Every network the station may join: `wifi_ssid` with priority 0, unless
+    /// it is empty, followed by [`ConfigStore::wifi_networks`].
+    pub fn known_networks(&self) -> anyhow::Result<Vec<Network>> {
+        let mut networks = Vec::new();
+        let ssid = self.wifi_ssid()?;
+        if !ssid.is_empty() {
+            networks.push(Network {
+                ssid,
+                psk: self.wifi_psk()?,
+                priority: 0,
+            });
+        }
+        networks.extend(self.wifi_networks()?);
+        Ok(networks)
+    }
+
+    /// Whether to open an access point instead of joining a network.
+    pub fn wifi_ap(&self) -> anyhow::Result<bool> {
+        self.parsed(WIFI_AP_KEY, self.defaults.wifi_ap)
+    }
+
+    pub fn set_wifi_ap(&mut self, ap: bool) -> anyhow::Result<()> {
+        self.set(WIFI_AP_KEY, &ap.to_string())
+    }
+
+    /// Name announced to the DHCP server.
+    pub fn hostname(&self) -> anyhow::Result<String> {
+        Ok(self
+            .storage
+            .get(HOSTNAME_KEY)?
+            .unwrap_or_else(|| self.defaults.hostname.into()))
+    }
+
+    pub fn set_hostname(&mut self, hostname: &str) -> anyhow::Result<()> {
+        self.set(HOSTNAME_KEY, hostname)
+    }
+
+    /// How often the sensor is read, in whole seconds.
+    pub fn sensor_interval(&self) -> anyhow::Result<Duration> {
+        let secs = self.parsed(SENSOR_INTERVAL_KEY, self.defaults.sensor_interval_secs)?;
+        Ok(Duration::from_secs(secs.into()))
+    }
+
+    pub fn set_sensor_interval(&mut self, interval: Duration) -> anyhow::Result<()> {
+        self.set(SENSOR_INTERVAL_KEY, &interval.as_secs().to_string())
+    }
+
+    /// Stored as JSON, none by default.
+    pub fn alert_rules(&self) -> anyhow::Result<Vec<Rule>> {
+        match self.storage.get(ALERT_RULES_KEY)? {
+            Some(rules) => serde_json::from_str(&rules).context("invalid alert rules"),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn set_alert_rules(&mut self, rules: &[Rule]) -> anyhow::Result<()> {
+        self.set(ALERT_RULES_KEY, &serde_json::to_string(rules)?)
+    }
+
+    /// Users and API tokens, stored as JSON. None by default, which leaves
+    /// every route open.
+    pub fn credentials(&self) -> anyhow::Result<Credentials> {
+        match self.storage.get(CREDENTIALS_KEY)? {
+            Some(credentials) => serde_json::from_str(&credentials).context("invalid credentials"),
+            None => Ok(Credentials::default()),
+        }
+    }
+
+    pub fn set_credentials(&mut self, credentials: &Credentials) -> anyhow::Result<()> {
+        self.set(CREDENTIALS_KEY, &serde_json::to_string(credentials)?)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        self.storage.set(key, value)?;
+        self.changes += 1;
+        Ok(())
+    }
+
+    fn parsed<T: FromStr>(&self, key: &str, default: T) -> anyhow::Result<T> {
+        match self.storage.get(key)? {
+            Some(value) => value
+                .parse()
+                .map_err(|_| anyhow!("invalid value {value:?} for {key}")),
+            None => Ok(default),
+        }
+    }
+}
+
+/// Checks a network name for either mode.
+pub fn validate_ssid(ssid: &str) -> Result<(), &'static str> {
+    if ssid.is_empty() || ssid.len() > 32 {
+        return Err("The network name must be between 1 and 32 bytes long.");
+    }
+    Ok(())
+}
+
+/// Checks a non-empty password: a WPA2 passphrase, or for the station also a
+/// 64 digit hex key.
+pub fn validate_psk(psk: &str, ap: bool) -> Result<(), &'static str> {
+    match (psk.len(), ap) {
+        (8..=63, _) | (64, false) => Ok(()),
+        (_, true) => Err("The password must be between 8 and 63 bytes long."),
+        (_, false) => Err("The password must be between 8 and 64 bytes long."),
+    }
+}
+
+/// Checks the networks to join besides `wifi_ssid`.
+pub fn validate_networks(networks: &[Network]) -> Result<(), &'static str> {
+    if networks.len() > MAX_NETWORKS {
+        return Err("There can be at most 8 other networks.");
+    }
+    for network in networks {
+        validate_ssid(&network.ssid)?;
+        if !network.psk.is_empty() {
+            validate_psk(&network.psk, false)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn validate_hostname(hostname: &str) -> Result<(), &'static str> {
+    if hostname.is_empty()
+        || hostname.len() > 30
+        || hostname.starts_with('-')
+        || hostname.ends_with('-')
+        || !hostname
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return Err("The hostname must be 1 to 30 letters, digits or dashes, not starting or ending with a dash.");
+    }
+    Ok(())
+}
+
+pub fn validate_sensor_interval(secs: u32) -> Result<Duration, &'static str> {
+    match secs {
+        1..=3600 => Ok(Duration::from_secs(secs.into())),
+        _ => Err("The sensor interval must be between 1 and 3600 seconds."),
+    }
+}
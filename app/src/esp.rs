@@ -0,0 +1,643 @@
+use core::{
+    cmp::Reverse,
+    ffi,
+    fmt::{self, Debug},
+    ptr,
+};
+use std::{
+    ffi::CString,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::Context;
+use embedded_svc::{
+    http::{
+        server::{HandlerResult, Request},
+        Method,
+    },
+    ota::SlotState,
+    wifi::{AuthMethod, Configuration},
+};
+use esp_idf_hal::{
+    gpio::AnyIOPin,
+    i2c::{I2cConfig, I2cDriver, I2C0},
+    prelude::*,
+};
+use esp_idf_svc::handle::RawHandle;
+use esp_idf_svc::{
+    http::{
+        client::{self, EspHttpConnection as EspHttpClientConnection},
+        server::{
+            ws::{EspHttpWsConnection, EspHttpWsDetachedSender},
+            EspHttpConnection, EspHttpServer,
+        },
+    },
+    nvs::{EspNvs, NvsCustom, NvsDefault},
+    ota::EspOta,
+    wifi::EspWifi,
+};
+use esp_idf_sys::{
+    esp, esp_chip_info, esp_chip_info_t, esp_chip_model_t, esp_chip_model_t_CHIP_ESP32,
+    esp_chip_model_t_CHIP_ESP32C2, esp_chip_model_t_CHIP_ESP32C3, esp_chip_model_t_CHIP_ESP32C6,
+    esp_chip_model_t_CHIP_ESP32H2, esp_chip_model_t_CHIP_ESP32S2, esp_chip_model_t_CHIP_ESP32S3,
+    esp_crt_bundle_attach, esp_err_t, esp_get_free_heap_size, esp_get_minimum_free_heap_size,
+    esp_ota_get_next_update_partition, esp_reset_reason, esp_reset_reason_t_ESP_RST_BROWNOUT,
+    esp_reset_reason_t_ESP_RST_DEEPSLEEP, esp_reset_reason_t_ESP_RST_EXT,
+    esp_reset_reason_t_ESP_RST_INT_WDT, esp_reset_reason_t_ESP_RST_PANIC,
+    esp_reset_reason_t_ESP_RST_POWERON, esp_reset_reason_t_ESP_RST_SDIO,
+    esp_reset_reason_t_ESP_RST_SW, esp_reset_reason_t_ESP_RST_TASK_WDT,
+    esp_reset_reason_t_ESP_RST_WDT, esp_wifi_sta_get_ap_info, http_method_HTTP_GET, httpd_handle_t,
+    httpd_queue_work, httpd_register_uri_handler, httpd_req_get_hdr_value_len,
+    httpd_req_get_hdr_value_str, httpd_req_t, httpd_req_to_sockfd, httpd_socket_send, httpd_uri_t,
+    wifi_ap_record_t, EspError, ESP_ERR_INVALID_ARG, ESP_FAIL, ESP_OK,
+};
+use shtcx::{PowerMode, ShtC3};
+use wifi::{LinkState, WifiSupervisor};
+
+use crate::{
+    api::{Device, WifiMode, WifiStatus},
+    config::{SharedConfig, Storage},
+    manifest::{Download, Fetcher},
+    ota::Updater,
+    persist::BlobStorage,
+    provisioning::{Provisioner, ScannedNetwork},
+    sampler::Snapshot,
+    sensor::{Channel, Reading, Sensor, SensorError},
+    server::{Server, StreamSender},
+    system::{format_mac, Chip, Heap, Macs, Netif, Platform},
+};
+
+impl Server for EspHttpServer {
+    type Connection<'a> = EspHttpConnection<'a>;
+    type WsConnection = EspHttpWsConnection;
+    type WsSender = EspHttpWsDetachedSender;
+    type SseSender = EspSseSender;
+    type Error = EspError;
+
+    fn fn_handler<F>(&mut self, uri: &str, method: Method, f: F) -> Result<&mut Self, Self::Error>
+    where
+        F: for<'a> Fn(Request<&mut Self::Connection<'a>>) -> HandlerResult + Send + 'static,
+    {
+        EspHttpServer::fn_handler(self, uri, method, f)
+    }
+
+    fn ws_handler<F, E>(&mut self, uri: &str, f: F) -> Result<&mut Self, Self::Error>
+    where
+        F: Fn(&mut Self::WsConnection) -> Result<(), E> + Send + Sync + 'static,
+        E: Debug,
+    {
+        EspHttpServer::ws_handler(self, uri, f)
+    }
+
+    fn sse_handler<F>(&mut self, uri: &str, f: F) -> Result<&mut Self, Self::Error>
+    where
+        F: Fn(Option<&str>, Self::SseSender) -> Option<String> + Send + Sync + 'static,
+    {
+        // `EspHttpServer` would finish the response once the handler returns,
+        // so this one is registered with the httpd directly. The httpd copies
+        // the URI, but keeps the handler for as long as it runs.
+        let c_uri =
+            CString::new(uri).map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+        let handler: Box<SseHandler> = Box::new(Box::new(f));
+        let conf = httpd_uri_t {
+            uri: c_uri.as_ptr(),
+            method: http_method_HTTP_GET as _,
+            handler: Some(handle_sse),
+            user_ctx: Box::into_raw(handler) as *mut _,
+            ..Default::default()
+        };
+        esp!(unsafe { httpd_register_uri_handler(self.handle(), &conf) })?;
+
+        Ok(self)
+    }
+}
+
+type SseHandler = Box<dyn Fn(Option<&str>, EspSseSender) -> Option<String> + Send + Sync>;
+
+/// Answers with the head of the event stream and leaves the session open. The
+/// session context is set to the sender's `closed` flag, so the httpd raises
+/// it through [`release_sse_session`] when the session is closed.
+extern "C" fn handle_sse(raw_req: *mut httpd_req_t) -> esp_err_t {
+    let req = unsafe { raw_req.as_mut() }.unwrap();
+    let handler = unsafe { (req.user_ctx as *const SseHandler).as_ref() }.unwrap();
+    let fd = unsafe { httpd_req_to_sockfd(raw_req) };
+
+    let last_event_id = request_header(raw_req, b"Last-Event-ID\0");
+    let closed = Arc::new(AtomicBool::new(false));
+    let sender = EspSseSender {
+        server: req.handle,
+        fd,
+        closed: closed.clone(),
+    };
+
+    let head = match handler(last_event_id.as_deref(), sender) {
+        Some(first) => {
+            req.sess_ctx = Arc::into_raw(closed) as *mut _;
+            req.free_ctx = Some(release_sse_session);
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\r\n{first}"
+            )
+        }
+        None => "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n".to_owned(),
+    };
+
+    if send_all(req.handle, fd, head.as_bytes()) {
+        ESP_OK as _
+    } else {
+        ESP_FAIL
+    }
+}
+
+extern "C" fn release_sse_session(ctx: *mut ffi::c_void) {
+    let closed = unsafe { Arc::from_raw(ctx as *const AtomicBool) };
+    closed.store(true, Ordering::SeqCst);
+}
+
+fn request_header(raw_req: *mut httpd_req_t, name: &[u8]) -> Option<String> {
+    let name = name.as_ptr() as *const ffi::c_char;
+    let len = unsafe { httpd_req_get_hdr_value_len(raw_req, name) };
+    if len == 0 {
+        return None;
+    }
+
+    let mut value = vec![0u8; len + 1];
+    esp!(unsafe {
+        httpd_req_get_hdr_value_str(raw_req, name, value.as_mut_ptr() as *mut _, value.len())
+    })
+    .ok()?;
+    value.truncate(len);
+    String::from_utf8(value).ok()
+}
+
+/// Only to be called from the httpd task.
+fn send_all(server: httpd_handle_t, fd: ffi::c_int, mut data: &[u8]) -> bool {
+    while !data.is_empty() {
+        let sent =
+            unsafe { httpd_socket_send(server, fd, data.as_ptr() as *const _, data.len(), 0) };
+        if sent <= 0 {
+            return false;
+        }
+        data = &data[sent as usize..];
+    }
+    true
+}
+
+/// Writes to an event stream by queueing the write on the httpd task, like
+/// `EspHttpWsDetachedSender`, so it can't race with the httpd closing the
+/// session.
+///
+/// Unlike that, it doesn't wait for the write: events are also published from
+/// handlers running on the httpd task, like OTA progress, which would wait
+/// for themselves forever. A failed write closes the sender, so it is dropped
+/// on the next send.
+#[derive(Clone)]
+pub struct EspSseSender {
+    server: httpd_handle_t,
+    fd: ffi::c_int,
+    closed: Arc<AtomicBool>,
+}
+
+unsafe impl Send for EspSseSender {}
+
+struct QueuedSend {
+    server: httpd_handle_t,
+    fd: ffi::c_int,
+    data: Vec<u8>,
+    closed: Arc<AtomicBool>,
+}
+
+extern "C" fn send_queued(arg: *mut ffi::c_void) {
+    let send = unsafe { Box::from_raw(arg as *mut QueuedSend) };
+
+    if !send.closed.load(Ordering::SeqCst) && !send_all(send.server, send.fd, &send.data) {
+        send.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+impl StreamSender for EspSseSender {
+    type Error = EspError;
+
+    fn send(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        if self.is_closed() {
+            return Err(EspError::from_infallible::<ESP_FAIL>());
+        }
+
+        let send = Box::into_raw(Box::new(QueuedSend {
+            server: self.server,
+            fd: self.fd,
+            data: data.to_vec(),
+            closed: self.closed.clone(),
+        }));
+        let queued =
+            esp!(unsafe { httpd_queue_work(self.server, Some(send_queued), send as *mut _) });
+        if queued.is_err() {
+            // Never going to run, so it's still ours to free.
+            drop(unsafe { Box::from_raw(send) });
+        }
+        queued
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+}
+
+impl Storage for EspNvs<NvsDefault> {
+    fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        let Some(len) = self.str_len(key)? else {
+            return Ok(None);
+        };
+
+        let mut buf = vec![0; len];
+        // The length includes the NUL terminator.
+        let value = self.get_str(key, &mut buf)?.unwrap_or_default();
+        Ok(Some(value.trim_end_matches('\0').to_owned()))
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        Ok(self.set_str(key, value)?)
+    }
+
+    fn remove(&mut self, key: &str) -> anyhow::Result<()> {
+        EspNvs::remove(self, key)?;
+        Ok(())
+    }
+}
+
+impl BlobStorage for EspNvs<NvsCustom> {
+    fn read(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(len) = self.blob_len(key)? else {
+            return Ok(None);
+        };
+
+        let mut buf = vec![0; len];
+        let len = self.get_blob(key, &mut buf)?.map_or(0, <[u8]>::len);
+        buf.truncate(len);
+        Ok(Some(buf))
+    }
+
+    fn write(&mut self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+        Ok(self.set_blob(key, data)?)
+    }
+}
+
+/// The board as seen by the API.
+pub struct EspDevice {
+    wifi: Arc<WifiSupervisor>,
+    snapshot: Arc<Snapshot>,
+}
+
+impl EspDevice {
+    pub fn new(wifi: Arc<WifiSupervisor>, snapshot: Arc<Snapshot>) -> Self {
+        Self { wifi, snapshot }
+    }
+}
+
+impl Device for EspDevice {
+    fn uptime(&self) -> Duration {
+        let micros = unsafe { esp_idf_sys::esp_timer_get_time() };
+        Duration::from_micros(micros as u64)
+    }
+
+    fn wifi_status(&self) -> anyhow::Result<WifiStatus> {
+        let connected = self.wifi.state() == LinkState::Up;
+        let wifi = self.wifi.wifi();
+
+        let status = match wifi.get_configuration()? {
+            Configuration::Client(client) | Configuration::Mixed(client, _) => {
+                let mut ap_info = wifi_ap_record_t::default();
+                let associated = esp!(unsafe { esp_wifi_sta_get_ap_info(&mut ap_info) }).is_ok();
+
+                WifiStatus {
+                    mode: WifiMode::Station,
+                    ssid: client.ssid.as_str().into(),
+                    connected,
+                    ip: connected
+                        .then(|| wifi.sta_netif().get_ip_info())
+                        .transpose()?
+                        .map(|ip_info| ip_info.ip),
+                    rssi: associated.then_some(ap_info.rssi),
+                    channel: associated.then_some(ap_info.primary),
+                }
+            }
+            Configuration::AccessPoint(ap) => WifiStatus {
+                mode: WifiMode::AccessPoint,
+                ssid: ap.ssid.as_str().into(),
+                connected: true,
+                ip: Some(wifi.ap_netif().get_ip_info()?.ip),
+                rssi: None,
+                channel: Some(ap.channel),
+            },
+            Configuration::None => anyhow::bail!("wifi is not configured"),
+        };
+
+        Ok(status)
+    }
+
+    fn reading(&self) -> Option<Reading> {
+        self.snapshot.reading()
+    }
+
+    fn platform(&self) -> anyhow::Result<Platform> {
+        let mut chip = esp_chip_info_t::default();
+        unsafe { esp_chip_info(&mut chip) };
+
+        let (mode, connected) = match self.wifi_status() {
+            Ok(status) => (Some(status.mode), status.connected),
+            Err(_) => (None, false),
+        };
+        let wifi = self.wifi.wifi();
+        let ip_info = match mode {
+            Some(WifiMode::Station) if connected => Some(wifi.sta_netif().get_ip_info()?),
+            Some(WifiMode::AccessPoint) => Some(wifi.ap_netif().get_ip_info()?),
+            _ => None,
+        };
+
+        Ok(Platform {
+            reset_reason: reset_reason(),
+            heap: Some(Heap {
+                free_bytes: unsafe { esp_get_free_heap_size() } as usize,
+                min_free_bytes: unsafe { esp_get_minimum_free_heap_size() } as usize,
+            }),
+            chip: Chip {
+                model: chip_model(chip.model),
+                revision: chip.revision,
+                cores: chip.cores,
+            },
+            mac: Macs {
+                station: Some(format_mac(wifi.sta_netif().get_mac()?)),
+                access_point: Some(format_mac(wifi.ap_netif().get_mac()?)),
+            },
+            netif: ip_info.map(|ip_info| Netif {
+                ip: ip_info.ip,
+                prefix_len: ip_info.subnet.mask.0,
+                gateway: Some(ip_info.subnet.gateway).filter(|gateway| !gateway.is_unspecified()),
+                dns: ip_info.dns,
+            }),
+        })
+    }
+}
+
+#[allow(non_upper_case_globals)]
+fn reset_reason() -> &'static str {
+    match unsafe { esp_reset_reason() } {
+        esp_reset_reason_t_ESP_RST_POWERON => "power_on",
+        esp_reset_reason_t_ESP_RST_EXT => "external_pin",
+        esp_reset_reason_t_ESP_RST_SW => "software",
+        esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        esp_reset_reason_t_ESP_RST_INT_WDT => "interrupt_watchdog",
+        esp_reset_reason_t_ESP_RST_TASK_WDT => "task_watchdog",
+        esp_reset_reason_t_ESP_RST_WDT => "watchdog",
+        esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deep_sleep",
+        esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        esp_reset_reason_t_ESP_RST_SDIO => "sdio",
+        _ => "unknown",
+    }
+}
+
+#[allow(non_upper_case_globals)]
+fn chip_model(model: esp_chip_model_t) -> &'static str {
+    match model {
+        esp_chip_model_t_CHIP_ESP32 => "ESP32",
+        esp_chip_model_t_CHIP_ESP32S2 => "ESP32-S2",
+        esp_chip_model_t_CHIP_ESP32S3 => "ESP32-S3",
+        esp_chip_model_t_CHIP_ESP32C3 => "ESP32-C3",
+        esp_chip_model_t_CHIP_ESP32C2 => "ESP32-C2",
+        esp_chip_model_t_CHIP_ESP32C6 => "ESP32-C6",
+        esp_chip_model_t_CHIP_ESP32H2 => "ESP32-H2",
+        _ => "unknown",
+    }
+}
+
+/// Where the sensor is connected, see `cfg.toml`.
+#[derive(Copy, Clone, Debug)]
+pub struct I2cBus {
+    pub sda: i32,
+    pub scl: i32,
+    pub baudrate_khz: u32,
+}
+
+/// A Sensirion SHTC3 on I2C, measuring both temperature and humidity.
+pub struct Shtc3Sensor {
+    sensor: ShtC3<I2cDriver<'static>>,
+}
+
+impl Shtc3Sensor {
+    pub fn new(i2c: I2C0, bus: I2cBus) -> Result<Self, EspError> {
+        // Safety: the pins are chosen in the configuration rather than taken
+        // from `Peripherals`, and nothing else in the app uses any.
+        let (sda, scl) = unsafe { (AnyIOPin::new(bus.sda), AnyIOPin::new(bus.scl)) };
+        let config = I2cConfig::new().baudrate(bus.baudrate_khz.kHz().into());
+        let i2c = I2cDriver::new(i2c, sda, scl, &config)?;
+
+        Ok(Self {
+            sensor: shtcx::shtc3(i2c),
+        })
+    }
+}
+
+impl Sensor for Shtc3Sensor {
+    fn channels(&self) -> &'static [Channel] {
+        &Channel::ALL
+    }
+
+    fn read(&mut self) -> Result<Reading, SensorError> {
+        self.sensor
+            .start_measurement(PowerMode::NormalMode)
+            .map_err(sensor_error)?;
+        let duration = shtcx::max_measurement_duration(&self.sensor, PowerMode::NormalMode);
+        thread::sleep(Duration::from_micros(duration.into()));
+
+        let measurement = self.sensor.get_measurement_result().map_err(sensor_error)?;
+        Ok(Reading {
+            temperature_celsius: measurement.temperature.as_degrees_celsius(),
+            humidity_percent: measurement.humidity.as_percent(),
+        })
+    }
+}
+
+fn sensor_error<E: fmt::Display>(err: shtcx::Error<E>) -> SensorError {
+    match err {
+        shtcx::Error::I2c(err) => SensorError::Bus(err.to_string()),
+        shtcx::Error::Crc => SensorError::Checksum,
+    }
+}
+
+pub struct EspProvisioner {
+    wifi: Mutex<Box<EspWifi<'static>>>,
+    config: SharedConfig<EspNvs<NvsDefault>>,
+    reason: Option<String>,
+}
+
+impl EspProvisioner {
+    /// `wifi` must already be in provisioning mode, see
+    /// [`wifi::start_provisioning`].
+    pub fn new(
+        wifi: Box<EspWifi<'static>>,
+        config: SharedConfig<EspNvs<NvsDefault>>,
+        reason: Option<String>,
+    ) -> Self {
+        Self {
+            wifi: Mutex::new(wifi),
+            config,
+            reason,
+        }
+    }
+}
+
+impl Provisioner for EspProvisioner {
+    fn scan(&self) -> anyhow::Result<Vec<ScannedNetwork>> {
+        let mut ap_infos = self.wifi.lock().unwrap().scan()?;
+        ap_infos.sort_by_key(|ap| Reverse(ap.signal_strength));
+
+        let mut networks: Vec<ScannedNetwork> = Vec::new();
+        for ap in ap_infos {
+            // Hidden networks can't be picked, and only the strongest access
+            // point of each network is interesting.
+            if ap.ssid.is_empty() || networks.iter().any(|n| n.ssid == ap.ssid.as_str()) {
+                continue;
+            }
+            networks.push(ScannedNetwork {
+                ssid: ap.ssid.as_str().into(),
+                signal_strength: ap.signal_strength,
+                open: ap.auth_method == AuthMethod::None,
+            });
+        }
+
+        Ok(networks)
+    }
+
+    fn reason(&self) -> Option<String> {
+        self.reason.clone()
+    }
+
+    fn save(&self, ssid: &str, password: &str) -> anyhow::Result<()> {
+        let mut config = self.config.lock().unwrap();
+        config.set_wifi_ssid(ssid)?;
+        config.set_wifi_psk(password)?;
+        // Joining the network is the point of provisioning.
+        config.set_wifi_ap(false)
+    }
+
+    fn restart(&self) {
+        thread::spawn(|| {
+            thread::sleep(Duration::from_secs(1));
+            esp_idf_hal::reset::restart();
+        });
+    }
+}
+
+/// The OTA slot of `partitions.csv` that isn't running.
+pub struct EspUpdater {
+    ota: EspOta,
+}
+
+impl EspUpdater {
+    pub fn new() -> Result<Self, EspError> {
+        Ok(Self {
+            ota: EspOta::new()?,
+        })
+    }
+}
+
+impl Updater for EspUpdater {
+    fn slot_size(&self) -> anyhow::Result<usize> {
+        let partition = unsafe { esp_ota_get_next_update_partition(ptr::null()).as_ref() };
+        let partition = partition.context("there is no OTA slot to update")?;
+        Ok(partition.size as usize)
+    }
+
+    fn begin(&mut self) -> anyhow::Result<()> {
+        self.ota.initiate_update()?;
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let update = self.ota.get_update().context("no update in progress")?;
+        update.write(data)?;
+        Ok(())
+    }
+
+    fn complete(&mut self) -> anyhow::Result<()> {
+        let update = self.ota.get_update().context("no update in progress")?;
+        Ok(update.complete()?)
+    }
+
+    fn abort(&mut self) -> anyhow::Result<()> {
+        let update = self.ota.get_update().context("no update in progress")?;
+        Ok(update.abort()?)
+    }
+
+    fn restart(&self) {
+        thread::spawn(|| {
+            thread::sleep(Duration::from_secs(1));
+            esp_idf_hal::reset::restart();
+        });
+    }
+
+    fn pending_verify(&self) -> anyhow::Result<bool> {
+        Ok(self.ota.get_running_slot()?.state == SlotState::Unverified)
+    }
+
+    fn mark_valid(&mut self) -> anyhow::Result<()> {
+        Ok(self.ota.mark_running_slot_valid()?)
+    }
+
+    fn roll_back(&mut self) -> anyhow::Result<()> {
+        // Only returns if it failed.
+        Err(self.ota.mark_running_slot_invalid_and_reboot().into())
+    }
+
+    fn rolled_back(&self) -> anyhow::Result<bool> {
+        Ok(self.ota.get_last_invalid_slot()?.is_some())
+    }
+}
+
+/// How long the [`EspFetcher`] waits for the server.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// [`Fetcher`] on the ESP-IDF HTTP client, checking HTTPS servers against the
+/// certificate bundle of ESP-IDF. Only `https://` URLs are fetched, and
+/// redirects aren't followed: the SHA-256 an image is checked against comes
+/// from the manifest, so whoever could change one in transit could change
+/// both.
+pub struct EspFetcher;
+
+impl Fetcher for EspFetcher {
+    fn get(&mut self, url: &str) -> anyhow::Result<Box<dyn Download + '_>> {
+        let https = url
+            .get(..8)
+            .is_some_and(|scheme| scheme.eq_ignore_ascii_case("https://"));
+        anyhow::ensure!(https, "only https:// URLs can be fetched, not {url}");
+
+        // A connection per request, they can't be moved between threads.
+        let mut connection = EspHttpClientConnection::new(&client::Configuration {
+            timeout: Some(FETCH_TIMEOUT),
+            follow_redirects_policy: client::FollowRedirectsPolicy::FollowNone,
+            crt_bundle_attach: Some(esp_crt_bundle_attach),
+            ..Default::default()
+        })?;
+        connection.initiate_request(Method::Get, url, &[])?;
+        connection.initiate_response()?;
+        Ok(Box::new(connection))
+    }
+}
+
+impl Download for EspHttpClientConnection {
+    fn status(&self) -> u16 {
+        EspHttpClientConnection::status(self)
+    }
+
+    fn content_len(&self) -> Option<u64> {
+        self.header("Content-Length")?.parse().ok()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        Ok(EspHttpClientConnection::read(self, buf)?)
+    }
+}
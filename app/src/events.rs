@@ -0,0 +1,170 @@
+//! Server-sent events on `/api/v1/events`: sensor readings and system events,
+//! for clients that can't do WebSockets. Every event has an ID, so a client
+//! that reconnects with `Last-Event-ID` gets what it missed, as far as that is
+//! still in the backlog.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    alerts::Notification,
+    api::ConfigBody,
+    ota::OtaStatus,
+    sensor::Reading,
+    server::{Server, StreamSender},
+};
+
+/// Clients served at once by default. Like WebSocket clients, each keeps one
+/// of the httpd's sockets.
+pub const MAX_CLIENTS: usize = 2;
+
+/// Events kept for clients that reconnect, by default.
+pub const BACKLOG_LEN: usize = 32;
+
+/// How long a client waits before reconnecting, in milliseconds.
+const RETRY_MS: u32 = 3000;
+
+#[derive(Clone, Debug)]
+pub enum Event {
+    Reading(Reading),
+    /// The Wi-Fi station lost or regained its connection.
+    Wifi {
+        connected: bool,
+    },
+    /// The runtime configuration was changed.
+    Config(ConfigBody),
+    /// An alert became active or was cleared.
+    Alert(Notification),
+    /// A firmware update made progress, see [`crate::ota`].
+    Ota(OtaStatus),
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Reading(_) => "reading",
+            Self::Wifi { .. } => "wifi",
+            Self::Config(_) => "config",
+            Self::Alert(_) => "alert",
+            Self::Ota(_) => "ota",
+        }
+    }
+
+    fn data(&self) -> String {
+        match self {
+            Self::Reading(reading) => serde_json::to_string(reading),
+            Self::Wifi { connected } => {
+                serde_json::to_string(&serde_json::json!({ "connected": connected }))
+            }
+            Self::Config(config) => serde_json::to_string(config),
+            Self::Alert(notification) => serde_json::to_string(notification),
+            Self::Ota(status) => serde_json::to_string(status),
+        }
+        .unwrap()
+    }
+}
+
+pub struct EventStream<S> {
+    max_clients: usize,
+    backlog_len: usize,
+    state: Mutex<State<S>>,
+}
+
+struct State<S> {
+    next_id: u64,
+    /// Recent events with their IDs, already formatted for the stream.
+    backlog: VecDeque<(u64, String)>,
+    clients: Vec<S>,
+}
+
+impl<S: StreamSender> EventStream<S> {
+    pub fn new(max_clients: usize, backlog_len: usize) -> Self {
+        Self {
+            max_clients,
+            backlog_len,
+            state: Mutex::new(State {
+                next_id: 1,
+                backlog: VecDeque::with_capacity(backlog_len),
+                clients: Vec::new(),
+            }),
+        }
+    }
+
+    /// Number of connected clients.
+    pub fn clients(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.clients.retain(|client| !client.is_closed());
+        state.clients.len()
+    }
+
+    /// Sends `event` to every client and keeps it in the backlog.
+    pub fn publish(&self, event: &Event) {
+        // Like for WebSockets, sending may wait for the httpd, so the lock is
+        // not held meanwhile.
+        let (message, clients) = {
+            let mut state = self.state.lock().unwrap();
+            let id = state.next_id;
+            state.next_id += 1;
+
+            let message = format!(
+                "id: {id}\nevent: {}\ndata: {}\n\n",
+                event.name(),
+                event.data()
+            );
+            if state.backlog.len() == self.backlog_len {
+                state.backlog.pop_front();
+            }
+            state.backlog.push_back((id, message.clone()));
+
+            (message, state.clients.clone())
+        };
+
+        let mut failed = false;
+        for mut client in clients {
+            failed |= client.send(message.as_bytes()).is_err();
+        }
+
+        if failed {
+            let mut state = self.state.lock().unwrap();
+            state.clients.retain(|client| !client.is_closed());
+        }
+    }
+
+    /// Adds a client, returning the start of its stream: the events after
+    /// `last_event_id`, if it has one. `None` if there are too many clients
+    /// already.
+    pub fn subscribe(&self, last_event_id: Option<&str>, sender: S) -> Option<String> {
+        let mut state = self.state.lock().unwrap();
+        state.clients.retain(|client| !client.is_closed());
+        if state.clients.len() >= self.max_clients {
+            return None;
+        }
+
+        let mut start = format!("retry: {RETRY_MS}\n\n");
+        if let Some(last_id) = last_event_id.and_then(|id| id.trim().parse::<u64>().ok()) {
+            // An ID from the future is from before a restart, so everything
+            // is new to that client.
+            let last_id = if last_id >= state.next_id { 0 } else { last_id };
+            for (_, message) in state.backlog.iter().filter(|(id, _)| *id > last_id) {
+                start.push_str(message);
+            }
+        }
+
+        state.clients.push(sender);
+        Some(start)
+    }
+}
+
+/// Serves `events` on `/api/v1/events`. Has to be called before the router is
+/// mounted.
+pub fn register<Srv: Server>(
+    server: &mut Srv,
+    events: Arc<EventStream<Srv::SseSender>>,
+) -> Result<(), Srv::Error> {
+    server.sse_handler("/api/v1/events", move |last_event_id, sender| {
+        events.subscribe(last_event_id, sender)
+    })?;
+    Ok(())
+}
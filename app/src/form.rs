@@ -0,0 +1,88 @@
+//! `application/x-www-form-urlencoded` request bodies.
+
+use core::fmt;
+
+use embedded_svc::http::{
+    server::{Connection, Request},
+    Headers,
+};
+
+/// Why a request body couldn't be read.
+#[derive(Debug)]
+pub enum BodyError {
+    TooLarge {
+        max_len: usize,
+    },
+    NotUtf8,
+    /// Receiving the body failed.
+    Io(anyhow::Error),
+}
+
+impl BodyError {
+    /// The HTTP status to answer with.
+    pub fn status(&self) -> u16 {
+        match self {
+            Self::TooLarge { .. } => 413,
+            Self::NotUtf8 => 400,
+            Self::Io(_) => 500,
+        }
+    }
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLarge { max_len } => {
+                write!(f, "The request body must be at most {max_len} bytes long.")
+            }
+            Self::NotUtf8 => write!(f, "The request body is not UTF-8."),
+            Self::Io(err) => write!(f, "Receiving the request body failed: {err:#}"),
+        }
+    }
+}
+
+impl std::error::Error for BodyError {}
+
+/// Reads the whole body of `request`, failing if it is longer than `max_len`.
+/// Only as much is allocated as the body announced, and later read.
+pub fn read_body<C: Connection>(
+    request: &mut Request<C>,
+    max_len: usize,
+) -> Result<String, BodyError> {
+    let announced = request.content_len().unwrap_or(0);
+    if announced > max_len as u64 {
+        return Err(BodyError::TooLarge { max_len });
+    }
+
+    let mut body = Vec::with_capacity(announced as usize);
+    let mut chunk = [0; 128];
+    loop {
+        let read = request
+            .read(&mut chunk)
+            .map_err(|err| BodyError::Io(anyhow::anyhow!("{err:?}")))?;
+        if read == 0 {
+            break;
+        }
+        if body.len() + read > max_len {
+            return Err(BodyError::TooLarge { max_len });
+        }
+        body.extend_from_slice(&chunk[..read]);
+    }
+
+    String::from_utf8(body).map_err(|_| BodyError::NotUtf8)
+}
+
+/// Splits `body` into decoded name/value pairs.
+pub fn parse(body: &str) -> Vec<(String, String)> {
+    // Pairs of strings can't fail to deserialize, malformed escapes are kept
+    // as they are.
+    serde_urlencoded::from_str(body).unwrap_or_default()
+}
+
+/// Value of the first field called `name`.
+pub fn field<'a>(fields: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    fields
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, v)| v.as_str())
+}
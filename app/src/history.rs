@@ -0,0 +1,459 @@
+//! Past readings, kept at several resolutions: finely for the last minutes and
+//! ever more coarsely for the last day and week. Served on
+//! `/api/v1/history`, as JSON, and on `/api/v1/history.csv`.
+//!
+//! Every resolution is a ring buffer of the mean readings over its period,
+//! allocated up front so the history never takes more RAM than it was given.
+
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    mem,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use embedded_svc::{
+    http::server::{Connection, HandlerResult, Request},
+    io::Write,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    router::{reject, Params, Router},
+    sensor::Reading,
+    server::Server,
+};
+
+/// One resolution of the history: a point per `period_secs`, for the last
+/// `span_secs`, as far as the RAM budget allows.
+#[derive(Copy, Clone, Debug)]
+pub struct Tier {
+    pub period_secs: u32,
+    pub span_secs: u32,
+}
+
+/// Every second for ten minutes, every minute for a day and every quarter of
+/// an hour for a week, which takes about 32 KiB.
+pub const DEFAULT_TIERS: [Tier; 3] = [
+    Tier {
+        period_secs: 1,
+        span_secs: 10 * 60,
+    },
+    Tier {
+        period_secs: 60,
+        span_secs: 24 * 60 * 60,
+    },
+    Tier {
+        period_secs: 15 * 60,
+        span_secs: 7 * 24 * 60 * 60,
+    },
+];
+
+/// The mean reading over the period starting at `time`, in seconds since the
+/// Unix epoch.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+pub struct Point {
+    pub time: u32,
+    pub temperature_celsius: f32,
+    pub humidity_percent: f32,
+}
+
+/// Readings of a period that isn't over yet.
+#[derive(Copy, Clone, Debug)]
+struct Bucket {
+    start: u32,
+    temperature_celsius: f32,
+    humidity_percent: f32,
+    count: u32,
+}
+
+impl Bucket {
+    fn new(start: u32) -> Self {
+        Self {
+            start,
+            temperature_celsius: 0.0,
+            humidity_percent: 0.0,
+            count: 0,
+        }
+    }
+
+    fn add(&mut self, temperature_celsius: f32, humidity_percent: f32) {
+        self.temperature_celsius += temperature_celsius;
+        self.humidity_percent += humidity_percent;
+        self.count += 1;
+    }
+
+    fn mean(&self) -> Point {
+        Point {
+            time: self.start,
+            temperature_celsius: self.temperature_celsius / self.count as f32,
+            humidity_percent: self.humidity_percent / self.count as f32,
+        }
+    }
+}
+
+struct Ring {
+    period_secs: u32,
+    capacity: usize,
+    points: VecDeque<Point>,
+    bucket: Option<Bucket>,
+}
+
+impl Ring {
+    fn record(&mut self, time: u32, reading: &Reading) {
+        let start = time - time % self.period_secs;
+        match &mut self.bucket {
+            Some(bucket) if bucket.start == start => {}
+            bucket => {
+                if let Some(done) = bucket.replace(Bucket::new(start)) {
+                    if self.points.len() == self.capacity {
+                        self.points.pop_front();
+                    }
+                    self.points.push_back(done.mean());
+                }
+            }
+        }
+        self.bucket
+            .as_mut()
+            .unwrap()
+            .add(reading.temperature_celsius, reading.humidity_percent);
+    }
+
+    /// Whether this ring still has everything since `time`.
+    fn covers(&self, time: u32) -> bool {
+        self.points.len() < self.capacity
+            || self.points.front().is_some_and(|point| point.time <= time)
+    }
+
+    /// The finished points and the one in progress.
+    fn points(&self) -> impl Iterator<Item = Point> + '_ {
+        let current = self.bucket.as_ref().map(Bucket::mean);
+        self.points.iter().copied().chain(current)
+    }
+}
+
+pub struct History {
+    rings: Vec<Ring>,
+    changes: u64,
+}
+
+/// A [`History`] shared between the sampler and the HTTP handlers.
+pub type SharedHistory = Arc<Mutex<History>>;
+
+impl History {
+    /// Panics unless `tiers` are ordered from the finest to the coarsest.
+    /// If they don't all fit into `ram_budget` bytes, every tier is shortened
+    /// by the same factor.
+    pub fn new(tiers: &[Tier], ram_budget: usize) -> Self {
+        assert!(!tiers.is_empty(), "a history needs at least one tier");
+        assert!(
+            tiers
+                .windows(2)
+                .all(|pair| pair[0].period_secs < pair[1].period_secs),
+            "history tiers must go from the finest to the coarsest"
+        );
+
+        let wanted: Vec<usize> = tiers
+            .iter()
+            .map(|tier| (tier.span_secs / tier.period_secs).max(1) as usize)
+            .collect();
+        let total: usize = wanted.iter().sum();
+        let affordable = ram_budget / mem::size_of::<Point>();
+
+        let rings = tiers
+            .iter()
+            .zip(wanted)
+            .map(|(tier, wanted)| {
+                let capacity = if total <= affordable {
+                    wanted
+                } else {
+                    (wanted * affordable / total).max(1)
+                };
+                Ring {
+                    period_secs: tier.period_secs,
+                    capacity,
+                    points: VecDeque::with_capacity(capacity),
+                    bucket: None,
+                }
+            })
+            .collect();
+
+        Self { rings, changes: 0 }
+    }
+
+    /// Adds `reading`, taken at `time` seconds since the Unix epoch.
+    pub fn record(&mut self, time: u32, reading: &Reading) {
+        for ring in &mut self.rings {
+            ring.record(time, reading);
+        }
+        self.changes += 1;
+    }
+
+    /// Counts the readings recorded, so that whoever saves the history can
+    /// tell whether it changed since.
+    pub fn changes(&self) -> u64 {
+        self.changes
+    }
+
+    /// The finished points of every tier, as read by [`Self::restore`]: the
+    /// number of tiers as a byte, then for each its period and number of
+    /// points as little-endian `u32`s, followed by the points as a `u32` time
+    /// and two `f32`s.
+    pub fn encode(&self) -> Vec<u8> {
+        let points: usize = self.rings.iter().map(|ring| ring.points.len()).sum();
+        let mut encoded = Vec::with_capacity(1 + self.rings.len() * 8 + points * POINT_LEN);
+
+        encoded.push(self.rings.len() as u8);
+        for ring in &self.rings {
+            encoded.extend_from_slice(&ring.period_secs.to_le_bytes());
+            encoded.extend_from_slice(&(ring.points.len() as u32).to_le_bytes());
+            for point in &ring.points {
+                encoded.extend_from_slice(&point.time.to_le_bytes());
+                encoded.extend_from_slice(&point.temperature_celsius.to_le_bytes());
+                encoded.extend_from_slice(&point.humidity_percent.to_le_bytes());
+            }
+        }
+        encoded
+    }
+
+    /// Replaces the points of every tier with those in `encoded`, as written
+    /// by [`Self::encode`]. Tiers that aren't configured anymore are skipped,
+    /// and only the latest points are kept of tiers that have become shorter.
+    /// Nothing is changed if `encoded` is malformed.
+    pub fn restore(&mut self, encoded: &[u8]) -> anyhow::Result<()> {
+        let mut decoder = Decoder(encoded);
+        let mut tiers = Vec::new();
+        for _ in 0..decoder.u8()? {
+            let period_secs = decoder.u32()?;
+            let len = decoder.u32()? as usize;
+            anyhow::ensure!(len * POINT_LEN <= decoder.0.len(), "history is truncated");
+            let points: Vec<_> = (0..len)
+                .map(|_| {
+                    Ok(Point {
+                        time: decoder.u32()?,
+                        temperature_celsius: f32::from_bits(decoder.u32()?),
+                        humidity_percent: f32::from_bits(decoder.u32()?),
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?;
+            tiers.push((period_secs, points));
+        }
+        anyhow::ensure!(decoder.0.is_empty(), "history has trailing bytes");
+
+        for (period_secs, points) in tiers {
+            let Some(ring) = self
+                .rings
+                .iter_mut()
+                .find(|ring| ring.period_secs == period_secs)
+            else {
+                continue;
+            };
+            let skip = points.len().saturating_sub(ring.capacity);
+            ring.points.clear();
+            ring.points.extend(&points[skip..]);
+            ring.bucket = None;
+        }
+        Ok(())
+    }
+
+    /// The points from `from` to `to`, both inclusive. Without a
+    /// `resolution`, they come from the finest tier that goes back to
+    /// `from`; with one, from the coarsest tier that is at least as fine,
+    /// merged into points of `resolution` seconds if necessary.
+    pub fn query(&self, from: u32, to: u32, resolution: Option<u32>) -> Series {
+        let ring = match resolution {
+            Some(resolution) => self
+                .rings
+                .iter()
+                .rev()
+                .find(|ring| ring.period_secs <= resolution)
+                .unwrap_or(&self.rings[0]),
+            None => self
+                .rings
+                .iter()
+                .find(|ring| ring.covers(from))
+                .unwrap_or(self.rings.last().unwrap()),
+        };
+        let resolution_secs = resolution.unwrap_or(0).max(ring.period_secs);
+        let points = ring
+            .points()
+            .filter(|point| (from..=to).contains(&point.time));
+
+        let points = if resolution_secs == ring.period_secs {
+            points.collect()
+        } else {
+            let mut merged = Vec::new();
+            let mut bucket: Option<Bucket> = None;
+            for point in points {
+                let start = point.time - point.time % resolution_secs;
+                if bucket.is_some_and(|bucket| bucket.start != start) {
+                    merged.extend(bucket.take().map(|bucket| bucket.mean()));
+                }
+                bucket
+                    .get_or_insert_with(|| Bucket::new(start))
+                    .add(point.temperature_celsius, point.humidity_percent);
+            }
+            merged.extend(bucket.map(|bucket| bucket.mean()));
+            merged
+        };
+
+        Series {
+            resolution_secs,
+            points,
+        }
+    }
+
+    /// The RAM taken by the points, allocated up front.
+    pub fn ram_usage(&self) -> usize {
+        self.rings
+            .iter()
+            .map(|ring| ring.points.capacity() * mem::size_of::<Point>())
+            .sum()
+    }
+}
+
+/// Responses are sent in pieces of about this many bytes instead of being
+/// built whole, which for a day of minutes would take about 100 KB.
+const CHUNK_LEN: usize = 1024;
+
+/// Bytes per point in [`History::encode`].
+const POINT_LEN: usize = 12;
+
+/// Reads little-endian numbers off the front of a slice.
+struct Decoder<'a>(&'a [u8]);
+
+impl Decoder<'_> {
+    fn take<const N: usize>(&mut self) -> anyhow::Result<[u8; N]> {
+        anyhow::ensure!(self.0.len() >= N, "history is truncated");
+        let (bytes, rest) = self.0.split_at(N);
+        self.0 = rest;
+        Ok(bytes.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+}
+
+/// What `/api/v1/history` answers with.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Series {
+    pub resolution_secs: u32,
+    pub points: Vec<Point>,
+}
+
+/// Times before this, in November 2023, can only come from a clock that SNTP
+/// hasn't set yet.
+pub const MIN_TIME: u32 = 1_700_000_000;
+
+/// Whether readings taken at `time` belong in the history: until the clock is
+/// set, they would be filed decades back, ahead of everything else.
+pub fn clock_is_set(time: u32) -> bool {
+    time >= MIN_TIME
+}
+
+/// The time to record readings at, in seconds since the Unix epoch; on the
+/// device, that's only right once SNTP has synchronized the clock, see
+/// [`clock_is_set`].
+pub fn now() -> u32 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    since_epoch.as_secs().try_into().unwrap_or(u32::MAX)
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    from: Option<u32>,
+    to: Option<u32>,
+    resolution: Option<u32>,
+}
+
+pub fn register<Srv: Server + 'static>(router: &mut Router<Srv>, history: SharedHistory) {
+    {
+        let history = history.clone();
+        router.get("/api/v1/history", move |request, params| {
+            match series(&history, params) {
+                Ok(series) => respond(request, &series),
+                Err(message) => reject(request, 400, &message),
+            }
+        });
+    }
+    router.get("/api/v1/history.csv", move |request, params| {
+        match series(&history, params) {
+            Ok(series) => csv(request, &series),
+            Err(message) => reject(request, 400, &message),
+        }
+    });
+}
+
+fn series(history: &SharedHistory, params: &Params) -> Result<Series, String> {
+    let query = params
+        .query::<HistoryQuery>()
+        .map_err(|err| err.to_string())?;
+    let (from, to) = (query.from.unwrap_or(0), query.to.unwrap_or(u32::MAX));
+    if from > to {
+        return Err("from must not be after to".into());
+    }
+    if query.resolution == Some(0) {
+        return Err("resolution must be at least one second".into());
+    }
+
+    Ok(history.lock().unwrap().query(from, to, query.resolution))
+}
+
+/// Serializes like [`json::respond`](crate::json::respond) would, a chunk at
+/// a time.
+fn respond<C: Connection>(request: Request<C>, series: &Series) -> HandlerResult {
+    let mut response =
+        request.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?;
+    let mut chunk = format!(
+        r#"{{"resolution_secs":{},"points":["#,
+        series.resolution_secs
+    )
+    .into_bytes();
+    for (i, point) in series.points.iter().enumerate() {
+        if i > 0 {
+            chunk.push(b',');
+        }
+        serde_json::to_writer(&mut chunk, point)?;
+        if chunk.len() >= CHUNK_LEN {
+            response.write_all(&chunk)?;
+            chunk.clear();
+        }
+    }
+    chunk.extend_from_slice(b"]}");
+    Ok(response.write_all(&chunk)?)
+}
+
+fn csv<C: Connection>(request: Request<C>, series: &Series) -> HandlerResult {
+    let mut response = request.into_response(
+        200,
+        Some("OK"),
+        &[
+            ("Content-Type", "text/csv; charset=utf-8"),
+            (
+                "Content-Disposition",
+                "attachment; filename=\"history.csv\"",
+            ),
+        ],
+    )?;
+    let mut chunk = String::from("time,temperature_celsius,humidity_percent\r\n");
+    for point in &series.points {
+        write!(
+            chunk,
+            "{},{},{}\r\n",
+            point.time, point.temperature_celsius, point.humidity_percent
+        )?;
+        if chunk.len() >= CHUNK_LEN {
+            response.write_all(chunk.as_bytes())?;
+            chunk.clear();
+        }
+    }
+    Ok(response.write_all(chunk.as_bytes())?)
+}
@@ -0,0 +1,1216 @@
+//! A minimal HTTP/1.1 server on top of `std::net`, standing in for the ESP-IDF
+//! httpd when the app runs on the build machine.
+//!
+//! Like the httpd, connections are served one at a time from a single thread,
+//! URIs are matched exactly (ignoring the query string) unless wildcards are
+//! enabled, and a handler that doesn't send a response gets an empty `200 OK`.
+//! WebSocket sessions get a thread of their own once the handshake is done.
+
+use core::fmt::Debug;
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, BufRead, BufReader, Write as _},
+    net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use embedded_svc::{
+    http::{
+        server::{Connection, FnHandler, Handler, HandlerResult, Request},
+        Headers, Method, Query,
+    },
+    io::{Io, Read, Write},
+    ws::{
+        callback_server::{SenderFactory, SessionProvider},
+        ErrorType, FrameType, Receiver, Sender,
+    },
+};
+use sha1::{Digest, Sha1};
+
+use crate::{
+    api::{Device, WifiMode, WifiStatus},
+    config::Storage,
+    manifest::{Download, Fetcher},
+    ota::Updater,
+    persist::BlobStorage,
+    sampler::Snapshot,
+    sensor::Reading,
+    server::{Server, StreamSender},
+    system::{Chip, Macs, Netif, Platform},
+};
+
+#[derive(Copy, Clone, Debug)]
+pub struct Configuration {
+    /// Port to listen on, `0` picks a free one (see [`HostServer::local_addr`]).
+    pub http_port: u16,
+    /// Whether a URI ending in `*` matches every path starting with the rest
+    /// of it, like `uri_match_wildcard` of the httpd.
+    pub uri_match_wildcard: bool,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Self {
+            http_port: 8080,
+            uri_match_wildcard: false,
+        }
+    }
+}
+
+type BoxedHandler = Box<dyn for<'a> Handler<HostConnection<'a>>>;
+
+type WsHandler = Arc<dyn Fn(&mut HostWsConnection) -> Result<(), String> + Send + Sync>;
+
+type SseHandler = Arc<dyn Fn(Option<&str>, HostSseSender) -> Option<String> + Send + Sync>;
+
+enum Handlers {
+    Http(Method, BoxedHandler),
+    Ws(WsHandler),
+    Sse(SseHandler),
+}
+
+struct Registration {
+    uri: String,
+    handlers: Handlers,
+}
+
+impl Registration {
+    fn method(&self) -> Method {
+        match self.handlers {
+            Handlers::Http(method, _) => method,
+            Handlers::Ws(_) | Handlers::Sse(_) => Method::Get,
+        }
+    }
+}
+
+/// How long a connection may stall while a request is read or its response
+/// written, like the `recv_wait_timeout` and `send_wait_timeout` of the
+/// ESP-IDF httpd. Requests are served one at a time, so without it a client
+/// that sends nothing would hold up everyone else.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Listens on `127.0.0.1` until the process exits.
+pub struct HostServer {
+    local_addr: SocketAddr,
+    registrations: Arc<Mutex<Vec<Registration>>>,
+}
+
+impl HostServer {
+    pub fn new(conf: &Configuration) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, conf.http_port))?;
+        let local_addr = listener.local_addr()?;
+        let registrations = Arc::new(Mutex::new(Vec::new()));
+
+        let shared = registrations.clone();
+        let wildcard = conf.uri_match_wildcard;
+        thread::Builder::new().name("httpd".into()).spawn(move || {
+            for stream in listener.incoming() {
+                if let Err(err) = stream.and_then(|stream| serve(stream, &shared, wildcard)) {
+                    eprintln!("Error while serving request: {err}");
+                }
+            }
+        })?;
+
+        println!("Httpd server started on {local_addr}");
+
+        Ok(Self {
+            local_addr,
+            registrations,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn handler<H>(&mut self, uri: &str, method: Method, handler: H) -> io::Result<&mut Self>
+    where
+        H: for<'a> Handler<HostConnection<'a>> + 'static,
+    {
+        self.registrations.lock().unwrap().push(Registration {
+            uri: uri.to_owned(),
+            handlers: Handlers::Http(method, Box::new(handler)),
+        });
+
+        Ok(self)
+    }
+
+    pub fn fn_handler<F>(&mut self, uri: &str, method: Method, f: F) -> io::Result<&mut Self>
+    where
+        F: for<'a> Fn(Request<&mut HostConnection<'a>>) -> HandlerResult + Send + 'static,
+    {
+        self.handler(uri, method, FnHandler::new(f))
+    }
+
+    pub fn ws_handler<F, E>(&mut self, uri: &str, f: F) -> io::Result<&mut Self>
+    where
+        F: Fn(&mut HostWsConnection) -> Result<(), E> + Send + Sync + 'static,
+        E: Debug,
+    {
+        self.registrations.lock().unwrap().push(Registration {
+            uri: uri.to_owned(),
+            handlers: Handlers::Ws(Arc::new(move |connection| {
+                f(connection).map_err(|err| format!("{err:?}"))
+            })),
+        });
+
+        Ok(self)
+    }
+
+    pub fn sse_handler<F>(&mut self, uri: &str, f: F) -> io::Result<&mut Self>
+    where
+        F: Fn(Option<&str>, HostSseSender) -> Option<String> + Send + Sync + 'static,
+    {
+        self.registrations.lock().unwrap().push(Registration {
+            uri: uri.to_owned(),
+            handlers: Handlers::Sse(Arc::new(f)),
+        });
+
+        Ok(self)
+    }
+}
+
+impl Server for HostServer {
+    type Connection<'a> = HostConnection<'a>;
+    type WsConnection = HostWsConnection;
+    type WsSender = HostWsSender;
+    type SseSender = HostSseSender;
+    type Error = io::Error;
+
+    fn fn_handler<F>(&mut self, uri: &str, method: Method, f: F) -> Result<&mut Self, Self::Error>
+    where
+        F: for<'a> Fn(Request<&mut Self::Connection<'a>>) -> HandlerResult + Send + 'static,
+    {
+        HostServer::fn_handler(self, uri, method, f)
+    }
+
+    fn ws_handler<F, E>(&mut self, uri: &str, f: F) -> Result<&mut Self, Self::Error>
+    where
+        F: Fn(&mut Self::WsConnection) -> Result<(), E> + Send + Sync + 'static,
+        E: Debug,
+    {
+        HostServer::ws_handler(self, uri, f)
+    }
+
+    fn sse_handler<F>(&mut self, uri: &str, f: F) -> Result<&mut Self, Self::Error>
+    where
+        F: Fn(Option<&str>, Self::SseSender) -> Option<String> + Send + Sync + 'static,
+    {
+        HostServer::sse_handler(self, uri, f)
+    }
+}
+
+fn serve(
+    stream: TcpStream,
+    registrations: &Mutex<Vec<Registration>>,
+    wildcard: bool,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut stream = BufReader::new(stream);
+    let head = RequestHead::parse(&mut stream)?;
+    let path = head.path().to_owned();
+    let method = head.method;
+
+    let registrations = registrations.lock().unwrap();
+    let registration = registrations
+        .iter()
+        .find(|r| uri_matches(&r.uri, &path, wildcard) && r.method() == method);
+
+    match registration.map(|r| &r.handlers) {
+        Some(Handlers::Ws(handler)) => {
+            let handler = handler.clone();
+            drop(registrations);
+            return accept_ws(stream, head, handler);
+        }
+        Some(Handlers::Sse(handler)) => {
+            let handler = handler.clone();
+            drop(registrations);
+            return start_sse(stream, &head, handler);
+        }
+        _ => (),
+    }
+
+    let mut connection = HostConnection::new(head, &mut stream);
+    let result = match registration.map(|r| &r.handlers) {
+        Some(Handlers::Http(_, handler)) => handler.handle(&mut connection),
+        Some(Handlers::Ws(_) | Handlers::Sse(_)) => unreachable!(),
+        None if registrations
+            .iter()
+            .any(|r| uri_matches(&r.uri, &path, wildcard)) =>
+        {
+            connection
+                .initiate_response(405, Some("Method Not Allowed"), &[])
+                .map_err(Into::into)
+        }
+        None => connection
+            .initiate_response(404, Some("Not Found"), &[])
+            .map_err(Into::into),
+    };
+
+    match result {
+        Ok(()) if !connection.is_response_initiated() => {
+            connection.initiate_response(200, Some("OK"), &[])?
+        }
+        Ok(()) => (),
+        Err(err) if !connection.is_response_initiated() => {
+            connection.initiate_response(500, Some("Internal Server Error"), &[])?;
+            io::Write::write_all(connection.io.stream.get_mut(), err.message().as_bytes())?;
+        }
+        Err(err) => eprintln!("Handler for {path} failed after responding: {err}"),
+    }
+    connection.flush()?;
+
+    // Like the httpd, skip what the handler left of the body, or closing the
+    // connection could reset it before the client read the response.
+    let mut rest = [0; 512];
+    while connection.io.read(&mut rest)? > 0 {}
+    Ok(())
+}
+
+fn uri_matches(uri: &str, path: &str, wildcard: bool) -> bool {
+    match uri.strip_suffix('*') {
+        Some(prefix) if wildcard => path.starts_with(prefix),
+        _ => uri == path,
+    }
+}
+
+pub struct RequestHead {
+    method: Method,
+    uri: String,
+    headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    fn parse(stream: &mut impl BufRead) -> io::Result<Self> {
+        let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_owned());
+
+        let mut line = String::new();
+        stream.read_line(&mut line)?;
+        let mut parts = line.split_whitespace();
+        let method = parts
+            .next()
+            .and_then(parse_method)
+            .ok_or_else(|| invalid("bad request method"))?;
+        let uri = parts
+            .next()
+            .ok_or_else(|| invalid("missing request URI"))?
+            .to_owned();
+
+        Ok(Self {
+            method,
+            uri,
+            headers: read_headers(stream)?,
+        })
+    }
+
+    fn path(&self) -> &str {
+        self.uri.split('?').next().unwrap_or_default()
+    }
+}
+
+impl Query for RequestHead {
+    fn uri(&self) -> &'_ str {
+        &self.uri
+    }
+
+    fn method(&self) -> Method {
+        self.method
+    }
+}
+
+impl Headers for RequestHead {
+    fn header(&self, name: &str) -> Option<&'_ str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Reads header lines up to the empty one ending them.
+fn read_headers(stream: &mut impl BufRead) -> io::Result<Vec<(String, String)>> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_owned());
+
+    let mut headers = Vec::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if stream.read_line(&mut line)? == 0 {
+            return Err(invalid("connection closed in headers"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid("malformed header"))?;
+        headers.push((name.trim().to_owned(), value.trim().to_owned()));
+    }
+}
+
+fn parse_method(method: &str) -> Option<Method> {
+    Some(match method {
+        "DELETE" => Method::Delete,
+        "GET" => Method::Get,
+        "HEAD" => Method::Head,
+        "POST" => Method::Post,
+        "PUT" => Method::Put,
+        "CONNECT" => Method::Connect,
+        "OPTIONS" => Method::Options,
+        "TRACE" => Method::Trace,
+        "PATCH" => Method::Patch,
+        _ => return None,
+    })
+}
+
+/// Request body and response stream of a single connection.
+pub struct HostIo<'a> {
+    stream: &'a mut BufReader<TcpStream>,
+    remaining: u64,
+}
+
+impl<'a> Io for HostIo<'a> {
+    type Error = io::Error;
+}
+
+impl<'a> Read for HostIo<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let len = buf
+            .len()
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        // An empty read would still block on the socket to fill the buffer.
+        if len == 0 {
+            return Ok(0);
+        }
+        let read = io::Read::read(self.stream, &mut buf[..len])?;
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+impl<'a> Write for HostIo<'a> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        io::Write::write(self.stream.get_mut(), buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        io::Write::flush(self.stream.get_mut())
+    }
+}
+
+pub struct HostConnection<'a> {
+    head: RequestHead,
+    io: HostIo<'a>,
+    response_initiated: bool,
+}
+
+impl<'a> HostConnection<'a> {
+    fn new(head: RequestHead, stream: &'a mut BufReader<TcpStream>) -> Self {
+        let remaining = head.content_len().unwrap_or(0);
+
+        Self {
+            head,
+            io: HostIo { stream, remaining },
+            response_initiated: false,
+        }
+    }
+}
+
+impl<'a> Io for HostConnection<'a> {
+    type Error = io::Error;
+}
+
+impl<'a> Read for HostConnection<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.io.read(buf)
+    }
+}
+
+impl<'a> Write for HostConnection<'a> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.io.flush()
+    }
+}
+
+impl<'a> Query for HostConnection<'a> {
+    fn uri(&self) -> &'_ str {
+        self.head.uri()
+    }
+
+    fn method(&self) -> Method {
+        self.head.method()
+    }
+}
+
+impl<'a> Headers for HostConnection<'a> {
+    fn header(&self, name: &str) -> Option<&'_ str> {
+        self.head.header(name)
+    }
+}
+
+impl<'a> Connection for HostConnection<'a> {
+    type Headers = RequestHead;
+    type Read = HostIo<'a>;
+    type RawConnectionError = io::Error;
+    type RawConnection = HostIo<'a>;
+
+    fn split(&mut self) -> (&Self::Headers, &mut Self::Read) {
+        (&self.head, &mut self.io)
+    }
+
+    fn initiate_response<'b>(
+        &'b mut self,
+        status: u16,
+        message: Option<&'b str>,
+        headers: &'b [(&'b str, &'b str)],
+    ) -> Result<(), Self::Error> {
+        if self.response_initiated {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "response already initiated",
+            ));
+        }
+
+        let mut head = format!("HTTP/1.1 {status} {}\r\n", message.unwrap_or_default());
+        for (name, value) in headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        // There is no chunked encoding, so the body is terminated by closing
+        // the connection.
+        head.push_str("Connection: close\r\n\r\n");
+
+        self.response_initiated = true;
+        io::Write::write_all(self.io.stream.get_mut(), head.as_bytes())
+    }
+
+    fn is_response_initiated(&self) -> bool {
+        self.response_initiated
+    }
+
+    fn raw_connection(&mut self) -> Result<&mut Self::RawConnection, Self::Error> {
+        Ok(&mut self.io)
+    }
+}
+
+/// Key suffix of the WebSocket handshake, from RFC 6455.
+const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Largest frame accepted from a WebSocket client.
+const MAX_WS_FRAME_LEN: u64 = 64 * 1024;
+
+static NEXT_WS_SESSION: AtomicI32 = AtomicI32::new(1);
+
+/// Completes the handshake and hands the session to `handler` on a thread of
+/// its own.
+fn accept_ws(
+    mut stream: BufReader<TcpStream>,
+    head: RequestHead,
+    handler: WsHandler,
+) -> io::Result<()> {
+    let upgrade = head
+        .header("Upgrade")
+        .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+    let Some(key) = head.header("Sec-WebSocket-Key").filter(|_| upgrade) else {
+        return io::Write::write_all(
+            stream.get_mut(),
+            b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n",
+        );
+    };
+
+    let accept = BASE64.encode(Sha1::digest(format!("{key}{WS_GUID}")));
+    io::Write::write_all(
+        stream.get_mut(),
+        format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {accept}\r\n\r\n"
+        )
+        .as_bytes(),
+    )?;
+
+    // Sessions stay open while idle.
+    stream.get_ref().set_read_timeout(None)?;
+    let sender = HostWsSender {
+        session: NEXT_WS_SESSION.fetch_add(1, Ordering::Relaxed),
+        stream: Arc::new(Mutex::new(stream.get_ref().try_clone()?)),
+        closed: Arc::new(AtomicBool::new(false)),
+    };
+    let mut connection = HostWsConnection {
+        sender,
+        state: WsState::New,
+    };
+    if let Err(err) = handler(&mut connection) {
+        eprintln!("WebSocket handler failed: {err}");
+    }
+
+    thread::Builder::new()
+        .name(format!("ws-{}", connection.sender.session))
+        .spawn(move || {
+            while let Ok((frame_type, payload)) = read_frame(&mut stream) {
+                match frame_type {
+                    FrameType::Ping => {
+                        if connection.sender.send(FrameType::Pong, &payload).is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    FrameType::Pong => continue,
+                    FrameType::Close => {
+                        let _ = connection.sender.send(FrameType::Close, &payload);
+                        break;
+                    }
+                    _ => (),
+                }
+
+                connection.state = WsState::Receiving(Some((frame_type, payload)));
+                if let Err(err) = handler(&mut connection) {
+                    eprintln!("WebSocket handler failed: {err}");
+                }
+            }
+
+            connection.sender.closed.store(true, Ordering::Relaxed);
+            let _ = stream.get_ref().shutdown(Shutdown::Both);
+            connection.state = WsState::Closed;
+            if let Err(err) = handler(&mut connection) {
+                eprintln!("WebSocket handler failed: {err}");
+            }
+        })?;
+
+    Ok(())
+}
+
+fn read_frame(stream: &mut impl io::Read) -> io::Result<(FrameType, Vec<u8>)> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_owned());
+
+    let mut head = [0; 2];
+    stream.read_exact(&mut head)?;
+    let fin = head[0] & 0x80 != 0;
+    let masked = head[1] & 0x80 != 0;
+    let len = match head[1] & 0x7f {
+        126 => {
+            let mut len = [0; 2];
+            stream.read_exact(&mut len)?;
+            u16::from_be_bytes(len).into()
+        }
+        127 => {
+            let mut len = [0; 8];
+            stream.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => len.into(),
+    };
+    if !masked {
+        return Err(invalid("unmasked frame from client"));
+    }
+    if len > MAX_WS_FRAME_LEN {
+        return Err(invalid("frame too large"));
+    }
+
+    let mut mask = [0; 4];
+    stream.read_exact(&mut mask)?;
+    let mut payload = vec![0; len as usize];
+    stream.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+
+    let frame_type = match head[0] & 0x0f {
+        0x0 => FrameType::Continue(fin),
+        0x1 => FrameType::Text(!fin),
+        0x2 => FrameType::Binary(!fin),
+        0x8 => FrameType::Close,
+        0x9 => FrameType::Ping,
+        0xa => FrameType::Pong,
+        _ => return Err(invalid("unknown opcode")),
+    };
+    Ok((frame_type, payload))
+}
+
+/// Sends to a WebSocket session, from its handler or any other thread.
+#[derive(Clone)]
+pub struct HostWsSender {
+    session: i32,
+    stream: Arc<Mutex<TcpStream>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl ErrorType for HostWsSender {
+    type Error = io::Error;
+}
+
+impl Sender for HostWsSender {
+    fn send(&mut self, frame_type: FrameType, frame_data: &[u8]) -> Result<(), Self::Error> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+
+        let opcode = match frame_type {
+            FrameType::Continue(_) => 0x0,
+            FrameType::Text(_) => 0x1,
+            FrameType::Binary(_) => 0x2,
+            FrameType::Close => 0x8,
+            FrameType::Ping => 0x9,
+            FrameType::Pong => 0xa,
+            FrameType::SocketClose => return Err(io::ErrorKind::InvalidInput.into()),
+        };
+        let fin = if frame_type.is_final() { 0x80 } else { 0 };
+
+        let mut frame = vec![fin | opcode];
+        match frame_data.len() {
+            len @ 0..=125 => frame.push(len as u8),
+            len @ 126..=0xffff => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(frame_data);
+
+        let result = io::Write::write_all(&mut *self.stream.lock().unwrap(), &frame);
+        if result.is_err() {
+            self.closed.store(true, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+impl SessionProvider for HostWsSender {
+    type Session = i32;
+
+    fn session(&self) -> Self::Session {
+        self.session
+    }
+
+    fn is_new(&self) -> bool {
+        false
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+}
+
+enum WsState {
+    New,
+    /// A frame that hasn't been read by the handler yet.
+    Receiving(Option<(FrameType, Vec<u8>)>),
+    Closed,
+}
+
+/// A WebSocket session as handed to a handler, like `EspHttpWsConnection`.
+pub struct HostWsConnection {
+    sender: HostWsSender,
+    state: WsState,
+}
+
+impl ErrorType for HostWsConnection {
+    type Error = io::Error;
+}
+
+impl Sender for HostWsConnection {
+    fn send(&mut self, frame_type: FrameType, frame_data: &[u8]) -> Result<(), Self::Error> {
+        self.sender.send(frame_type, frame_data)
+    }
+}
+
+impl Receiver for HostWsConnection {
+    /// Like the httpd, reports text frames one byte longer than they are, to
+    /// fit a NUL terminator, and only consumes the frame if `frame_data_buf` is
+    /// large enough.
+    fn recv(&mut self, frame_data_buf: &mut [u8]) -> Result<(FrameType, usize), Self::Error> {
+        match &mut self.state {
+            WsState::New | WsState::Receiving(None) => Err(io::ErrorKind::WouldBlock.into()),
+            WsState::Receiving(frame @ Some(_)) => {
+                let (frame_type, payload) = frame.as_ref().unwrap();
+                let frame_type = *frame_type;
+                let len = match frame_type {
+                    FrameType::Text(_) => payload.len() + 1,
+                    _ => payload.len(),
+                };
+
+                if frame_data_buf.len() >= len {
+                    frame_data_buf[..payload.len()].copy_from_slice(payload);
+                    if len > payload.len() {
+                        frame_data_buf[payload.len()] = 0;
+                    }
+                    *frame = None;
+                }
+
+                Ok((frame_type, len))
+            }
+            WsState::Closed => Ok((FrameType::SocketClose, 0)),
+        }
+    }
+}
+
+impl SessionProvider for HostWsConnection {
+    type Session = i32;
+
+    fn session(&self) -> Self::Session {
+        self.sender.session
+    }
+
+    fn is_new(&self) -> bool {
+        matches!(self.state, WsState::New)
+    }
+
+    fn is_closed(&self) -> bool {
+        matches!(self.state, WsState::Closed)
+    }
+}
+
+impl SenderFactory for HostWsConnection {
+    type Sender = HostWsSender;
+
+    fn create(&self) -> Result<Self::Sender, Self::Error> {
+        Ok(self.sender.clone())
+    }
+}
+
+/// Sends the start of the event stream and keeps an eye on the connection, so
+/// the sender knows when the client goes away.
+fn start_sse(
+    stream: BufReader<TcpStream>,
+    head: &RequestHead,
+    handler: SseHandler,
+) -> io::Result<()> {
+    let sender = HostSseSender {
+        stream: Arc::new(Mutex::new(stream.get_ref().try_clone()?)),
+        closed: Arc::new(AtomicBool::new(false)),
+    };
+
+    {
+        // Holding the stream keeps events published meanwhile from getting
+        // ahead of the first ones.
+        let mut writer = sender.stream.lock().unwrap();
+        let Some(first) = handler(head.header("Last-Event-ID"), sender.clone()) else {
+            return io::Write::write_all(
+                &mut *writer,
+                b"HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\n\r\n",
+            );
+        };
+        io::Write::write_all(
+            &mut *writer,
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n{first}"
+            )
+            .as_bytes(),
+        )?;
+    }
+
+    thread::Builder::new().name("sse".into()).spawn(move || {
+        let mut stream = stream;
+        let mut buf = [0; 64];
+        while matches!(io::Read::read(&mut stream, &mut buf), Ok(len) if len > 0) {}
+        sender.closed.store(true, Ordering::Relaxed);
+    })?;
+
+    Ok(())
+}
+
+/// Writes to an event stream from any thread.
+#[derive(Clone)]
+pub struct HostSseSender {
+    stream: Arc<Mutex<TcpStream>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl StreamSender for HostSseSender {
+    type Error = io::Error;
+
+    fn send(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+
+        let result = io::Write::write_all(&mut *self.stream.lock().unwrap(), data);
+        if result.is_err() {
+            self.closed.store(true, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+}
+
+/// Stands in for the board in the API: a station with a perfect link and a
+/// sensor whose readings are set by hand.
+pub struct HostDevice {
+    started: Instant,
+    snapshot: Arc<Snapshot>,
+}
+
+impl HostDevice {
+    pub fn new() -> Self {
+        Self::with_snapshot(Arc::new(Snapshot::new()))
+    }
+
+    /// Shows the readings of a [`Sampler`](crate::sampler::Sampler).
+    pub fn with_snapshot(snapshot: Arc<Snapshot>) -> Self {
+        Self {
+            started: Instant::now(),
+            snapshot,
+        }
+    }
+
+    pub fn set_reading(&self, reading: Reading) {
+        self.snapshot.update(reading);
+    }
+}
+
+impl Default for HostDevice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Device for HostDevice {
+    fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    fn wifi_status(&self) -> anyhow::Result<WifiStatus> {
+        Ok(WifiStatus {
+            mode: WifiMode::Station,
+            ssid: "host".into(),
+            connected: true,
+            ip: Some(Ipv4Addr::LOCALHOST),
+            rssi: None,
+            channel: None,
+        })
+    }
+
+    fn reading(&self) -> Option<Reading> {
+        self.snapshot.reading()
+    }
+
+    fn platform(&self) -> anyhow::Result<Platform> {
+        let cores = thread::available_parallelism().map_or(1, |cores| cores.get());
+        Ok(Platform {
+            reset_reason: "power_on",
+            heap: None,
+            chip: Chip {
+                model: std::env::consts::ARCH,
+                revision: 0,
+                cores: cores.try_into().unwrap_or(u8::MAX),
+            },
+            mac: Macs::default(),
+            netif: Some(Netif {
+                ip: Ipv4Addr::LOCALHOST,
+                prefix_len: 8,
+                gateway: None,
+                dns: None,
+            }),
+        })
+    }
+}
+
+/// Configuration [`Storage`] in a text file of `key=value` lines, standing in
+/// for NVS. The whole file is rewritten on every change.
+pub struct FileStorage {
+    path: PathBuf,
+    values: BTreeMap<String, String>,
+}
+
+impl FileStorage {
+    /// Loads `path`, which doesn't have to exist yet.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+
+        let mut values = BTreeMap::new();
+        for line in contents.lines() {
+            let (key, value) = line.split_once('=').ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("malformed line in {}: {line:?}", path.display()),
+                )
+            })?;
+            values.insert(key.to_owned(), unescape(value));
+        }
+
+        Ok(Self { path, values })
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let contents: String = self
+            .values
+            .iter()
+            .map(|(key, value)| format!("{key}={}\n", escape(value)))
+            .collect();
+
+        // Write a copy first so a crash can't leave a truncated file behind.
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, contents)?;
+        fs::rename(tmp, &self.path)
+    }
+}
+
+impl Storage for FileStorage {
+    fn get(&self, key: &str) -> anyhow::Result<Option<String>> {
+        Ok(self.values.get(key).cloned())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        self.values.insert(key.into(), value.into());
+        Ok(self.save()?)
+    }
+
+    fn remove(&mut self, key: &str) -> anyhow::Result<()> {
+        if self.values.remove(key).is_some() {
+            self.save()?;
+        }
+
+        Ok(())
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                unescaped.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                unescaped.push('\\');
+                chars.next();
+            }
+            (c, _) => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// [`BlobStorage`] in a directory, a file per key, standing in for a flash
+/// partition. Files are written in place, so like on flash, a crash can leave
+/// a truncated blob behind.
+pub struct FileBlobs {
+    dir: PathBuf,
+}
+
+impl FileBlobs {
+    /// Creates `dir` if it doesn't exist yet.
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    pub fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.bin"))
+    }
+}
+
+impl BlobStorage for FileBlobs {
+    fn read(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        match fs::read(self.path(key)) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn write(&mut self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+        Ok(fs::write(self.path(key), data)?)
+    }
+}
+
+/// Size of an OTA slot in `partitions.csv`.
+const SLOT_SIZE: usize = 0x1e0000;
+
+/// [`Updater`] writing images to a directory, standing in for the OTA slots:
+/// `update.bin` while an image is being written and `firmware.bin` once it is
+/// complete. Restarting is left to whoever runs the app, and until the next
+/// run has passed its checks, `pending-verify` marks it as on trial.
+pub struct FileUpdater {
+    dir: PathBuf,
+    update: Option<fs::File>,
+}
+
+impl FileUpdater {
+    /// Creates `dir` if it doesn't exist yet.
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir, update: None })
+    }
+
+    /// Of the image installed last.
+    pub fn firmware_path(&self) -> PathBuf {
+        self.dir.join("firmware.bin")
+    }
+
+    fn update_path(&self) -> PathBuf {
+        self.dir.join("update.bin")
+    }
+
+    fn pending_path(&self) -> PathBuf {
+        self.dir.join("pending-verify")
+    }
+
+    fn rolled_back_path(&self) -> PathBuf {
+        self.dir.join("rolled-back")
+    }
+}
+
+impl Updater for FileUpdater {
+    fn slot_size(&self) -> anyhow::Result<usize> {
+        Ok(SLOT_SIZE)
+    }
+
+    fn begin(&mut self) -> anyhow::Result<()> {
+        self.update = Some(fs::File::create(self.update_path())?);
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let update = self.update.as_mut().context("no update in progress")?;
+        Ok(update.write_all(data)?)
+    }
+
+    fn complete(&mut self) -> anyhow::Result<()> {
+        let update = self.update.take().context("no update in progress")?;
+        update.sync_all()?;
+        fs::rename(self.update_path(), self.firmware_path())?;
+        Ok(fs::write(self.pending_path(), "")?)
+    }
+
+    fn abort(&mut self) -> anyhow::Result<()> {
+        self.update.take().context("no update in progress")?;
+        Ok(fs::remove_file(self.update_path())?)
+    }
+
+    fn restart(&self) {
+        println!("Restart to boot {}", self.firmware_path().display());
+    }
+
+    fn pending_verify(&self) -> anyhow::Result<bool> {
+        Ok(self.pending_path().try_exists()?)
+    }
+
+    fn mark_valid(&mut self) -> anyhow::Result<()> {
+        Ok(fs::remove_file(self.pending_path())?)
+    }
+
+    fn roll_back(&mut self) -> anyhow::Result<()> {
+        fs::remove_file(self.firmware_path())?;
+        fs::remove_file(self.pending_path())?;
+        fs::write(self.rolled_back_path(), "")?;
+        println!("Restart to boot the previous firmware");
+        Ok(())
+    }
+
+    fn rolled_back(&self) -> anyhow::Result<bool> {
+        Ok(self.rolled_back_path().try_exists()?)
+    }
+}
+
+/// How long the [`HostFetcher`] waits for the server.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// [`Fetcher`] for plain `http://` URLs, standing in for the ESP-IDF HTTP
+/// client, e.g. to pull updates from `python3 -m http.server`.
+pub struct HostFetcher;
+
+impl Fetcher for HostFetcher {
+    fn get(&mut self, url: &str) -> anyhow::Result<Box<dyn Download + '_>> {
+        let rest = url
+            .strip_prefix("http://")
+            .with_context(|| format!("only http:// URLs can be fetched, not {url}"))?;
+        let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        let path = if path.is_empty() { "/" } else { path };
+        let addr = if authority.contains(':') {
+            authority.to_owned()
+        } else {
+            format!("{authority}:80")
+        };
+
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(FETCH_TIMEOUT))?;
+        write!(
+            stream,
+            "GET {path} HTTP/1.1\r\nHost: {authority}\r\nConnection: close\r\n\r\n"
+        )?;
+
+        let mut stream = BufReader::new(stream);
+        let mut line = String::new();
+        stream.read_line(&mut line)?;
+        let status = line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .with_context(|| format!("malformed status line {line:?}"))?;
+        let content_len = read_headers(&mut stream)?
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+            .and_then(|(_, value)| value.parse().ok());
+
+        Ok(Box::new(HostDownload {
+            stream,
+            status,
+            content_len,
+            remaining: content_len.unwrap_or(u64::MAX),
+        }))
+    }
+}
+
+/// The body ends after `Content-Length` bytes, or without one, when the
+/// server closes the connection.
+struct HostDownload {
+    stream: BufReader<TcpStream>,
+    status: u16,
+    content_len: Option<u64>,
+    remaining: u64,
+}
+
+impl Download for HostDownload {
+    fn status(&self) -> u16 {
+        self.status
+    }
+
+    fn content_len(&self) -> Option<u64> {
+        self.content_len
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        let len = buf
+            .len()
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        if len == 0 {
+            return Ok(0);
+        }
+        let read = io::Read::read(&mut self.stream, &mut buf[..len])?;
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
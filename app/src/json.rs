@@ -0,0 +1,67 @@
+//! JSON responses and content negotiation.
+
+use embedded_svc::{
+    http::server::{Connection, HandlerResult, Request},
+    io::Write,
+};
+use serde::Serialize;
+
+use crate::router::reason_phrase;
+
+/// Body of every JSON error response.
+#[derive(Debug, Serialize)]
+pub struct ErrorBody<'a> {
+    pub error: ErrorDetails<'a>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorDetails<'a> {
+    pub status: u16,
+    pub message: &'a str,
+}
+
+impl<'a> ErrorBody<'a> {
+    pub fn new(status: u16, message: &'a str) -> Self {
+        Self {
+            error: ErrorDetails { status, message },
+        }
+    }
+}
+
+/// Whether the client listed `application/json` in its `Accept` header.
+pub fn accepts_json<C: Connection>(request: &Request<C>) -> bool {
+    request.header("Accept").is_some_and(|accept| {
+        accept
+            .split(',')
+            .any(|range| range.trim().starts_with("application/json"))
+    })
+}
+
+/// Responds with `body` serialized to JSON.
+pub fn respond<C: Connection, T: Serialize>(
+    request: Request<C>,
+    status: u16,
+    body: &T,
+) -> HandlerResult {
+    respond_with(request, status, &[], body)
+}
+
+/// As [`respond`], with additional response headers.
+pub fn respond_with<C: Connection, T: Serialize>(
+    request: Request<C>,
+    status: u16,
+    headers: &[(&str, &str)],
+    body: &T,
+) -> HandlerResult {
+    let json = serde_json::to_vec(body)?;
+    let mut all_headers = vec![("Content-Type", "application/json")];
+    all_headers.extend_from_slice(headers);
+
+    let mut response = request.into_response(status, reason_phrase(status), &all_headers)?;
+    Ok(response.write_all(&json)?)
+}
+
+/// Responds with an [`ErrorBody`].
+pub fn error<C: Connection>(request: Request<C>, status: u16, message: &str) -> HandlerResult {
+    respond(request, status, &ErrorBody::new(status, message))
+}
@@ -0,0 +1,36 @@
+//! Platform-agnostic core of the HTTP app.
+//!
+//! Handlers are written against the `embedded-svc` HTTP traits and registered
+//! through [`server::Server`], which is implemented both for the ESP-IDF httpd
+//! (feature `esp`) and for a `std` TCP server on the build machine (feature
+//! `host`).
+
+#[cfg(all(feature = "esp", feature = "host"))]
+compile_error!("features `esp` and `host` are mutually exclusive, build the host backend with `--no-default-features`");
+
+pub mod alerts;
+pub mod api;
+pub mod assets;
+pub mod auth;
+pub mod captive;
+pub mod config;
+#[cfg(feature = "esp")]
+pub mod esp;
+pub mod events;
+pub mod form;
+pub mod history;
+#[cfg(feature = "host")]
+pub mod host;
+pub mod json;
+pub mod live;
+pub mod manifest;
+pub mod ota;
+pub mod pages;
+pub mod persist;
+pub mod provisioning;
+pub mod router;
+pub mod sampler;
+pub mod sensor;
+pub mod server;
+pub mod settings;
+pub mod system;
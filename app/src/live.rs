@@ -0,0 +1,132 @@
+//! Pushes sensor readings to WebSocket clients of `/ws`, as JSON text frames
+//! like those of `/api/v1/sensor`.
+
+use std::sync::{Arc, Mutex};
+
+use embedded_svc::ws::{
+    callback_server::{SenderFactory, SessionProvider},
+    FrameType, Receiver, Sender,
+};
+
+use crate::{sensor::Reading, server::Server};
+
+/// Sockets left for WebSocket clients by default. Every client keeps one of
+/// the httpd's sockets open, so there have to be some left for plain requests.
+pub const MAX_CLIENTS: usize = 3;
+
+/// Longest message accepted from a client, which isn't expected to send
+/// anything but control frames.
+const MAX_MESSAGE_LEN: usize = 128;
+
+/// Close codes from RFC 6455.
+const CLOSE_TOO_BIG: u16 = 1009;
+const CLOSE_TRY_AGAIN_LATER: u16 = 1013;
+
+pub struct LiveReadings<S> {
+    max_clients: usize,
+    state: Mutex<State<S>>,
+}
+
+struct State<S> {
+    clients: Vec<S>,
+    last: Option<Reading>,
+}
+
+impl<S: Sender + SessionProvider + Clone + Send> LiveReadings<S> {
+    pub fn new(max_clients: usize) -> Self {
+        Self {
+            max_clients,
+            state: Mutex::new(State {
+                clients: Vec::new(),
+                last: None,
+            }),
+        }
+    }
+
+    /// Number of connected clients.
+    pub fn clients(&self) -> usize {
+        self.state.lock().unwrap().clients.len()
+    }
+
+    /// Sends `reading` to every client, and to those connecting later until
+    /// there is a newer one. Clients that can't be reached anymore are
+    /// dropped.
+    pub fn publish(&self, reading: &Reading) {
+        let message = serde_json::to_string(reading).unwrap();
+
+        // Sending from outside the handler waits for the httpd, which may be
+        // waiting for the lock in `handle`, so the lock is not held meanwhile.
+        let clients = {
+            let mut state = self.state.lock().unwrap();
+            state.last = Some(*reading);
+            state.clients.clone()
+        };
+
+        let mut gone = Vec::new();
+        for mut client in clients {
+            if client.is_closed()
+                || client
+                    .send(FrameType::Text(false), message.as_bytes())
+                    .is_err()
+            {
+                gone.push(client.session());
+            }
+        }
+
+        if !gone.is_empty() {
+            let mut state = self.state.lock().unwrap();
+            state
+                .clients
+                .retain(|client| !gone.contains(&client.session()));
+        }
+    }
+
+    /// The WebSocket handler, see [`register`].
+    pub fn handle<C>(&self, connection: &mut C) -> Result<(), C::Error>
+    where
+        C: Sender + Receiver + SessionProvider<Session = S::Session> + SenderFactory<Sender = S>,
+    {
+        if connection.is_new() {
+            let mut state = self.state.lock().unwrap();
+            if state.clients.len() >= self.max_clients {
+                drop(state);
+                return connection.send(FrameType::Close, &CLOSE_TRY_AGAIN_LATER.to_be_bytes());
+            }
+            state.clients.push(connection.create()?);
+            let last = state.last;
+            drop(state);
+
+            if let Some(reading) = last {
+                let message = serde_json::to_string(&reading).unwrap();
+                connection.send(FrameType::Text(false), message.as_bytes())?;
+            }
+            return Ok(());
+        }
+
+        if connection.is_closed() {
+            let session = connection.session();
+            let mut state = self.state.lock().unwrap();
+            state.clients.retain(|client| client.session() != session);
+            return Ok(());
+        }
+
+        // Whatever the client sends is ignored, but it still has to be read.
+        let (_, len) = connection.recv(&mut [])?;
+        if len > MAX_MESSAGE_LEN {
+            return connection.send(FrameType::Close, &CLOSE_TOO_BIG.to_be_bytes());
+        }
+        if len > 0 {
+            connection.recv(&mut vec![0; len])?;
+        }
+        Ok(())
+    }
+}
+
+/// Serves `live` on `/ws`. Has to be called before the router is mounted.
+pub fn register<Srv: Server>(
+    server: &mut Srv,
+    live: Arc<LiveReadings<Srv::WsSender>>,
+) -> Result<(), Srv::Error> {
+    server.ws_handler("/ws", move |connection| live.handle(connection))?;
+    Ok(())
+}
@@ -1,22 +1,75 @@
 use anyhow::Result;
-use core::str;
-use embedded_svc::{http::Method, io::Write};
-use esp_idf_hal::{
-    prelude::*,
+use embedded_svc::http::Method;
+use http_server::{
+    alerts::{self, Alerts, Notification, SharedAlerts},
+    api::Device,
+    auth::{self, Auth, Lockout, Strict},
+    config::{ConfigStore, Defaults, SharedConfig, Storage},
+    events::{self, Event, EventStream},
+    history::{self, History, SharedHistory},
+    live::{self, LiveReadings},
+    manifest::{Fetcher, UpdateChecker, Updates},
+    ota::{self, Ota, OtaStatus, Updater},
+    persist::{BlobStorage, HistoryStore},
+    router::Router,
+    sampler::Sampler,
+    sensor::Sensor,
+    server::{Server, StreamSender},
+    system,
 };
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "esp")]
+use embedded_svc::wifi::AccessPointConfiguration;
+#[cfg(feature = "esp")]
+use esp_idf_hal::prelude::*;
+#[cfg(feature = "esp")]
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
     http::server::{Configuration, EspHttpServer},
+    nvs::{EspCustomNvsPartition, EspDefaultNvsPartition, EspNvs},
+    sntp::EspSntp,
+    wifi::EspWifi,
 };
-
-use std::{
-    thread::sleep,
-    time::Duration,
+#[cfg(feature = "esp")]
+use http_server::{
+    captive::CaptiveDns,
+    esp::{EspDevice, EspFetcher, EspProvisioner, EspUpdater, I2cBus, Shtc3Sensor},
+};
+#[cfg(feature = "esp")]
+use wifi::{
+    start_provisioning, Backoff, KnownNetwork, LinkState, WifiBuilder, WifiError, WifiMode,
+    WifiSupervisor,
 };
-use wifi::wifi;
 // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
+#[cfg(feature = "esp")]
 use esp_idf_sys as _;
 
+#[cfg(feature = "host")]
+use http_server::{
+    host::{
+        Configuration, FileBlobs, FileStorage, FileUpdater, HostDevice, HostFetcher, HostServer,
+    },
+    sensor::{Reading, ReplaySensor},
+};
+
+/// Stands in for NVS when running on the host.
+#[cfg(feature = "host")]
+const HOST_CONFIG_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/target/host-config.txt");
+
+/// Stands in for the history partition when running on the host.
+#[cfg(feature = "host")]
+const HOST_HISTORY_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/target/host-history");
+
+/// Stands in for the OTA slots when running on the host.
+#[cfg(feature = "host")]
+const HOST_OTA_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/target/host-ota");
+
 #[toml_cfg::toml_config]
 pub struct Config {
     #[default("")]
@@ -25,8 +78,290 @@ pub struct Config {
     wifi_psk: &'static str,
     #[default(false)]
     wifi_ap: bool,
+    #[default("esp-rs")]
+    hostname: &'static str,
+    #[default(10)]
+    sensor_interval_secs: u32,
+    /// GPIOs and clock of the I2C bus the SHTC3 is on; the defaults are those
+    /// of the ESP32-C3-DevKit-RUST-1.
+    #[default(10)]
+    sensor_sda: i32,
+    #[default(8)]
+    sensor_scl: i32,
+    #[default(100)]
+    sensor_i2c_khz: u32,
+    /// RAM for the sensor history, see `history::DEFAULT_TIERS`.
+    #[default(32)]
+    history_ram_kib: u32,
+    /// How often the history is saved to flash.
+    #[default(15)]
+    history_flush_mins: u32,
+    /// How long new firmware has to pass its checks before it is rolled back.
+    #[default(120)]
+    ota_verify_secs: u32,
+    /// Firmware manifest to check for updates, see `manifest.rs`; empty
+    /// disables checking.
+    #[default("")]
+    ota_manifest_url: &'static str,
+    #[default(60)]
+    ota_check_mins: u32,
+    /// Revision of the board, compared with `min_hw_revision` of manifests.
+    #[default(0)]
+    hw_revision: u32,
+}
+
+/// The constant `CONFIG` is auto-generated by `toml_config`; its values apply
+/// until they are changed at runtime.
+fn defaults(config: &Config) -> Defaults {
+    Defaults {
+        wifi_ssid: config.wifi_ssid,
+        wifi_psk: config.wifi_psk,
+        wifi_ap: config.wifi_ap,
+        hostname: config.hostname,
+        sensor_interval_secs: config.sensor_interval_secs,
+    }
+}
+
+/// `CONFIG` as served on `/api/v1/system`, with secrets replaced by whether
+/// they are set.
+fn build_config(config: &Config) -> serde_json::Value {
+    serde_json::json!({
+        "wifi_ssid": config.wifi_ssid,
+        "wifi_psk_set": !config.wifi_psk.is_empty(),
+        "wifi_ap": config.wifi_ap,
+        "hostname": config.hostname,
+        "sensor_interval_secs": config.sensor_interval_secs,
+        "sensor_sda": config.sensor_sda,
+        "sensor_scl": config.sensor_scl,
+        "sensor_i2c_khz": config.sensor_i2c_khz,
+        "history_ram_kib": config.history_ram_kib,
+        "history_flush_mins": config.history_flush_mins,
+        "ota_verify_secs": config.ota_verify_secs,
+        "ota_manifest_url": config.ota_manifest_url,
+        "ota_check_mins": config.ota_check_mins,
+        "hw_revision": config.hw_revision,
+    })
+}
+
+/// Samples `sensor` as often as configured, records every reading in
+/// `history`, checks it for `alerts` and pushes it to the clients of `live`
+/// and `events`.
+fn start_sampler<Srv: Server, S: Storage>(
+    sensor: impl Sensor,
+    config: SharedConfig<S>,
+    history: SharedHistory,
+    alerts: SharedAlerts,
+    live: Arc<LiveReadings<Srv::WsSender>>,
+    events: Arc<EventStream<Srv::SseSender>>,
+) -> io::Result<Sampler> {
+    let interval = move || {
+        let interval = config.lock().unwrap().sensor_interval();
+        interval.unwrap_or_else(|err| {
+            eprintln!("Could not read the sensor interval ({err}), using the default");
+            Duration::from_secs(CONFIG.sensor_interval_secs.into())
+        })
+    };
+    let publish = move |reading: &_| {
+        let now = history::now();
+        if history::clock_is_set(now) {
+            history.lock().unwrap().record(now, reading);
+        }
+        alerts.lock().unwrap().evaluate(now, reading);
+        live.publish(reading);
+        events.publish(&Event::Reading(*reading));
+    };
+
+    Sampler::start(sensor, interval, publish)
+}
+
+/// The history as last saved in `blobs`, if there are any, and the store to
+/// keep saving it in.
+fn restore_history<B: BlobStorage>(
+    blobs: Option<B>,
+) -> Result<(SharedHistory, Option<HistoryStore<B>>)> {
+    let ram_budget = CONFIG.history_ram_kib as usize * 1024;
+    let mut history = History::new(&history::DEFAULT_TIERS, ram_budget);
+    let store = blobs
+        .map(|blobs| HistoryStore::open(blobs, &mut history))
+        .transpose()?;
+    Ok((Arc::new(Mutex::new(history)), store))
+}
+
+/// Saves the history if it's been `history_flush_mins` since `last_save`.
+/// Every save rewrites all of it, so they are few and far between to spare
+/// the flash; a failed one is tried again next time.
+fn save_history<B: BlobStorage>(
+    store: &mut Option<HistoryStore<B>>,
+    history: &SharedHistory,
+    last_save: &mut Instant,
+) {
+    let interval = Duration::from_secs(u64::from(CONFIG.history_flush_mins) * 60);
+    let Some(store) = store else {
+        return;
+    };
+    if last_save.elapsed() < interval {
+        return;
+    }
+
+    *last_save = Instant::now();
+    if let Err(err) = store.save(history) {
+        eprintln!("Could not save the history: {err:#}");
+    }
+}
+
+/// The configured alert rules, with notifications going to the log and to
+/// `events`.
+fn start_alerts<S: Storage, E: StreamSender>(
+    config: &SharedConfig<S>,
+    events: Arc<EventStream<E>>,
+) -> SharedAlerts {
+    let rules = config.lock().unwrap().alert_rules();
+    let rules = rules.unwrap_or_else(|err| {
+        eprintln!("Could not load the alert rules ({err:#}), starting without");
+        Vec::new()
+    });
+
+    let mut alerts = Alerts::new(rules);
+    alerts.add_sink(|notification: &Notification| {
+        println!(
+            "Alert {} {:?}: {} {} is {} (threshold {})",
+            notification.rule,
+            notification.transition,
+            notification.channel.name(),
+            notification.value,
+            notification.channel.unit(),
+            notification.threshold,
+        )
+    });
+    alerts.add_sink(move |notification: &Notification| {
+        events.publish(&Event::Alert(notification.clone()))
+    });
+    Arc::new(Mutex::new(alerts))
+}
+
+/// Firmware updates through `updater`, with their progress going to the log
+/// and to `events`.
+fn start_ota<U: Updater, E: StreamSender>(updater: U, events: Arc<EventStream<E>>) -> Arc<Ota<U>> {
+    Arc::new(Ota::new(updater, move |status: &OtaStatus| {
+        match status {
+            OtaStatus::Receiving { received, size } => {
+                println!("Update: {received} of {size} bytes")
+            }
+            status => println!("Update: {status:?}"),
+        }
+        events.publish(&Event::Ota(status.clone()))
+    }))
+}
+
+/// How long new firmware has to pass its checks, see [`Ota::verify`].
+fn verify_deadline() -> Duration {
+    Duration::from_secs(CONFIG.ota_verify_secs.into())
+}
+
+/// Checks the manifest at `ota_manifest_url` for new firmware every
+/// `ota_check_mins`, if there is one.
+fn start_updates<F: Fetcher, U: Updater>(
+    fetcher: F,
+    ota: Arc<Ota<U>>,
+) -> io::Result<Option<UpdateChecker>> {
+    if CONFIG.ota_manifest_url.is_empty() {
+        return Ok(None);
+    }
+
+    let updates = Updates::new(fetcher, ota, CONFIG.ota_manifest_url, CONFIG.hw_revision);
+    let interval = Duration::from_secs(u64::from(CONFIG.ota_check_mins) * 60);
+    UpdateChecker::start(updates, interval).map(Some)
+}
+
+/// The routes of the app once it is on the network, the same on every
+/// platform.
+fn register_routes<Srv: Server + 'static, D: Device, S: Storage, U: Updater>(
+    router: &mut Router<Srv>,
+    device: Arc<D>,
+    config: &SharedConfig<S>,
+    history: &SharedHistory,
+    alerts: &SharedAlerts,
+    ota: Arc<Ota<U>>,
+) -> Result<()> {
+    http_server::assets::register(router);
+    http_server::settings::register(router, config.clone());
+    http_server::api::register(router, device.clone(), config.clone());
+    system::register(router, device, config.clone(), build_config(&CONFIG));
+    history::register(router, history.clone());
+    alerts::register(router, alerts.clone(), config.clone());
+    ota::register(router, ota);
+    protect(router, config.clone())
 }
 
+/// Serves the management of users and API tokens, and has them guard the
+/// routes that change the device or show its Wi-Fi credentials. Firmware
+/// uploads stay closed until there are credentials.
+fn protect<Srv: Server + 'static, S: Storage>(
+    router: &mut Router<Srv>,
+    config: SharedConfig<S>,
+) -> Result<()> {
+    const ALL: [Method; 5] = [
+        Method::Get,
+        Method::Post,
+        Method::Put,
+        Method::Delete,
+        Method::Patch,
+    ];
+    const CHANGES: [Method; 4] = [Method::Post, Method::Put, Method::Delete, Method::Patch];
+
+    let auth = Arc::new(Auth::new(config, Lockout::default())?);
+    router
+        .protect(&[Method::Get, Method::Post], "/settings", auth.clone())
+        .protect(&CHANGES, "/api/v1/config", auth.clone())
+        .protect(&CHANGES, "/api/v1/alerts", auth.clone())
+        .protect(&CHANGES, "/api/v1/wifi/networks", auth.clone())
+        .protect(&ALL, "/api/v1/auth", auth.clone())
+        .protect(&ALL, "/api/v1/auth/{*rest}", auth.clone())
+        .protect(
+            &[Method::Post],
+            "/api/v1/ota",
+            Arc::new(Strict(auth.clone())),
+        );
+    auth::register(router, auth);
+    Ok(())
+}
+
+/// Tells event stream clients about the configuration if it changed since
+/// `seen` changes, and picks up changed alert rules. Reading the
+/// configuration failing is logged and tried again next time, rather than
+/// taking the device down.
+fn apply_config_changes<S: Storage, E: StreamSender>(
+    events: &EventStream<E>,
+    alerts: &SharedAlerts,
+    config: &SharedConfig<S>,
+    seen: &mut u64,
+) {
+    let changes = config.lock().unwrap().changes();
+    if changes == *seen {
+        return;
+    }
+
+    let applied = http_server::api::config_body(config).and_then(|body| {
+        let rules = config.lock().unwrap().alert_rules()?;
+        events.publish(&Event::Config(body));
+        alerts.lock().unwrap().set_rules(rules);
+        Ok(())
+    });
+    match applied {
+        Ok(()) => *seen = changes,
+        Err(err) => eprintln!("Could not apply the configuration changes: {err:#}"),
+    }
+}
+
+/// The NVS partition the history is saved in, see `partitions.csv`.
+#[cfg(feature = "esp")]
+const HISTORY_PARTITION: &str = "history";
+
+/// Name of the open access point to set up Wi-Fi from.
+#[cfg(feature = "esp")]
+const PROVISIONING_SSID: &str = "esp-rs-setup";
+
+#[cfg(feature = "esp")]
 fn main() -> Result<()> {
     esp_idf_sys::link_patches();
     esp_idf_svc::log::EspLogger::initialize_default();
@@ -34,51 +369,225 @@ fn main() -> Result<()> {
     let peripherals = Peripherals::take().unwrap();
     let sysloop = EspSystemEventLoop::take()?;
 
-    // The constant `CONFIG` is auto-generated by `toml_config`.
-    let app_config = CONFIG;
+    let nvs = EspDefaultNvsPartition::take()?;
+    let config = ConfigStore::open(EspNvs::new(nvs.clone(), "config", true)?, defaults(&CONFIG))?;
+
+    let wifi_ap = config.wifi_ap()?;
+    // Also for the supervisor to choose from when the network goes away.
+    let known_networks: Vec<_> = if wifi_ap {
+        Vec::new()
+    } else {
+        let networks = config.known_networks()?;
+        networks
+            .iter()
+            .map(|network| KnownNetwork::new(&network.ssid, &network.psk, network.priority))
+            .collect()
+    };
+    let mode = if wifi_ap {
+        WifiMode::access_point(&config.wifi_ssid()?, &config.wifi_psk()?)
+    } else {
+        WifiMode::Station(known_networks.clone())
+    };
+    let builder = WifiBuilder::new(mode).hostname(&config.hostname()?);
+    let config = Arc::new(Mutex::new(config));
+    let mut esp_wifi = Box::new(EspWifi::new(peripherals.modem, sysloop.clone(), Some(nvs))?);
+
+    let mut server = EspHttpServer::new(&Configuration {
+        uri_match_wildcard: true,
+        // As many as lwIP allows by default; WebSocket and event stream
+        // clients keep theirs open.
+        max_open_sockets: 7,
+        ..Default::default()
+    })?;
+    let mut router = Router::new();
+
+    if let Err(err) = builder.configure(&mut esp_wifi, sysloop.clone()) {
+        println!("Could not start wifi ({err}), starting provisioning");
+
+        start_provisioning(
+            &mut esp_wifi,
+            sysloop,
+            AccessPointConfiguration {
+                ssid: PROVISIONING_SSID.into(),
+                ..Default::default()
+            },
+        )?;
+
+        let ap_ip = esp_wifi.ap_netif().get_ip_info()?.ip;
+        let _dns = CaptiveDns::start(ap_ip)?;
+        http_server::captive::register(&mut router, ap_ip);
+
+        // New firmware that can't join the network is no good, however it is
+        // set up.
+        let ota = Arc::new(Ota::new(EspUpdater::new()?, |_: &OtaStatus| ()));
+        let offline = ota::HealthCheck::new("wifi", || false);
+        ota.verify(vec![offline], verify_deadline())?;
+
+        // Not having any credentials yet is no error worth showing.
+        let reason = (!matches!(err, WifiError::MissingSsid)).then(|| err.to_string());
+        let provisioner = EspProvisioner::new(esp_wifi, config, reason);
+        http_server::provisioning::register(&mut router, Arc::new(provisioner));
+        http_server::assets::register(&mut router);
+        router.mount(&mut server)?;
+
+        println!("Provisioning awaiting connection on {PROVISIONING_SSID}");
+
+        // Prevent program from exiting, the provisioner restarts the device
+        loop {
+            sleep(Duration::from_millis(1000));
+        }
+    }
 
-    let _wifi = wifi(
-        app_config.wifi_ssid,
-        app_config.wifi_psk,
-        peripherals.modem,
-        sysloop,
-        app_config.wifi_ap,
+    // Clients of our own access point get sent to the index page.
+    let _dns = if wifi_ap {
+        let ap_ip = esp_wifi.ap_netif().get_ip_info()?.ip;
+        http_server::captive::register(&mut router, ap_ip);
+        Some(CaptiveDns::start(ap_ip)?)
+    } else {
+        None
+    };
+
+    let wifi = Arc::new(WifiSupervisor::start(
+        esp_wifi,
+        &sysloop,
+        known_networks,
+        Backoff::default(),
+    )?);
+
+    // The history is recorded in wall-clock time.
+    let _sntp = EspSntp::new_default()?;
+
+    // Without the partition from `partitions.csv`, the history is only kept
+    // until the next reset.
+    let blobs = EspCustomNvsPartition::take(HISTORY_PARTITION)
+        .and_then(|partition| EspNvs::new(partition, "history", true))
+        .map_err(|err| println!("Could not open the history partition ({err})"))
+        .ok();
+    let (history, mut history_store) = restore_history(blobs)?;
+    let live = Arc::new(LiveReadings::new(live::MAX_CLIENTS));
+    let events = Arc::new(EventStream::new(events::MAX_CLIENTS, events::BACKLOG_LEN));
+    let alerts = start_alerts(&config, events.clone());
+    let bus = I2cBus {
+        sda: CONFIG.sensor_sda,
+        scl: CONFIG.sensor_scl,
+        baudrate_khz: CONFIG.sensor_i2c_khz,
+    };
+    let sensor = Shtc3Sensor::new(peripherals.i2c0, bus)?;
+    let sampler = start_sampler::<EspHttpServer, _>(
+        sensor,
+        config.clone(),
+        history.clone(),
+        alerts.clone(),
+        live.clone(),
+        events.clone(),
+    )?;
+    let device = Arc::new(EspDevice::new(wifi.clone(), sampler.snapshot()));
+    let ota = start_ota(EspUpdater::new()?, events.clone());
+
+    live::register(&mut server, live)?;
+    events::register(&mut server, events.clone())?;
+    register_routes(
+        &mut router,
+        device.clone(),
+        &config,
+        &history,
+        &alerts,
+        ota.clone(),
     )?;
+    router.mount(&mut server)?;
+
+    println!("Server awaiting connection");
+    ota.verify(ota::health_checks(device, 80), verify_deadline())?;
+    let _updates = start_updates(EspFetcher, ota)?;
+
+    // Prevent program from exiting
+    let mut link_state = wifi.state();
+    let mut config_changes = config.lock().unwrap().changes();
+    let mut last_save = Instant::now();
+    loop {
+        sleep(Duration::from_millis(1000));
 
-    let mut server = EspHttpServer::new(&Configuration::default())?;
+        if wifi.state() != link_state {
+            link_state = wifi.state();
+            println!("Wifi link state: {link_state:?}");
+            events.publish(&Event::Wifi {
+                connected: link_state == LinkState::Up,
+            });
+        }
+        apply_config_changes(&events, &alerts, &config, &mut config_changes);
+        save_history(&mut history_store, &history, &mut last_save);
+    }
+}
+
+#[cfg(feature = "host")]
+fn main() -> Result<()> {
+    let storage = FileStorage::open(HOST_CONFIG_PATH)?;
+    let config = Arc::new(Mutex::new(ConfigStore::open(storage, defaults(&CONFIG))?));
 
-    server.fn_handler("/", Method::Get, |request| {
-        let mut response = request.into_ok_response()?;
-        let data = index_html();
-        Ok(response.write_all(data.as_bytes())?)
+    let mut server = HostServer::new(&Configuration {
+        uri_match_wildcard: true,
+        ..Default::default()
     })?;
+    let blobs = FileBlobs::open(HOST_HISTORY_DIR)?;
+    let (history, mut history_store) = restore_history(Some(blobs))?;
+    let live = Arc::new(LiveReadings::new(live::MAX_CLIENTS));
+    let events = Arc::new(EventStream::new(events::MAX_CLIENTS, events::BACKLOG_LEN));
+    let alerts = start_alerts(&config, events.clone());
+    let sampler = start_sampler::<HostServer, _>(
+        host_sensor(),
+        config.clone(),
+        history.clone(),
+        alerts.clone(),
+        live.clone(),
+        events.clone(),
+    )?;
+    let device = Arc::new(HostDevice::with_snapshot(sampler.snapshot()));
+    let ota = start_ota(FileUpdater::open(HOST_OTA_DIR)?, events.clone());
 
-    println!("Server awaiting connection");
+    live::register(&mut server, live)?;
+    events::register(&mut server, events.clone())?;
+    let mut router = Router::new();
+    register_routes(
+        &mut router,
+        device.clone(),
+        &config,
+        &history,
+        &alerts,
+        ota.clone(),
+    )?;
+    router.mount(&mut server)?;
+
+    println!(
+        "Server awaiting connection on http://{}/",
+        server.local_addr()
+    );
+    let checks = ota::health_checks(device, server.local_addr().port());
+    ota.verify(checks, verify_deadline())?;
+    let _updates = start_updates(HostFetcher, ota)?;
 
     // Prevent program from exiting
+    let mut config_changes = config.lock().unwrap().changes();
+    let mut last_save = Instant::now();
     loop {
         sleep(Duration::from_millis(1000));
+
+        apply_config_changes(&events, &alerts, &config, &mut config_changes);
+        save_history(&mut history_store, &history, &mut last_save);
     }
 }
 
-fn templated(content: impl AsRef<str>) -> String {
-    format!(
-        r#"
-<!DOCTYPE html>
-<html>
-    <head>
-        <meta charset="utf-8">
-        <title>esp-rs web server</title>
-    </head>
-    <body>
-        {}
-    </body>
-</html>
-"#,
-        content.as_ref()
-    )
-}
-
-fn index_html() -> String {
-    templated("✨ Quint was here!")
+/// Stands in for the SHTC3 when running on the host: a room slowly warming
+/// up and cooling down again.
+#[cfg(feature = "host")]
+fn host_sensor() -> ReplaySensor {
+    let readings = [21.0, 21.5, 22.0, 22.5, 22.0, 21.5]
+        .into_iter()
+        .map(|temperature_celsius| {
+            Ok(Reading {
+                temperature_celsius,
+                humidity_percent: 45.0,
+            })
+        })
+        .collect();
+    ReplaySensor::new(readings)
 }
@@ -0,0 +1,243 @@
+//! Updates pulled from a firmware manifest, for fleets of devices.
+//!
+//! Every so often the device fetches a manifest like
+//!
+//! ```json
+//! {
+//!     "version": "0.2.0",
+//!     "url": "https://example.com/firmware-0.2.0.bin",
+//!     "sha256": "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08",
+//!     "min_hw_revision": 2
+//! }
+//! ```
+//!
+//! and if the version is newer than the running firmware and the board is
+//! recent enough, it downloads the image and installs it through [`Ota`], like
+//! an upload to `POST /api/v1/ota`.
+//!
+//! The image is only as trustworthy as the manifest its SHA-256 comes from,
+//! so the device fetches both over HTTPS only; plain HTTP is left to the
+//! `host` backend, for trying this out.
+
+use std::{
+    io,
+    sync::{
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use semver::Version;
+use serde::Deserialize;
+
+use crate::ota::{self, ImageState, Ota, UpdateError, Updater};
+
+/// Largest accepted manifest.
+const MAX_MANIFEST_LEN: usize = 4096;
+
+/// The version of the running firmware.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct Manifest {
+    /// A semantic version, compared with [`VERSION`].
+    pub version: String,
+    /// Where to download the image from.
+    pub url: String,
+    /// Hex SHA-256 of the image.
+    pub sha256: String,
+    /// The oldest board revision the image runs on.
+    #[serde(default)]
+    pub min_hw_revision: u32,
+}
+
+/// The platform side of pulling updates: an HTTPS client on the device.
+pub trait Fetcher: Send + 'static {
+    /// Sends a `GET` for `url` and receives the head of the response.
+    fn get(&mut self, url: &str) -> anyhow::Result<Box<dyn Download + '_>>;
+}
+
+/// A response whose body is being received.
+pub trait Download {
+    fn status(&self) -> u16;
+
+    fn content_len(&self) -> Option<u64>;
+
+    /// Reads the next part of the body, 0 once there is nothing left.
+    fn read(&mut self, buf: &mut [u8]) -> anyhow::Result<usize>;
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CheckOutcome {
+    /// The manifest is for the running firmware or an older one.
+    UpToDate,
+    /// There is newer firmware, but not for this board.
+    Unsupported { min_hw_revision: u32 },
+    /// The newer firmware is in place and boots after the restart.
+    Installed { version: String },
+}
+
+/// Looks for new firmware in the manifest at a URL.
+pub struct Updates<F, U> {
+    fetcher: F,
+    ota: Arc<Ota<U>>,
+    manifest_url: String,
+    hw_revision: u32,
+}
+
+impl<F: Fetcher, U: Updater> Updates<F, U> {
+    /// `hw_revision` is the revision of this board, see
+    /// [`Manifest::min_hw_revision`].
+    pub fn new(
+        fetcher: F,
+        ota: Arc<Ota<U>>,
+        manifest_url: impl Into<String>,
+        hw_revision: u32,
+    ) -> Self {
+        Self {
+            fetcher,
+            ota,
+            manifest_url: manifest_url.into(),
+            hw_revision,
+        }
+    }
+
+    /// Fetches the manifest and installs the image it points at if that is
+    /// newer, without restarting.
+    pub fn check(&mut self) -> anyhow::Result<CheckOutcome> {
+        // A new image can't be installed before the running one is confirmed.
+        if self.ota.health().state == ImageState::PendingVerify {
+            return Err(UpdateError::Unverified.into());
+        }
+
+        let manifest = self.manifest()?;
+        let version = Version::parse(&manifest.version)
+            .with_context(|| format!("invalid version {:?} in the manifest", manifest.version))?;
+        if version <= Version::parse(VERSION)? {
+            return Ok(CheckOutcome::UpToDate);
+        }
+        if manifest.min_hw_revision > self.hw_revision {
+            return Ok(CheckOutcome::Unsupported {
+                min_hw_revision: manifest.min_hw_revision,
+            });
+        }
+        let sha256 =
+            ota::parse_sha256(&manifest.sha256).context("invalid SHA-256 in the manifest")?;
+
+        let mut download = self.fetcher.get(&manifest.url)?;
+        if download.status() != 200 {
+            bail!(
+                "downloading {} failed with {}",
+                manifest.url,
+                download.status()
+            );
+        }
+        let size = download
+            .content_len()
+            .context("the image size is unknown")?;
+        self.ota
+            .install(size as usize, &sha256, |buf| download.read(buf))?;
+
+        Ok(CheckOutcome::Installed {
+            version: manifest.version,
+        })
+    }
+
+    fn manifest(&mut self) -> anyhow::Result<Manifest> {
+        let mut download = self.fetcher.get(&self.manifest_url)?;
+        if download.status() != 200 {
+            bail!(
+                "fetching {} failed with {}",
+                self.manifest_url,
+                download.status()
+            );
+        }
+
+        let mut body = Vec::new();
+        let mut buf = [0; 512];
+        loop {
+            let read = download.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            if body.len() + read > MAX_MANIFEST_LEN {
+                bail!("the manifest is larger than {MAX_MANIFEST_LEN} bytes");
+            }
+            body.extend_from_slice(&buf[..read]);
+        }
+
+        serde_json::from_slice(&body).context("invalid manifest")
+    }
+
+    /// Reboots into the image installed last.
+    pub fn restart(&self) {
+        self.ota.restart();
+    }
+}
+
+/// Checks for updates every interval until dropped, or until one was
+/// installed and the device restarts.
+pub struct UpdateChecker {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl UpdateChecker {
+    /// Checks once right away and then every `interval`. Failed checks are
+    /// logged and tried again next time.
+    pub fn start<F: Fetcher, U: Updater>(
+        updates: Updates<F, U>,
+        interval: Duration,
+    ) -> io::Result<Self> {
+        // Dropping the sender wakes the thread up to stop.
+        let (stop, stopped) = mpsc::channel();
+
+        let thread = thread::Builder::new()
+            .name("updates".into())
+            // TLS handshakes need more stack than the other threads.
+            .stack_size(12 * 1024)
+            .spawn(move || check_every(updates, interval, &stopped))?;
+
+        Ok(Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for UpdateChecker {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn check_every<F: Fetcher, U: Updater>(
+    mut updates: Updates<F, U>,
+    interval: Duration,
+    stopped: &mpsc::Receiver<()>,
+) {
+    loop {
+        match updates.check() {
+            Ok(CheckOutcome::UpToDate) => (),
+            Ok(CheckOutcome::Unsupported { min_hw_revision }) => {
+                println!("New firmware needs hardware revision {min_hw_revision}, skipping it")
+            }
+            Ok(CheckOutcome::Installed { version }) => {
+                println!("Installed firmware {version}, restarting");
+                updates.restart();
+                return;
+            }
+            Err(err) => eprintln!("Could not check for updates: {err:#}"),
+        }
+
+        if stopped.recv_timeout(interval) != Err(RecvTimeoutError::Timeout) {
+            return;
+        }
+    }
+}
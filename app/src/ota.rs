@@ -0,0 +1,498 @@
+//! Firmware updates over the air, uploaded to `POST /api/v1/ota`.
+//!
+//! The image is streamed into the OTA slot that isn't running, a chunk at a
+//! time, while its SHA-256 is computed on the way. Only once all of it arrived
+//! and the hash matches is the slot made the one to boot next; anything else
+//! leaves the running firmware in charge.
+//!
+//! A new image has to prove itself after it booted: unless it passes its
+//! [`HealthCheck`]s in time, see [`Ota::verify`], the previous image is booted
+//! again. The outcome is served on `/api/v1/ota/health`.
+
+use core::fmt;
+use std::{
+    net::{SocketAddr, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use embedded_svc::http::{
+    server::{Connection, HandlerResult, Request},
+    Headers,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{api::Device, json, router::Router, server::Server};
+
+/// Header with the hex SHA-256 of the uploaded image.
+pub const SHA256_HEADER: &str = "X-Firmware-SHA256";
+
+/// First byte of every ESP32 app image.
+const IMAGE_MAGIC: u8 = 0xe9;
+
+/// Bytes read and written at a time.
+const CHUNK_LEN: usize = 4096;
+
+/// How often progress is reported, in percent.
+const PROGRESS_STEP: usize = 5;
+
+/// How often failed health checks are repeated.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The platform side of firmware updates: the OTA slot that isn't running.
+pub trait Updater: Send + 'static {
+    /// The largest image the slot can take.
+    fn slot_size(&self) -> anyhow::Result<usize>;
+
+    /// Starts writing a new image, erasing the slot.
+    fn begin(&mut self) -> anyhow::Result<()>;
+
+    fn write(&mut self, data: &[u8]) -> anyhow::Result<()>;
+
+    /// Checks the image and boots it next.
+    fn complete(&mut self) -> anyhow::Result<()>;
+
+    /// Gives up on the image, the running firmware stays the one booted.
+    fn abort(&mut self) -> anyhow::Result<()>;
+
+    /// Reboots shortly, after the response has been sent.
+    fn restart(&self);
+
+    /// Whether the running image was just installed and hasn't been
+    /// confirmed yet.
+    fn pending_verify(&self) -> anyhow::Result<bool>;
+
+    /// Keeps the running image for good.
+    fn mark_valid(&mut self) -> anyhow::Result<()>;
+
+    /// Gives up on the running image and reboots into the previous one.
+    fn roll_back(&mut self) -> anyhow::Result<()>;
+
+    /// Whether an update was rolled back before.
+    fn rolled_back(&self) -> anyhow::Result<bool>;
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case", tag = "state")]
+pub enum OtaStatus {
+    Idle,
+    Receiving {
+        received: usize,
+        size: usize,
+    },
+    /// The new image is in place and boots after the restart.
+    Rebooting,
+    Failed {
+        error: String,
+    },
+}
+
+/// Something a freshly updated image has to get working, e.g. joining the
+/// Wi-Fi.
+pub struct HealthCheck {
+    name: &'static str,
+    check: Box<dyn FnMut() -> bool + Send>,
+}
+
+impl HealthCheck {
+    pub fn new(name: &'static str, check: impl FnMut() -> bool + Send + 'static) -> Self {
+        Self {
+            name,
+            check: Box::new(check),
+        }
+    }
+}
+
+/// The checks for the app: the Wi-Fi is up, the HTTP server accepts
+/// connections on `port` and the sensor delivered a reading.
+pub fn health_checks<D: Device>(device: Arc<D>, port: u16) -> Vec<HealthCheck> {
+    let wifi = {
+        let device = device.clone();
+        move || device.wifi_status().is_ok_and(|status| status.connected)
+    };
+    let http = {
+        let device = device.clone();
+        move || {
+            let Some(ip) = device.wifi_status().ok().and_then(|status| status.ip) else {
+                return false;
+            };
+            let addr = SocketAddr::from((ip, port));
+            TcpStream::connect_timeout(&addr, CHECK_INTERVAL).is_ok()
+        }
+    };
+    let sensor = move || device.reading().is_some();
+
+    vec![
+        HealthCheck::new("wifi", wifi),
+        HealthCheck::new("http", http),
+        HealthCheck::new("sensor", sensor),
+    ]
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageState {
+    /// Confirmed, by the checks of this boot or before.
+    Valid,
+    /// Booted for the first time after an update, the checks are running.
+    PendingVerify,
+    /// The checks failed, the previous image boots again.
+    Failed,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct CheckStatus {
+    pub name: &'static str,
+    pub passed: bool,
+}
+
+/// How the running image fared, served on `/api/v1/ota/health`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Health {
+    pub state: ImageState,
+    /// The checks of this boot, empty unless it followed an update.
+    pub checks: Vec<CheckStatus>,
+    /// Whether an update before failed its checks and was undone.
+    pub rolled_back: bool,
+}
+
+#[derive(Debug)]
+pub enum UpdateError {
+    /// Another image is being written, or one was written and the restart
+    /// into it is pending.
+    Busy,
+    /// The running image hasn't passed its checks yet.
+    Unverified,
+    TooLarge {
+        size: usize,
+        capacity: usize,
+    },
+    /// The upload ended before `size` bytes.
+    Truncated {
+        received: usize,
+        size: usize,
+    },
+    ChecksumMismatch,
+    InvalidImage(String),
+    /// Receiving the image or writing it to flash failed.
+    Io(anyhow::Error),
+}
+
+impl UpdateError {
+    /// The HTTP status to answer with.
+    pub fn status(&self) -> u16 {
+        match self {
+            Self::Busy | Self::Unverified => 409,
+            Self::TooLarge { .. } => 413,
+            Self::Truncated { .. } | Self::ChecksumMismatch | Self::InvalidImage(_) => 400,
+            Self::Io(_) => 500,
+        }
+    }
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Busy => write!(f, "An update is already in progress"),
+            Self::Unverified => write!(f, "The running firmware is still being checked"),
+            Self::TooLarge { size, capacity } => write!(
+                f,
+                "The image is {size} bytes, but the OTA slot only holds {capacity}"
+            ),
+            Self::Truncated { received, size } => {
+                write!(f, "The upload ended after {received} of {size} bytes")
+            }
+            Self::ChecksumMismatch => write!(f, "The image doesn't match its SHA-256"),
+            Self::InvalidImage(err) => write!(f, "The image is not valid firmware: {err}"),
+            Self::Io(err) => write!(f, "Writing the image failed: {err:#}"),
+        }
+    }
+}
+
+impl std::error::Error for UpdateError {}
+
+impl From<anyhow::Error> for UpdateError {
+    fn from(err: anyhow::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+pub struct Ota<U> {
+    updater: Mutex<U>,
+    status: Mutex<OtaStatus>,
+    on_status: Box<dyn Fn(&OtaStatus) + Send + Sync>,
+    health: Mutex<Health>,
+}
+
+impl<U: Updater> Ota<U> {
+    /// `on_status` is called with every change of the status, e.g. to pass on
+    /// the progress of an upload.
+    pub fn new(updater: U, on_status: impl Fn(&OtaStatus) + Send + Sync + 'static) -> Self {
+        Self {
+            updater: Mutex::new(updater),
+            status: Mutex::new(OtaStatus::Idle),
+            on_status: Box::new(on_status),
+            health: Mutex::new(Health {
+                state: ImageState::Valid,
+                checks: Vec::new(),
+                rolled_back: false,
+            }),
+        }
+    }
+
+    pub fn status(&self) -> OtaStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Writes the image of `size` bytes that `read` yields to the slot that
+    /// isn't running and boots it next, if its hash is `sha256`. `read`
+    /// returns 0 once there is nothing left.
+    pub fn install(
+        &self,
+        size: usize,
+        sha256: &[u8; 32],
+        read: impl FnMut(&mut [u8]) -> anyhow::Result<usize>,
+    ) -> Result<(), UpdateError> {
+        if self.health().state == ImageState::PendingVerify {
+            return Err(UpdateError::Unverified);
+        }
+        let mut updater = self.updater.try_lock().map_err(|_| UpdateError::Busy)?;
+        if matches!(self.status(), OtaStatus::Rebooting) {
+            return Err(UpdateError::Busy);
+        }
+        let result = self.write_image(&mut *updater, size, sha256, read);
+
+        let status = match &result {
+            Ok(()) => OtaStatus::Rebooting,
+            Err(err) => OtaStatus::Failed {
+                error: err.to_string(),
+            },
+        };
+        self.set_status(status);
+        result
+    }
+
+    fn write_image(
+        &self,
+        updater: &mut U,
+        size: usize,
+        sha256: &[u8; 32],
+        mut read: impl FnMut(&mut [u8]) -> anyhow::Result<usize>,
+    ) -> Result<(), UpdateError> {
+        let capacity = updater.slot_size()?;
+        if size == 0 {
+            return Err(UpdateError::InvalidImage("the image is empty".into()));
+        }
+        if size > capacity {
+            return Err(UpdateError::TooLarge { size, capacity });
+        }
+
+        updater.begin()?;
+        self.set_status(OtaStatus::Receiving { received: 0, size });
+
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; CHUNK_LEN];
+        let mut received = 0;
+        let written = loop {
+            if received == size {
+                break Ok(());
+            }
+
+            let len = CHUNK_LEN.min(size - received);
+            let chunk = match read(&mut buf[..len]) {
+                Ok(0) => break Err(UpdateError::Truncated { received, size }),
+                Ok(read) => &buf[..read],
+                Err(err) => break Err(err.into()),
+            };
+            if received == 0 && chunk[0] != IMAGE_MAGIC {
+                break Err(UpdateError::InvalidImage("not an ESP32 app image".into()));
+            }
+            hasher.update(chunk);
+            if let Err(err) = updater.write(chunk) {
+                break Err(err.into());
+            }
+
+            let before = received * 100 / size / PROGRESS_STEP;
+            received += chunk.len();
+            if received * 100 / size / PROGRESS_STEP != before {
+                self.set_status(OtaStatus::Receiving { received, size });
+            }
+        };
+
+        let checked = written.and_then(|()| {
+            if hasher.finalize()[..] == sha256[..] {
+                Ok(())
+            } else {
+                Err(UpdateError::ChecksumMismatch)
+            }
+        });
+        if let Err(err) = checked {
+            if let Err(err) = updater.abort() {
+                eprintln!("Could not abort the update: {err:#}");
+            }
+            return Err(err);
+        }
+
+        updater
+            .complete()
+            .map_err(|err| UpdateError::InvalidImage(format!("{err:#}")))
+    }
+
+    fn set_status(&self, status: OtaStatus) {
+        (self.on_status)(&status);
+        *self.status.lock().unwrap() = status;
+    }
+
+    /// Reboots into the image installed last, see [`Updater::restart`].
+    pub fn restart(&self) {
+        self.updater.lock().unwrap().restart();
+    }
+
+    pub fn health(&self) -> Health {
+        self.health.lock().unwrap().clone()
+    }
+
+    /// If the running image was just installed, keeps it once every one of
+    /// `checks` passed, or rolls back to the previous one if they didn't
+    /// within `deadline`. The checks run on a thread of their own, every
+    /// second until they passed once.
+    pub fn verify(
+        self: &Arc<Self>,
+        checks: Vec<HealthCheck>,
+        deadline: Duration,
+    ) -> anyhow::Result<()> {
+        let (pending, rolled_back) = {
+            let updater = self.updater.lock().unwrap();
+            (updater.pending_verify()?, updater.rolled_back()?)
+        };
+        {
+            let mut health = self.health.lock().unwrap();
+            health.rolled_back = rolled_back;
+            if !pending {
+                return Ok(());
+            }
+            health.state = ImageState::PendingVerify;
+            health.checks = checks
+                .iter()
+                .map(|check| CheckStatus {
+                    name: check.name,
+                    passed: false,
+                })
+                .collect();
+        }
+
+        println!("Checking the new firmware");
+        let ota = self.clone();
+        thread::Builder::new()
+            .name("verify".into())
+            .stack_size(4096)
+            .spawn(move || ota.run_checks(checks, deadline))?;
+        Ok(())
+    }
+
+    fn run_checks(&self, mut checks: Vec<HealthCheck>, deadline: Duration) {
+        let started = Instant::now();
+        let mut passed = vec![false; checks.len()];
+        loop {
+            for (check, passed) in checks.iter_mut().zip(&mut passed) {
+                *passed = *passed || (check.check)();
+            }
+            let mut health = self.health.lock().unwrap();
+            for (status, passed) in health.checks.iter_mut().zip(&passed) {
+                status.passed = *passed;
+            }
+
+            if passed.iter().all(|passed| *passed) {
+                match self.updater.lock().unwrap().mark_valid() {
+                    Ok(()) => {
+                        println!("The new firmware passed its checks");
+                        health.state = ImageState::Valid;
+                    }
+                    // The bootloader rolls back after the next reset.
+                    Err(err) => eprintln!("Could not keep the new firmware: {err:#}"),
+                }
+                return;
+            }
+            if started.elapsed() >= deadline {
+                let failed: Vec<_> = health
+                    .checks
+                    .iter()
+                    .filter(|status| !status.passed)
+                    .map(|status| status.name)
+                    .collect();
+                eprintln!(
+                    "The new firmware failed its checks ({}), rolling back",
+                    failed.join(", ")
+                );
+                // Only reported once rolled back, so nobody acting on it
+                // races the rollback.
+                if let Err(err) = self.updater.lock().unwrap().roll_back() {
+                    eprintln!("Could not roll back: {err:#}");
+                }
+                health.state = ImageState::Failed;
+                return;
+            }
+            drop(health);
+
+            thread::sleep(CHECK_INTERVAL);
+        }
+    }
+}
+
+/// Uploads are open like any other route until protected, see
+/// [`auth::Strict`](crate::auth::Strict).
+pub fn register<Srv: Server + 'static, U: Updater>(router: &mut Router<Srv>, ota: Arc<Ota<U>>) {
+    {
+        let ota = ota.clone();
+        router.get("/api/v1/ota", move |request, _| {
+            json::respond(request, 200, &ota.status())
+        });
+    }
+    {
+        let ota = ota.clone();
+        router.get("/api/v1/ota/health", move |request, _| {
+            json::respond(request, 200, &ota.health())
+        });
+    }
+    router.post("/api/v1/ota", move |request, _| upload(request, &ota));
+}
+
+/// Installs the image in the body and restarts into it.
+pub fn upload<C: Connection, U: Updater>(mut request: Request<C>, ota: &Ota<U>) -> HandlerResult {
+    let Some(size) = request.content_len() else {
+        return json::error(request, 411, "The image size is required.");
+    };
+    let Some(sha256) = request.header(SHA256_HEADER).and_then(parse_sha256) else {
+        return json::error(request, 400, "The image SHA-256 is required.");
+    };
+
+    let read = |buf: &mut [u8]| {
+        request
+            .read(buf)
+            .map_err(|err| anyhow::anyhow!("receiving failed: {err:?}"))
+    };
+    match ota.install(size as usize, &sha256, read) {
+        Ok(()) => {
+            json::respond(request, 200, &ota.status())?;
+            ota.restart();
+            Ok(())
+        }
+        Err(err) => json::error(request, err.status(), &err.to_string()),
+    }
+}
+
+pub(crate) fn parse_sha256(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let mut hash = [0; 32];
+    for (byte, digits) in hash.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let digits = core::str::from_utf8(digits).ok()?;
+        *byte = u8::from_str_radix(digits, 16).ok()?;
+    }
+    Some(hash)
+}
@@ -0,0 +1,35 @@
+//! HTML pages, as [askama] templates from `templates/`.
+//!
+//! The templates are compiled into the binary, so a typo in a field name is a
+//! build error, and values are HTML-escaped unless a template says otherwise.
+//! Every page extends `layout.html` and can set its own title with the
+//! `title` block.
+
+use askama::Template;
+use embedded_svc::{
+    http::server::{Connection, HandlerResult, Request},
+    io::Write,
+};
+
+use crate::{router::reason_phrase, sensor::Reading};
+
+#[derive(Template)]
+#[template(path = "temperature.html")]
+pub struct TemperaturePage<'a> {
+    pub reading: Option<&'a Reading>,
+}
+
+/// Renders `page` as the body of a `status` response.
+pub fn respond<C: Connection>(
+    request: Request<C>,
+    status: u16,
+    page: &impl Template,
+) -> HandlerResult {
+    let html = page.render()?;
+    let mut response = request.into_response(
+        status,
+        reason_phrase(status),
+        &[("Content-Type", "text/html; charset=utf-8")],
+    )?;
+    Ok(response.write_all(html.as_bytes())?)
+}
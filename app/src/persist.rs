@@ -0,0 +1,134 @@
+//! Keeps the sensor history across reboots, in two slots of flash that are
+//! written in turns.
+//!
+//! Every slot holds a complete copy of the history, checked with a CRC and
+//! numbered in sequence. A write cut short by a reset or brownout leaves the
+//! other slot intact, so loading falls back to the copy before. Flushes are
+//! meant to be batched, see [`HistoryStore::save`], as every one of them
+//! rewrites the whole history.
+
+use crate::history::{History, SharedHistory};
+
+/// Blobs of bytes by key, at most 15 bytes long, the NVS limit.
+pub trait BlobStorage: Send + 'static {
+    fn read(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+
+    fn write(&mut self, key: &str, data: &[u8]) -> anyhow::Result<()>;
+}
+
+const SLOTS: [&str; 2] = ["history_a", "history_b"];
+
+const MAGIC: &[u8; 4] = b"HIST";
+const FORMAT_VERSION: u8 = 1;
+
+/// Magic, format version, sequence number and payload length.
+const HEADER_LEN: usize = 4 + 1 + 4 + 4;
+const CRC_LEN: usize = 4;
+
+pub struct HistoryStore<B> {
+    blobs: B,
+    /// Of the slot written last.
+    sequence: u32,
+    saved_changes: u64,
+}
+
+impl<B: BlobStorage> HistoryStore<B> {
+    /// Restores the newest intact copy in `blobs` into `history`. Slots that
+    /// are missing, can't be read, are truncated or fail their CRC are
+    /// skipped.
+    pub fn open(blobs: B, history: &mut History) -> anyhow::Result<Self> {
+        let mut newest: Option<(u32, Vec<u8>)> = None;
+        for slot in SLOTS {
+            let blob = match blobs.read(slot) {
+                Ok(Some(blob)) => blob,
+                Ok(None) => continue,
+                Err(err) => {
+                    eprintln!("Skipping {slot}: {err:#}");
+                    continue;
+                }
+            };
+            match decode(&blob) {
+                Ok((sequence, payload)) => {
+                    let newer = match &newest {
+                        Some((newest, _)) => sequence > *newest,
+                        None => true,
+                    };
+                    if newer {
+                        newest = Some((sequence, payload.to_vec()));
+                    }
+                }
+                Err(err) => eprintln!("Skipping {slot}: {err}"),
+            }
+        }
+
+        let sequence = match newest {
+            Some((sequence, payload)) => {
+                if let Err(err) = history.restore(&payload) {
+                    eprintln!("Could not restore the history: {err}");
+                }
+                sequence
+            }
+            None => 0,
+        };
+
+        Ok(Self {
+            blobs,
+            sequence,
+            saved_changes: history.changes(),
+        })
+    }
+
+    /// Writes `history` over the older copy, unless nothing was recorded
+    /// since the last save. Returns whether it wrote anything.
+    pub fn save(&mut self, history: &SharedHistory) -> anyhow::Result<bool> {
+        let (changes, payload) = {
+            let history = history.lock().unwrap();
+            if self.saved_changes == history.changes() {
+                return Ok(false);
+            }
+            (history.changes(), history.encode())
+        };
+
+        let sequence = self.sequence.wrapping_add(1);
+        let slot = SLOTS[sequence as usize % SLOTS.len()];
+        self.blobs.write(slot, &encode(sequence, &payload))?;
+
+        self.sequence = sequence;
+        self.saved_changes = changes;
+        Ok(true)
+    }
+}
+
+fn encode(sequence: u32, payload: &[u8]) -> Vec<u8> {
+    let mut blob = Vec::with_capacity(HEADER_LEN + payload.len() + CRC_LEN);
+    blob.extend_from_slice(MAGIC);
+    blob.push(FORMAT_VERSION);
+    blob.extend_from_slice(&sequence.to_le_bytes());
+    blob.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    blob.extend_from_slice(payload);
+    let crc = crc32fast::hash(&blob);
+    blob.extend_from_slice(&crc.to_le_bytes());
+    blob
+}
+
+/// The sequence number and payload of a slot.
+fn decode(blob: &[u8]) -> anyhow::Result<(u32, &[u8])> {
+    anyhow::ensure!(blob.len() >= HEADER_LEN + CRC_LEN, "truncated header");
+    anyhow::ensure!(&blob[..4] == MAGIC, "not a history");
+    anyhow::ensure!(
+        blob[4] == FORMAT_VERSION,
+        "unknown format version {}",
+        blob[4]
+    );
+    let sequence = u32::from_le_bytes(blob[5..9].try_into().unwrap());
+    let len = u32::from_le_bytes(blob[9..13].try_into().unwrap()) as usize;
+
+    let (contents, crc) = blob.split_at(blob.len() - CRC_LEN);
+    anyhow::ensure!(contents.len() == HEADER_LEN + len, "truncated payload");
+    anyhow::ensure!(
+        crc32fast::hash(contents).to_le_bytes() == crc,
+        "CRC mismatch"
+    );
+
+    Ok((sequence, &contents[HEADER_LEN..]))
+}
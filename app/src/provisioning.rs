@@ -0,0 +1,101 @@
+//! Setup page served from the access point opened when the device has no
+//! working Wi-Fi credentials.
+
+use std::sync::Arc;
+
+use askama::Template;
+use embedded_svc::http::server::{Connection, HandlerResult, Request};
+
+use crate::{
+    config, form, pages,
+    router::{reject, Router},
+    server::Server,
+};
+
+#[derive(Clone, Debug)]
+pub struct ScannedNetwork {
+    pub ssid: String,
+    pub signal_strength: i8,
+    pub open: bool,
+}
+
+/// The platform side of provisioning.
+pub trait Provisioner: Send + Sync + 'static {
+    /// Networks in range, strongest first.
+    fn scan(&self) -> anyhow::Result<Vec<ScannedNetwork>>;
+
+    /// Why provisioning was started, e.g. the error of the last connection
+    /// attempt.
+    fn reason(&self) -> Option<String>;
+
+    /// Persists the station credentials.
+    fn save(&self, ssid: &str, password: &str) -> anyhow::Result<()>;
+
+    /// Reboots into station mode shortly, after the response has been sent.
+    fn restart(&self);
+}
+
+pub fn register<S: Server + 'static, P: Provisioner>(router: &mut Router<S>, provisioner: Arc<P>) {
+    {
+        let provisioner = provisioner.clone();
+        router.get("/", move |request, _| setup(request, &*provisioner));
+    }
+    router.post("/", move |request, _| save(request, &*provisioner));
+}
+
+pub fn setup<C: Connection>(request: Request<C>, provisioner: &impl Provisioner) -> HandlerResult {
+    let networks = provisioner.scan()?;
+    let reason = provisioner.reason();
+    let page = SetupPage {
+        networks: &networks,
+        error: reason.as_deref(),
+    };
+    pages::respond(request, 200, &page)
+}
+
+pub fn save<C: Connection>(
+    mut request: Request<C>,
+    provisioner: &impl Provisioner,
+) -> HandlerResult {
+    let body = match form::read_body(&mut request, 256) {
+        Ok(body) => body,
+        Err(err) => return reject(request, err.status(), &err.to_string()),
+    };
+    let fields = form::parse(&body);
+    let ssid = form::field(&fields, "ssid").unwrap_or_default();
+    let password = form::field(&fields, "password").unwrap_or_default();
+
+    // The device joins the network as a station, where no password means an
+    // open one.
+    let valid = config::validate_ssid(ssid).and_then(|()| match password {
+        "" => Ok(()),
+        password => config::validate_psk(password, false),
+    });
+    if let Err(error) = valid {
+        let networks = provisioner.scan()?;
+        let page = SetupPage {
+            networks: &networks,
+            error: Some(error),
+        };
+        return pages::respond(request, 400, &page);
+    }
+
+    provisioner.save(ssid, password)?;
+    pages::respond(request, 200, &SavedPage { ssid })?;
+
+    provisioner.restart();
+    Ok(())
+}
+
+#[derive(Template)]
+#[template(path = "setup.html")]
+pub struct SetupPage<'a> {
+    pub networks: &'a [ScannedNetwork],
+    pub error: Option<&'a str>,
+}
+
+#[derive(Template)]
+#[template(path = "setup_saved.html")]
+pub struct SavedPage<'a> {
+    pub ssid: &'a str,
+}
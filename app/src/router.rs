@@ -0,0 +1,389 @@
+//! Routes requests by method and path pattern, on any [`Server`].
+//!
+//! The router is mounted on the server as one wildcard handler per method and
+//! does the matching itself, so patterns can have parameters like
+//! `/sensors/{id}`, and a path that exists with another method gets a
+//! `405 Method Not Allowed` instead of a `404`. The server has to be created
+//! with `uri_match_wildcard` enabled.
+//!
+//! Routes can be protected with a [`Guard`], which is asked before the handler
+//! runs, see [`Router::protect`].
+
+use std::{fmt, str::FromStr, sync::Arc};
+
+use embedded_svc::{
+    http::{
+        server::{Connection, HandlerResult, Request},
+        Method,
+    },
+    io::Write,
+};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    auth::{self, Guard},
+    json,
+    server::Server,
+};
+
+/// Methods the router is mounted for; requests with any other method are
+/// answered by the server itself.
+const METHODS: [Method; 5] = [
+    Method::Get,
+    Method::Post,
+    Method::Put,
+    Method::Delete,
+    Method::Patch,
+];
+
+type BoxedHandler<S> = Box<
+    dyn for<'a> Fn(Request<&mut <S as Server>::Connection<'a>>, &Params) -> HandlerResult
+        + Send
+        + Sync,
+>;
+
+struct Route<S: Server> {
+    method: Method,
+    pattern: Pattern,
+    handler: BoxedHandler<S>,
+}
+
+/// A guard for the routes matching `pattern`, with one of `methods`.
+struct Protection {
+    methods: Vec<Method>,
+    pattern: Pattern,
+    guard: Arc<dyn Guard>,
+}
+
+pub struct Router<S: Server> {
+    routes: Vec<Route<S>>,
+    protections: Vec<Protection>,
+}
+
+impl<S: Server + 'static> Router<S> {
+    pub fn new() -> Self {
+        Self {
+            routes: Vec::new(),
+            protections: Vec::new(),
+        }
+    }
+
+    /// Adds a route for `pattern`, a path whose segments are either literal or
+    /// a `{name}` parameter matching any one segment. The last segment can also
+    /// be `{*name}`, matching the rest of the path. Routes are tried in the
+    /// order they were added.
+    pub fn route<F>(&mut self, pattern: &str, method: Method, handler: F) -> &mut Self
+    where
+        F: for<'a> Fn(Request<&mut S::Connection<'a>>, &Params) -> HandlerResult
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.routes.push(Route {
+            method,
+            pattern: Pattern::parse(pattern),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn get<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: for<'a> Fn(Request<&mut S::Connection<'a>>, &Params) -> HandlerResult
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.route(pattern, Method::Get, handler)
+    }
+
+    pub fn post<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: for<'a> Fn(Request<&mut S::Connection<'a>>, &Params) -> HandlerResult
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.route(pattern, Method::Post, handler)
+    }
+
+    /// Has `guard` check requests for paths matching `pattern` with one of
+    /// `methods` before they reach their handler, whether the routes are added
+    /// before or after. A denied request is answered by
+    /// [`auth::challenge`]; a path without a route for the method still gets
+    /// its `404` or `405`.
+    pub fn protect(
+        &mut self,
+        methods: &[Method],
+        pattern: &str,
+        guard: Arc<dyn Guard>,
+    ) -> &mut Self {
+        self.protections.push(Protection {
+            methods: methods.to_vec(),
+            pattern: Pattern::parse(pattern),
+            guard,
+        });
+        self
+    }
+
+    /// Hands every request to `server` to the router.
+    pub fn mount(self, server: &mut S) -> Result<(), S::Error> {
+        let router = Arc::new(self);
+
+        for method in METHODS {
+            let router = router.clone();
+            server.fn_handler("/*", method, move |request| router.dispatch(request))?;
+        }
+
+        Ok(())
+    }
+
+    fn dispatch(&self, request: Request<&mut S::Connection<'_>>) -> HandlerResult {
+        let uri = request.uri().to_owned();
+        let (path, query) = uri.split_once('?').unwrap_or((&uri, ""));
+        let method = request.method();
+
+        let mut allowed = Vec::new();
+        for route in &self.routes {
+            let Some(path_params) = route.pattern.matches(path) else {
+                continue;
+            };
+            if route.method == method {
+                if let Err(denied) = self.check(&request, method, path) {
+                    return auth::challenge(request, &denied);
+                }
+                let params = Params {
+                    path: path_params,
+                    query: query.into(),
+                };
+                return (route.handler)(request, &params);
+            }
+            if !allowed.contains(&method_name(route.method)) {
+                allowed.push(method_name(route.method));
+            }
+        }
+
+        if allowed.is_empty() {
+            return reject(request, 404, "Not Found");
+        }
+
+        let allow = allowed.join(", ");
+        if wants_json(&request) {
+            let message = format!("Allowed methods: {allow}");
+            let body = json::ErrorBody::new(405, &message);
+            return json::respond_with(request, 405, &[("Allow", &allow)], &body);
+        }
+
+        request.into_response(405, Some("Method Not Allowed"), &[("Allow", &allow)])?;
+        Ok(())
+    }
+
+    /// Asks every guard protecting `method` on `path`.
+    fn check(
+        &self,
+        request: &Request<&mut S::Connection<'_>>,
+        method: Method,
+        path: &str,
+    ) -> Result<(), auth::Denied> {
+        let guards = self.protections.iter().filter(|protection| {
+            protection.methods.contains(&method) && protection.pattern.matches(path).is_some()
+        });
+        for protection in guards {
+            protection.guard.check(request.header("Authorization"))?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Server + 'static> Default for Router<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Path parameters and query string of a routed request.
+#[derive(Clone, Debug, Default)]
+pub struct Params {
+    path: Vec<(String, String)>,
+    query: String,
+}
+
+impl Params {
+    /// The decoded value of the path parameter `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.path
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The path parameter `name`, parsed.
+    pub fn parse<T: FromStr>(&self, name: &str) -> Result<T, ParamError> {
+        let value = self.get(name).ok_or_else(|| ParamError::missing(name))?;
+        value.parse().map_err(|_| ParamError::invalid(name, value))
+    }
+
+    /// The query string deserialized into `T`, with missing fields defaulted
+    /// as `T`'s `serde` attributes say.
+    pub fn query<T: DeserializeOwned>(&self) -> Result<T, ParamError> {
+        serde_urlencoded::from_str(&self.query).map_err(|err| ParamError(err.to_string()))
+    }
+}
+
+/// A path parameter or query string that doesn't fit what the handler
+/// expects; usually answered with [`reject`] and a `400 Bad Request`.
+#[derive(Clone, Debug)]
+pub struct ParamError(String);
+
+impl ParamError {
+    fn missing(name: &str) -> Self {
+        Self(format!("missing parameter {name}"))
+    }
+
+    fn invalid(name: &str, value: &str) -> Self {
+        Self(format!("invalid value {value:?} for {name}"))
+    }
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ParamError {}
+
+/// Responds with `status` and `message`, as a JSON error object to API and
+/// JSON clients and as plain text to everyone else.
+pub fn reject<C: Connection>(request: Request<C>, status: u16, message: &str) -> HandlerResult {
+    if wants_json(&request) {
+        return json::error(request, status, message);
+    }
+
+    let mut response = request.into_response(
+        status,
+        reason_phrase(status),
+        &[("Content-Type", "text/plain; charset=utf-8")],
+    )?;
+    Ok(response.write_all(message.as_bytes())?)
+}
+
+pub(crate) fn wants_json<C: Connection>(request: &Request<C>) -> bool {
+    request.uri().starts_with("/api/") || json::accepts_json(request)
+}
+
+enum Segment {
+    Literal(String),
+    Param(String),
+    Rest(String),
+}
+
+struct Pattern(Vec<Segment>);
+
+impl Pattern {
+    fn parse(pattern: &str) -> Self {
+        Self(
+            segments(pattern)
+                .map(
+                    |segment| match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                        Some(name) => match name.strip_prefix('*') {
+                            Some(name) => Segment::Rest(name.into()),
+                            None => Segment::Param(name.into()),
+                        },
+                        None => Segment::Literal(segment.into()),
+                    },
+                )
+                .collect(),
+        )
+    }
+
+    /// The decoded parameters if `path` matches.
+    fn matches(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let mut params = Vec::new();
+        let mut segments = segments(path);
+
+        for expected in &self.0 {
+            let segment = segments.next()?;
+            match expected {
+                Segment::Literal(literal) if literal == segment => (),
+                Segment::Literal(_) => return None,
+                Segment::Param(_) if segment.is_empty() => return None,
+                Segment::Param(name) => params.push((name.clone(), percent_decode(segment))),
+                Segment::Rest(name) => {
+                    let rest = [segment].into_iter().chain(segments).collect::<Vec<_>>();
+                    let rest = percent_decode(&rest.join("/"));
+                    if rest.is_empty() {
+                        return None;
+                    }
+                    params.push((name.clone(), rest));
+                    return Some(params);
+                }
+            }
+        }
+
+        segments.next().is_none().then_some(params)
+    }
+}
+
+fn segments(path: &str) -> std::str::Split<'_, char> {
+    path.strip_prefix('/').unwrap_or(path).split('/')
+}
+
+/// Decodes a path, where unlike in form bodies, see [`form::parse`](crate::form::parse),
+/// `+` stays a plus. Malformed escapes are kept as they are.
+fn percent_decode(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        let decoded = (byte == b'%')
+            .then(|| rest.get(..2))
+            .flatten()
+            .and_then(|hex| core::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match decoded {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &rest[2..];
+            }
+            None => bytes.push(byte),
+        }
+    }
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+pub(crate) fn reason_phrase(status: u16) -> Option<&'static str> {
+    Some(match status {
+        200 => "OK",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => return None,
+    })
+}
+
+fn method_name(method: Method) -> &'static str {
+    match method {
+        Method::Delete => "DELETE",
+        Method::Get => "GET",
+        Method::Head => "HEAD",
+        Method::Post => "POST",
+        Method::Put => "PUT",
+        Method::Connect => "CONNECT",
+        Method::Options => "OPTIONS",
+        Method::Trace => "TRACE",
+        Method::Patch => "PATCH",
+        _ => "",
+    }
+}
@@ -0,0 +1,132 @@
+//! Reads the sensor on a thread of its own, so that handlers only ever look at
+//! the latest reading instead of waiting for the sensor.
+
+use std::{
+    io,
+    sync::{
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::sensor::{Reading, Sensor};
+
+/// A reading and when it was taken.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub reading: Reading,
+    pub taken: Instant,
+}
+
+impl Sample {
+    pub fn age(&self) -> Duration {
+        self.taken.elapsed()
+    }
+}
+
+/// The latest sample, shared between the sampler and whoever shows it.
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    latest: Mutex<Option<Sample>>,
+}
+
+impl Snapshot {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn latest(&self) -> Option<Sample> {
+        *self.latest.lock().unwrap()
+    }
+
+    pub fn reading(&self) -> Option<Reading> {
+        self.latest().map(|sample| sample.reading)
+    }
+
+    /// Replaces the latest sample with `reading`, taken just now.
+    pub fn update(&self, reading: Reading) {
+        *self.latest.lock().unwrap() = Some(Sample {
+            reading,
+            taken: Instant::now(),
+        });
+    }
+}
+
+/// Takes a reading every interval until dropped.
+pub struct Sampler {
+    snapshot: Arc<Snapshot>,
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Sampler {
+    /// Starts sampling `sensor`. Every reading goes into the snapshot and then
+    /// to `publish`. A failed reading is logged and leaves the last good one
+    /// in place.
+    ///
+    /// `interval` is asked again after every measurement, so it can follow
+    /// the runtime configuration.
+    pub fn start<I, P>(sensor: impl Sensor, interval: I, publish: P) -> io::Result<Self>
+    where
+        I: FnMut() -> Duration + Send + 'static,
+        P: FnMut(&Reading) + Send + 'static,
+    {
+        let snapshot = Arc::new(Snapshot::new());
+        // Dropping the sender wakes the thread up to stop.
+        let (stop, stopped) = mpsc::channel();
+
+        let thread = {
+            let snapshot = snapshot.clone();
+            thread::Builder::new()
+                .name("sampler".into())
+                .stack_size(4096)
+                .spawn(move || sample(&snapshot, &stopped, sensor, interval, publish))?
+        };
+
+        Ok(Self {
+            snapshot,
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        self.snapshot.clone()
+    }
+}
+
+impl Drop for Sampler {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn sample(
+    snapshot: &Snapshot,
+    stopped: &mpsc::Receiver<()>,
+    mut sensor: impl Sensor,
+    mut interval: impl FnMut() -> Duration,
+    mut publish: impl FnMut(&Reading),
+) {
+    loop {
+        let started = Instant::now();
+
+        match sensor.read() {
+            Ok(reading) => {
+                snapshot.update(reading);
+                publish(&reading);
+            }
+            Err(err) => eprintln!("{err}"),
+        }
+
+        let wait = interval().saturating_sub(started.elapsed());
+        if stopped.recv_timeout(wait) != Err(RecvTimeoutError::Timeout) {
+            return;
+        }
+    }
+}
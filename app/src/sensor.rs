@@ -0,0 +1,103 @@
+//! Temperature and humidity readings, and the sensors they come from.
+
+use core::fmt;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+pub struct Reading {
+    pub temperature_celsius: f32,
+    pub humidity_percent: f32,
+}
+
+impl Reading {
+    pub fn value(&self, channel: Channel) -> f32 {
+        match channel {
+            Channel::Temperature => self.temperature_celsius,
+            Channel::Humidity => self.humidity_percent,
+        }
+    }
+}
+
+/// One of the quantities in a [`Reading`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    Temperature,
+    Humidity,
+}
+
+impl Channel {
+    pub const ALL: [Self; 2] = [Self::Temperature, Self::Humidity];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Temperature => "temperature",
+            Self::Humidity => "humidity",
+        }
+    }
+
+    pub fn unit(self) -> &'static str {
+        match self {
+            Self::Temperature => "°C",
+            Self::Humidity => "%",
+        }
+    }
+}
+
+/// Something that takes readings, usually owned by a
+/// [`Sampler`](crate::sampler::Sampler).
+pub trait Sensor: Send + 'static {
+    /// The channels this sensor measures; the others are left at zero.
+    fn channels(&self) -> &'static [Channel];
+
+    /// Takes a reading, blocking until the sensor has one.
+    fn read(&mut self) -> Result<Reading, SensorError>;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SensorError {
+    /// Talking to the sensor failed.
+    Bus(String),
+    /// The sensor answered, but the checksum of its answer is wrong.
+    Checksum,
+}
+
+impl fmt::Display for SensorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bus(err) => write!(f, "Sensor bus error: {err}"),
+            Self::Checksum => write!(f, "Sensor reading failed its checksum"),
+        }
+    }
+}
+
+impl std::error::Error for SensorError {}
+
+/// Plays back a fixed sequence of readings and errors, over and over, for
+/// running on the host and in tests.
+#[derive(Clone, Debug)]
+pub struct ReplaySensor {
+    readings: Vec<Result<Reading, SensorError>>,
+    next: usize,
+}
+
+impl ReplaySensor {
+    /// Panics if `readings` is empty.
+    pub fn new(readings: Vec<Result<Reading, SensorError>>) -> Self {
+        assert!(!readings.is_empty(), "nothing to replay");
+        Self { readings, next: 0 }
+    }
+}
+
+impl Sensor for ReplaySensor {
+    fn channels(&self) -> &'static [Channel] {
+        &Channel::ALL
+    }
+
+    fn read(&mut self) -> Result<Reading, SensorError> {
+        let reading = self.readings[self.next].clone();
+        self.next = (self.next + 1) % self.readings.len();
+        reading
+    }
+}
@@ -0,0 +1,65 @@
+use core::fmt::Debug;
+
+use embedded_svc::{
+    http::{
+        server::{Connection, HandlerResult, Request},
+        Method,
+    },
+    ws::{
+        callback_server::{SenderFactory, SessionProvider},
+        Receiver, Sender,
+    },
+};
+
+/// An HTTP server that handlers can be registered on, mirroring
+/// `EspHttpServer::fn_handler` and `EspHttpServer::ws_handler`.
+pub trait Server {
+    type Connection<'a>: Connection;
+    /// A WebSocket session as handed to a WebSocket handler. The handler is
+    /// called once when a client connects (`is_new`), then for every frame it
+    /// sends and once more after it disconnected (`is_closed`). Control frames
+    /// are answered by the server.
+    type WsConnection: Sender
+        + Receiver
+        + SessionProvider<Session = <Self::WsSender as SessionProvider>::Session>
+        + SenderFactory<Sender = Self::WsSender>;
+    /// Sends to a WebSocket session from outside its handler.
+    type WsSender: Sender + SessionProvider + Clone + Send + 'static;
+    type SseSender: StreamSender;
+    type Error: Debug;
+
+    fn fn_handler<F>(&mut self, uri: &str, method: Method, f: F) -> Result<&mut Self, Self::Error>
+    where
+        F: for<'a> Fn(Request<&mut Self::Connection<'a>>) -> HandlerResult + Send + 'static;
+
+    /// Accepts WebSocket connections on `uri`. Handlers are matched in the
+    /// order they were registered, so this has to come before a wildcard
+    /// handler for the same URI, e.g. a mounted router.
+    fn ws_handler<F, E>(&mut self, uri: &str, f: F) -> Result<&mut Self, Self::Error>
+    where
+        F: Fn(&mut Self::WsConnection) -> Result<(), E> + Send + Sync + 'static,
+        E: Debug;
+
+    /// Answers GET requests to `uri` with a `text/event-stream` response that
+    /// stays open after `f` returned, until the client goes away. `f` gets the
+    /// `Last-Event-ID` header and a sender for later events, and returns what
+    /// to send right away, or `None` to turn the client away with a `503`; it
+    /// must not use the sender itself. Like [`Server::ws_handler`], this has to
+    /// come before a wildcard handler.
+    fn sse_handler<F>(&mut self, uri: &str, f: F) -> Result<&mut Self, Self::Error>
+    where
+        F: Fn(Option<&str>, Self::SseSender) -> Option<String> + Send + Sync + 'static;
+}
+
+/// Writes to a response that outlived its handler, see
+/// [`Server::sse_handler`].
+pub trait StreamSender: Clone + Send + 'static {
+    type Error: Debug;
+
+    /// Must not wait on the server, as handlers send too, e.g. OTA progress.
+    fn send(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Whether the client went away. A client that vanished without closing
+    /// the connection may only be noticed when sending fails.
+    fn is_closed(&self) -> bool;
+}
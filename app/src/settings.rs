@@ -0,0 +1,144 @@
+//! Page to change the runtime configuration from the browser.
+
+use std::time::Duration;
+
+use askama::Template;
+use embedded_svc::http::server::{Connection, HandlerResult, Request};
+
+use crate::{
+    config::{self, SharedConfig, Storage},
+    form, pages,
+    router::{reject, Router},
+    server::Server,
+};
+
+/// The values shown in the form. Kept as entered, so a rejected form can be
+/// shown again without losing the user's input.
+#[derive(Clone, Debug, Default)]
+pub struct SettingsForm {
+    pub wifi_ap: bool,
+    pub wifi_ssid: String,
+    pub hostname: String,
+    pub sensor_interval: String,
+}
+
+#[derive(Template)]
+#[template(path = "settings.html")]
+pub struct SettingsPage<'a> {
+    pub form: SettingsForm,
+    pub feedback: Option<Feedback<'a>>,
+}
+
+pub enum Feedback<'a> {
+    Saved,
+    Error(&'a str),
+}
+
+pub fn register<Srv: Server + 'static, S: Storage>(
+    router: &mut Router<Srv>,
+    config: SharedConfig<S>,
+) {
+    {
+        let config = config.clone();
+        router.get("/settings", move |request, _| show(request, &config));
+    }
+    router.post("/settings", move |request, _| save(request, &config));
+}
+
+pub fn show<C: Connection, S: Storage>(
+    request: Request<C>,
+    config: &SharedConfig<S>,
+) -> HandlerResult {
+    let page = SettingsPage {
+        form: current(config)?,
+        feedback: None,
+    };
+    pages::respond(request, 200, &page)
+}
+
+pub fn save<C: Connection, S: Storage>(
+    mut request: Request<C>,
+    config: &SharedConfig<S>,
+) -> HandlerResult {
+    let body = match form::read_body(&mut request, 512) {
+        Ok(body) => body,
+        Err(err) => return reject(request, err.status(), &err.to_string()),
+    };
+    let fields = form::parse(&body);
+    let form = SettingsForm {
+        wifi_ap: form::field(&fields, "wifi_mode") == Some("ap"),
+        wifi_ssid: form::field(&fields, "wifi_ssid").unwrap_or_default().into(),
+        hostname: form::field(&fields, "hostname").unwrap_or_default().into(),
+        sensor_interval: form::field(&fields, "sensor_interval")
+            .unwrap_or_default()
+            .trim()
+            .into(),
+    };
+    let psk = form::field(&fields, "wifi_psk").unwrap_or_default();
+    let open = form::field(&fields, "wifi_open").is_some();
+    // An empty password field keeps the current one, which has to suit the
+    // mode as well.
+    let wifi_psk = if open || !psk.is_empty() {
+        psk.to_owned()
+    } else {
+        config.lock().unwrap().wifi_psk()?
+    };
+
+    let sensor_interval = match validate(&form, &wifi_psk, open) {
+        Ok(sensor_interval) => sensor_interval,
+        Err(error) => {
+            let page = SettingsPage {
+                form,
+                feedback: Some(Feedback::Error(error)),
+            };
+            return pages::respond(request, 400, &page);
+        }
+    };
+
+    {
+        let mut config = config.lock().unwrap();
+        config.set_wifi_ap(form.wifi_ap)?;
+        config.set_wifi_ssid(&form.wifi_ssid)?;
+        if open || !psk.is_empty() {
+            config.set_wifi_psk(psk)?;
+        }
+        config.set_hostname(&form.hostname)?;
+        config.set_sensor_interval(sensor_interval)?;
+    }
+
+    let page = SettingsPage {
+        form: current(config)?,
+        feedback: Some(Feedback::Saved),
+    };
+    pages::respond(request, 200, &page)
+}
+
+fn current<S: Storage>(config: &SharedConfig<S>) -> anyhow::Result<SettingsForm> {
+    let config = config.lock().unwrap();
+
+    Ok(SettingsForm {
+        wifi_ap: config.wifi_ap()?,
+        wifi_ssid: config.wifi_ssid()?,
+        hostname: config.hostname()?,
+        sensor_interval: config.sensor_interval()?.as_secs().to_string(),
+    })
+}
+
+/// Checks the submitted values, with `psk` the password they end up with,
+/// returning the parsed sensor interval.
+fn validate(form: &SettingsForm, psk: &str, open: bool) -> Result<Duration, &'static str> {
+    config::validate_ssid(&form.wifi_ssid)?;
+    if open && !psk.is_empty() {
+        return Err("An open network can't have a password.");
+    }
+    if !psk.is_empty() {
+        config::validate_psk(psk, form.wifi_ap)?;
+    }
+    config::validate_hostname(&form.hostname)?;
+
+    let secs = form
+        .sensor_interval
+        .parse()
+        .map_err(|_| "The sensor interval must be a whole number of seconds.")?;
+    config::validate_sensor_interval(secs)
+}
@@ -0,0 +1,186 @@
+//! What the device knows about itself, on `/api/v1/system` and as the status
+//! page on `/`.
+
+use std::{net::Ipv4Addr, sync::Arc};
+
+use askama::Template;
+use embedded_svc::http::server::{Connection, HandlerResult, Request};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    api::{self, Device, WifiStatus},
+    config::{SharedConfig, Storage},
+    json, pages,
+    router::Router,
+    server::Server,
+};
+
+/// The running firmware, with the commit and time of the build captured by
+/// `build.rs`.
+pub const FIRMWARE: Firmware = Firmware {
+    name: env!("CARGO_PKG_NAME"),
+    version: env!("CARGO_PKG_VERSION"),
+    git_hash: env!("GIT_HASH"),
+    build_time: env!("BUILD_TIME"),
+};
+
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct Firmware {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub git_hash: &'static str,
+    /// RFC 3339, in UTC.
+    pub build_time: &'static str,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Chip {
+    pub model: &'static str,
+    pub revision: u16,
+    pub cores: u8,
+}
+
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct Heap {
+    pub free_bytes: usize,
+    /// The least there was free since the reset.
+    pub min_free_bytes: usize,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Macs {
+    pub station: Option<String>,
+    pub access_point: Option<String>,
+}
+
+/// Addresses of the network interface the device is reachable on.
+#[derive(Clone, Debug, Serialize)]
+pub struct Netif {
+    pub ip: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Option<Ipv4Addr>,
+    pub dns: Option<Ipv4Addr>,
+}
+
+/// What the platform knows about itself, see [`Device::platform`].
+#[derive(Clone, Debug, Serialize)]
+pub struct Platform {
+    pub reset_reason: &'static str,
+    pub heap: Option<Heap>,
+    pub chip: Chip,
+    pub mac: Macs,
+    pub netif: Option<Netif>,
+}
+
+/// Served on `/api/v1/system`.
+#[derive(Clone, Debug, Serialize)]
+pub struct SystemInfo {
+    pub firmware: Firmware,
+    pub uptime_secs: u64,
+    #[serde(flatten)]
+    pub platform: Platform,
+    /// `None` while the Wi-Fi isn't configured.
+    pub wifi: Option<WifiStatus>,
+    /// The settings in effect, see [`active_config`].
+    pub config: Value,
+}
+
+#[derive(Template)]
+#[template(path = "status.html")]
+pub struct StatusPage<'a> {
+    pub info: &'a SystemInfo,
+    pub uptime: String,
+    pub config: Vec<(&'a str, String)>,
+}
+
+/// Formats a MAC address as six hex bytes separated by colons.
+pub fn format_mac(mac: [u8; 6]) -> String {
+    let bytes: Vec<_> = mac.iter().map(|byte| format!("{byte:02x}")).collect();
+    bytes.join(":")
+}
+
+/// `build_config`, the settings from `cfg.toml` as a JSON object, with those
+/// changed at runtime replaced by their current values. Secrets must already
+/// be left out of it, and are replaced by whether they are set, like
+/// `wifi_psk_set`.
+pub fn active_config<S: Storage>(
+    build_config: &Value,
+    config: &SharedConfig<S>,
+) -> anyhow::Result<Value> {
+    let mut active = build_config.clone();
+    let Value::Object(runtime) = serde_json::to_value(api::config_body(config)?)? else {
+        anyhow::bail!("the configuration is not an object");
+    };
+    if let Value::Object(active) = &mut active {
+        // The schema version of the stored keys, not a setting.
+        active.extend(runtime.into_iter().filter(|(key, _)| key != "version"));
+    }
+    Ok(active)
+}
+
+pub fn system_info<S: Storage>(
+    device: &impl Device,
+    config: &SharedConfig<S>,
+    build_config: &Value,
+) -> anyhow::Result<SystemInfo> {
+    Ok(SystemInfo {
+        firmware: FIRMWARE,
+        uptime_secs: device.uptime().as_secs(),
+        platform: device.platform()?,
+        wifi: device.wifi_status().ok(),
+        config: active_config(build_config, config)?,
+    })
+}
+
+/// `build_config` is what `cfg.toml` set, see [`active_config`].
+pub fn register<Srv: Server + 'static, D: Device, S: Storage>(
+    router: &mut Router<Srv>,
+    device: Arc<D>,
+    config: SharedConfig<S>,
+    build_config: Value,
+) {
+    let build_config = Arc::new(build_config);
+    {
+        let (device, config, build_config) = (device.clone(), config.clone(), build_config.clone());
+        router.get("/", move |request, _| {
+            status(request, &*device, &config, &build_config)
+        });
+    }
+    router.get("/api/v1/system", move |request, _| {
+        let info = system_info(&*device, &config, &build_config)?;
+        json::respond(request, 200, &info)
+    });
+}
+
+pub fn status<C: Connection, S: Storage>(
+    request: Request<C>,
+    device: &impl Device,
+    config: &SharedConfig<S>,
+    build_config: &Value,
+) -> HandlerResult {
+    let info = system_info(device, config, build_config)?;
+    let config = match &info.config {
+        Value::Object(config) => config
+            .iter()
+            .map(|(key, value)| match value {
+                Value::String(value) => (key.as_str(), value.clone()),
+                value => (key.as_str(), value.to_string()),
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    let secs = info.uptime_secs;
+    let page = StatusPage {
+        info: &info,
+        uptime: format!(
+            "{}d {:02}:{:02}:{:02}",
+            secs / 86400,
+            secs / 3600 % 24,
+            secs / 60 % 60,
+            secs % 60
+        ),
+        config,
+    };
+    pages::respond(request, 200, &page)
+}
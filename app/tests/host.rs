@@ -0,0 +1,1376 @@
+use std::{
+    io::{Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpStream, UdpSocket},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use askama::Template;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use embedded_svc::{http::Method, io::Write as _};
+use http_server::alerts::{self, Alerts, Notification, Rule, Transition};
+use http_server::auth::{self, Auth, Lockout, Strict};
+use http_server::captive::CaptiveDns;
+use http_server::config::{self, ConfigStore, Defaults};
+use http_server::events::{self, Event, EventStream};
+use http_server::history::{self, History, Point, Tier};
+use http_server::host::{
+    Configuration, FileBlobs, FileStorage, FileUpdater, HostDevice, HostFetcher, HostServer,
+};
+use http_server::live::{self, LiveReadings};
+use http_server::manifest::{CheckOutcome, Updates};
+use http_server::ota::{self, Health, HealthCheck, ImageState, Ota, OtaStatus, UpdateError};
+use http_server::persist::HistoryStore;
+use http_server::provisioning::{self, Provisioner, ScannedNetwork};
+use http_server::router::{reject, Router};
+use http_server::sampler::Sampler;
+use http_server::sensor::{Channel, Reading, ReplaySensor, SensorError};
+use http_server::settings::{Feedback, SettingsForm, SettingsPage};
+use http_server::system;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// A server with the app's routes plus those added by `register`.
+fn server(register: impl FnOnce(&mut Router<HostServer>)) -> HostServer {
+    let mut server = HostServer::new(&Configuration {
+        http_port: 0,
+        uri_match_wildcard: true,
+    })
+    .unwrap();
+
+    let mut router = Router::new();
+    http_server::assets::register(&mut router);
+    register(&mut router);
+    router.mount(&mut server).unwrap();
+
+    server
+}
+
+fn request(addr: SocketAddr, request: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+#[test]
+fn unknown_routes() {
+    let server = server(|_| ());
+
+    let response = request(server.local_addr(), "GET /nope HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 404 "));
+
+    let response = request(
+        server.local_addr(),
+        "POST /static/style.css HTTP/1.1\r\nContent-Length: 0\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 405 "));
+    assert!(response.contains("Allow: GET\r\n"));
+}
+
+#[derive(Deserialize)]
+struct ReadingsQuery {
+    unit: String,
+    #[serde(default)]
+    limit: Option<u32>,
+}
+
+#[test]
+fn router_params() {
+    let server = server(|router| {
+        router.get("/sensors/{id}/readings", |request, params| {
+            let id: u8 = match params.parse("id") {
+                Ok(id) => id,
+                Err(err) => return reject(request, 400, &err.to_string()),
+            };
+            let query: ReadingsQuery = match params.query() {
+                Ok(query) => query,
+                Err(err) => return reject(request, 400, &err.to_string()),
+            };
+
+            let body = format!("sensor {id} in {} limit {:?}", query.unit, query.limit);
+            request.into_ok_response()?.write_all(body.as_bytes())?;
+            Ok(())
+        });
+    });
+
+    let response = request(
+        server.local_addr(),
+        "GET /sensors/3/readings?unit=%C2%B0C&limit=5 HTTP/1.1\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 200 "));
+    assert!(response.ends_with("sensor 3 in °C limit Some(5)"));
+
+    let response = request(
+        server.local_addr(),
+        "GET /sensors/x/readings?unit=K HTTP/1.1\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 400 "));
+
+    let response = request(
+        server.local_addr(),
+        "GET /sensors/3/readings HTTP/1.1\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 400 "));
+    assert!(response.contains("missing field `unit`"));
+
+    let response = request(server.local_addr(), "GET /sensors/3 HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 404 "));
+}
+
+#[test]
+fn captive_portal() {
+    let ip = Ipv4Addr::new(192, 168, 71, 1);
+
+    let server = server(|router| http_server::captive::register(router, ip));
+    let response = request(server.local_addr(), "GET /generate_204 HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 302 "));
+    assert!(response.contains("Location: http://192.168.71.1/\r\n"));
+
+    let dns = CaptiveDns::start_on((Ipv4Addr::LOCALHOST, 0).into(), ip).unwrap();
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    socket.connect(dns.local_addr()).unwrap();
+
+    // A query for example.com with id 0x1234.
+    let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    query.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+    socket.send(&query).unwrap();
+
+    let mut response = [0; 512];
+    let len = socket.recv(&mut response).unwrap();
+    let response = &response[..len];
+    assert_eq!(response[..2], [0x12, 0x34]);
+    assert_eq!(response[6..8], [0, 1]);
+    assert_eq!(response[len - 4..], ip.octets());
+}
+
+const DEFAULTS: Defaults = Defaults {
+    wifi_ssid: "default",
+    wifi_psk: "",
+    wifi_ap: false,
+    hostname: "esp-rs",
+    sensor_interval_secs: 10,
+};
+
+/// A config file for the test called `name` that doesn't exist yet.
+fn config_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("http-server-{name}-{}.txt", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+#[test]
+fn runtime_config() {
+    let path = config_path("runtime-config");
+    let defaults = DEFAULTS;
+
+    let mut config =
+        ConfigStore::open(FileStorage::open(&path).unwrap(), defaults.clone()).unwrap();
+    assert_eq!(config.version().unwrap(), Some(config::VERSION));
+    assert_eq!(config.wifi_ssid().unwrap(), "default");
+    config.set_wifi_ssid("home").unwrap();
+    config.set_wifi_psk("pass=word\nwith \\ escapes").unwrap();
+    config.set_wifi_ap(true).unwrap();
+
+    let mut config =
+        ConfigStore::open(FileStorage::open(&path).unwrap(), defaults.clone()).unwrap();
+    assert_eq!(config.wifi_ssid().unwrap(), "home");
+    assert_eq!(config.wifi_psk().unwrap(), "pass=word\nwith \\ escapes");
+    assert!(config.wifi_ap().unwrap());
+
+    config.reset().unwrap();
+    assert_eq!(config.wifi_ssid().unwrap(), "default");
+    assert!(!config.wifi_ap().unwrap());
+
+    // Configuration written by newer firmware is discarded.
+    std::fs::write(&path, "version=99\nwifi_ssid=future\n").unwrap();
+    let config = ConfigStore::open(FileStorage::open(&path).unwrap(), defaults).unwrap();
+    assert_eq!(config.version().unwrap(), Some(config::VERSION));
+    assert_eq!(config.wifi_ssid().unwrap(), "default");
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn settings() {
+    let path = config_path("settings");
+    let config = ConfigStore::open(FileStorage::open(&path).unwrap(), DEFAULTS).unwrap();
+    let config = Arc::new(Mutex::new(config));
+    let server = server(|router| http_server::settings::register(router, config.clone()));
+
+    let response = request(server.local_addr(), "GET /settings HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 "));
+    assert!(response.contains(r#"name="hostname" value="esp-rs""#));
+
+    let post = |body: &str| {
+        request(
+            server.local_addr(),
+            &format!(
+                "POST /settings HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            ),
+        )
+    };
+
+    let response =
+        post("wifi_mode=station&wifi_ssid=home&wifi_psk=short&hostname=esp&sensor_interval=5");
+    assert!(response.starts_with("HTTP/1.1 400 "));
+    assert!(response.contains("between 8 and 64 bytes"));
+    // The rejected values are kept.
+    assert!(response.contains(r#"name="wifi_ssid" value="home""#));
+    assert_eq!(config.lock().unwrap().wifi_ssid().unwrap(), "default");
+
+    let response = post("wifi_mode=ap&wifi_ssid=My+%22Net%22&wifi_psk=password&hostname=sensor-1&sensor_interval=30");
+    assert!(response.starts_with("HTTP/1.1 200 "));
+    assert!(response.contains("Saved."));
+    assert!(response.contains(r#"name="wifi_ssid" value="My &quot;Net&quot;""#));
+
+    {
+        let config = config.lock().unwrap();
+        assert!(config.wifi_ap().unwrap());
+        assert_eq!(config.wifi_ssid().unwrap(), "My \"Net\"");
+        assert_eq!(config.wifi_psk().unwrap(), "password");
+        assert_eq!(config.hostname().unwrap(), "sensor-1");
+        assert_eq!(config.sensor_interval().unwrap(), Duration::from_secs(30));
+    }
+
+    // A blank password keeps the stored one, which has to suit the new mode:
+    // a hex key only works for the station.
+    let key = "0123456789abcdef".repeat(4);
+    let response = post(&format!(
+        "wifi_mode=station&wifi_ssid=home&wifi_psk={key}&hostname=esp&sensor_interval=5"
+    ));
+    assert!(response.starts_with("HTTP/1.1 200 "));
+    let response = post("wifi_mode=ap&wifi_ssid=home&wifi_psk=&hostname=esp&sensor_interval=5");
+    assert!(response.starts_with("HTTP/1.1 400 "));
+    assert!(response.contains("between 8 and 63 bytes"));
+    assert!(!config.lock().unwrap().wifi_ap().unwrap());
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// Keeps what it is asked to save instead of touching the Wi-Fi.
+#[derive(Default)]
+struct TestProvisioner {
+    saved: Mutex<Option<(String, String)>>,
+    restarted: AtomicBool,
+}
+
+impl Provisioner for TestProvisioner {
+    fn scan(&self) -> anyhow::Result<Vec<ScannedNetwork>> {
+        Ok(Vec::new())
+    }
+
+    fn reason(&self) -> Option<String> {
+        None
+    }
+
+    fn save(&self, ssid: &str, password: &str) -> anyhow::Result<()> {
+        *self.saved.lock().unwrap() = Some((ssid.into(), password.into()));
+        Ok(())
+    }
+
+    fn restart(&self) {
+        self.restarted.store(true, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn provisioning() {
+    let provisioner = Arc::new(TestProvisioner::default());
+    let server = server(|router| provisioning::register(router, provisioner.clone()));
+    let post = |body: &str| {
+        request(
+            server.local_addr(),
+            &format!(
+                "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            ),
+        )
+    };
+
+    // Too short for WPA2, the device could never join.
+    let response = post("ssid=home&password=1234567");
+    assert!(response.starts_with("HTTP/1.1 400 "));
+    assert!(response.contains("between 8 and 64 bytes"));
+    assert!(provisioner.saved.lock().unwrap().is_none());
+    assert!(!provisioner.restarted.load(Ordering::Relaxed));
+
+    let response = post("ssid=home&password=12345678");
+    assert!(response.starts_with("HTTP/1.1 200 "));
+    assert_eq!(
+        *provisioner.saved.lock().unwrap(),
+        Some(("home".into(), "12345678".into()))
+    );
+    assert!(provisioner.restarted.load(Ordering::Relaxed));
+}
+
+#[test]
+fn json_api() {
+    let path = config_path("json-api");
+    let config = ConfigStore::open(FileStorage::open(&path).unwrap(), DEFAULTS).unwrap();
+    let config = Arc::new(Mutex::new(config));
+    let device = Arc::new(HostDevice::new());
+    let server =
+        server(|router| http_server::api::register(router, device.clone(), config.clone()));
+    let get = |uri: &str, accept: &str| {
+        request(
+            server.local_addr(),
+            &format!("GET {uri} HTTP/1.1\r\nAccept: {accept}\r\n\r\n"),
+        )
+    };
+
+    let response = get("/api/v1/device", "*/*");
+    assert!(response.starts_with("HTTP/1.1 200 "));
+    assert!(response.contains("Content-Type: application/json\r\n"));
+    assert!(response.contains(r#""hostname":"esp-rs""#));
+
+    let response = get("/api/v1/sensor", "*/*");
+    assert!(response.starts_with("HTTP/1.1 503 "));
+    assert!(response.contains(r#"{"error":{"status":503,"#));
+
+    device.set_reading(Reading {
+        temperature_celsius: 21.5,
+        humidity_percent: 40.0,
+    });
+    let response = get("/temperature", "text/html");
+    assert!(response.contains("Chip temperature: 21.50°C"));
+    let response = get("/temperature", "application/json, text/plain");
+    assert!(response.contains("Content-Type: application/json\r\n"));
+    assert!(response.ends_with(r#"{"temperature_celsius":21.5,"humidity_percent":40.0}"#));
+
+    let response = get("/api/v1/nope", "*/*");
+    assert!(response.starts_with("HTTP/1.1 404 "));
+    assert!(response.ends_with(r#"{"error":{"status":404,"message":"Not Found"}}"#));
+
+    let patch = |body: &str| {
+        request(
+            server.local_addr(),
+            &format!(
+                "PATCH /api/v1/config HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            ),
+        )
+    };
+    let response = patch(r#"{"hostname":"-bad"}"#);
+    assert!(response.starts_with("HTTP/1.1 400 "));
+    let response = patch(&" ".repeat(1000));
+    assert!(response.starts_with("HTTP/1.1 413 "));
+    let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+    stream
+        .write_all(b"PATCH /api/v1/config HTTP/1.1\r\nContent-Length: 2\r\n\r\n\xff\xfe")
+        .unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 400 "));
+    let response = patch(r#"{"hostname":"good","wifi_psk":"password"}"#);
+    assert!(response.starts_with("HTTP/1.1 200 "));
+    assert!(response.contains(r#""hostname":"good""#));
+    assert!(response.contains(r#""wifi_psk_set":true"#));
+    assert!(!response.contains("password"));
+
+    // A hex key only works for the station, so the stored one blocks the
+    // switch to an access point.
+    let key = "0123456789abcdef".repeat(4);
+    let response = patch(&format!(r#"{{"wifi_psk":"{key}"}}"#));
+    assert!(response.starts_with("HTTP/1.1 200 "));
+    let response = patch(r#"{"wifi_ap":true}"#);
+    assert!(response.starts_with("HTTP/1.1 400 "));
+    assert!(!config.lock().unwrap().wifi_ap().unwrap());
+    let response = patch(r#"{"wifi_ap":true,"wifi_psk":"password"}"#);
+    assert!(response.starts_with("HTTP/1.1 200 "));
+
+    // Other networks are tried after `wifi_ssid`, by priority.
+    let put = |body: &str| {
+        request(
+            server.local_addr(),
+            &format!(
+                "PUT /api/v1/wifi/networks HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            ),
+        )
+    };
+    let response = put(r#"[{"ssid":"office","psk":"short"}]"#);
+    assert!(response.starts_with("HTTP/1.1 400 "));
+    let response = put(r#"[{"ssid":"office","psk":"password","priority":2},{"ssid":"cafe"}]"#);
+    assert!(response.starts_with("HTTP/1.1 200 "));
+    assert!(response.ends_with(
+        r#"[{"ssid":"office","psk_set":true,"priority":2},{"ssid":"cafe","psk_set":false,"priority":0}]"#
+    ));
+    let response = get("/api/v1/wifi/networks", "*/*");
+    assert!(!response.contains("password"));
+    let networks = config.lock().unwrap().known_networks().unwrap();
+    let ssids: Vec<_> = networks
+        .iter()
+        .map(|network| network.ssid.as_str())
+        .collect();
+    assert_eq!(ssids, ["default", "office", "cafe"]);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn auth() {
+    let path = config_path("auth");
+    let config = ConfigStore::open(FileStorage::open(&path).unwrap(), DEFAULTS).unwrap();
+    let config = Arc::new(Mutex::new(config));
+    let lockout = Lockout {
+        max_failures: 3,
+        duration: Duration::from_millis(500),
+    };
+    let auth = Arc::new(Auth::new(config.clone(), lockout).unwrap());
+    let setup_code = auth.setup_code().unwrap().unwrap().to_owned();
+    let server = server(|router| {
+        http_server::settings::register(router, config.clone());
+        auth::register(router, auth.clone());
+        router
+            .protect(&[Method::Get], "/settings", auth.clone())
+            .protect(
+                &[Method::Put, Method::Post],
+                "/api/v1/auth/{*rest}",
+                auth.clone(),
+            );
+    });
+    let send = |method: &str, uri: &str, headers: &str, body: &str| {
+        request(
+            server.local_addr(),
+            &format!(
+                "{method} {uri} HTTP/1.1\r\nContent-Length: {}\r\n{headers}\r\n{body}",
+                body.len()
+            ),
+        )
+    };
+    let basic =
+        |credentials: &str| format!("Authorization: Basic {}\r\n", BASE64.encode(credentials));
+
+    // Open until the first user is set up, which takes the setup code.
+    let response = send("GET", "/settings", "", "");
+    assert!(response.starts_with("HTTP/1.1 200 "));
+    let setup = format!("Authorization: Bearer {setup_code}\r\n");
+    let response = send(
+        "PUT",
+        "/api/v1/auth/users/admin",
+        &setup,
+        r#"{"password":"short"}"#,
+    );
+    assert!(response.starts_with("HTTP/1.1 400 "));
+    for headers in ["", "Authorization: Bearer nope\r\n"] {
+        let response = send(
+            "PUT",
+            "/api/v1/auth/users/admin",
+            headers,
+            r#"{"password":"correct horse"}"#,
+        );
+        assert!(response.starts_with("HTTP/1.1 401 "));
+    }
+    let response = send("POST", "/api/v1/auth/tokens", "", r#"{"name":"ci"}"#);
+    assert!(response.starts_with("HTTP/1.1 401 "));
+    let response = send(
+        "PUT",
+        "/api/v1/auth/users/admin",
+        &setup,
+        r#"{"password":"correct horse"}"#,
+    );
+    assert!(response.starts_with("HTTP/1.1 200 "));
+    assert!(response.ends_with(r#"{"users":["admin"],"tokens":[]}"#));
+    assert_eq!(auth.setup_code().unwrap(), None);
+
+    let response = send("GET", "/settings", "", "");
+    assert!(response.starts_with("HTTP/1.1 401 "));
+    assert!(response.contains("WWW-Authenticate: Basic realm=\"esp-rs\", charset=\"UTF-8\"\r\n"));
+    assert!(response.contains("WWW-Authenticate: Bearer realm=\"esp-rs\"\r\n"));
+    let admin = basic("admin:correct horse");
+    let response = send("GET", "/settings", &admin, "");
+    assert!(response.starts_with("HTTP/1.1 200 "));
+
+    // The token is only ever shown once, only its hash is stored.
+    let response = send("POST", "/api/v1/auth/tokens", &admin, r#"{"name":"ci"}"#);
+    assert!(response.starts_with("HTTP/1.1 200 "));
+    #[derive(Deserialize)]
+    struct Token {
+        token: String,
+    }
+    let body = response.split("\r\n\r\n").nth(1).unwrap();
+    let token = serde_json::from_str::<Token>(body).unwrap().token;
+    let credentials = config.lock().unwrap().credentials().unwrap();
+    assert_eq!(credentials.tokens.len(), 1);
+    assert_ne!(credentials.tokens[0].hash, token);
+    let bearer = format!("Authorization: Bearer {token}\r\n");
+    let response = send("GET", "/settings", &bearer, "");
+    assert!(response.starts_with("HTTP/1.1 200 "));
+
+    // Wrong tokens only count against the token they start like.
+    for _ in 0..3 {
+        let response = send("GET", "/settings", "Authorization: Bearer nope\r\n", "");
+        assert!(response.starts_with("HTTP/1.1 401 "));
+    }
+    let response = send("GET", "/settings", &bearer, "");
+    assert!(response.starts_with("HTTP/1.1 200 "));
+
+    // Locked out after too many wrong passwords, even with the right one, and
+    // failing as other users in between doesn't help.
+    for _ in 0..2 {
+        let response = send("GET", "/settings", &basic("admin:wrong"), "");
+        assert!(response.starts_with("HTTP/1.1 401 "));
+    }
+    for i in 0..20 {
+        let response = send("GET", "/settings", &basic(&format!("user{i}:wrong")), "");
+        assert!(response.starts_with("HTTP/1.1 401 "));
+    }
+    let response = send("GET", "/settings", &basic("admin:wrong"), "");
+    assert!(response.starts_with("HTTP/1.1 401 "));
+    let response = send("GET", "/settings", &admin, "");
+    assert!(response.starts_with("HTTP/1.1 429 "));
+    assert!(response.contains("Retry-After: 1\r\n"));
+    let response = send("GET", "/settings", &bearer, "");
+    assert!(response.starts_with("HTTP/1.1 200 "));
+    std::thread::sleep(lockout.duration);
+    let response = send("GET", "/settings", &admin, "");
+    assert!(response.starts_with("HTTP/1.1 200 "));
+
+    // Without credentials left, the setup code is needed again.
+    assert!(auth.remove_token("ci").unwrap());
+    assert!(auth.remove_user("admin").unwrap());
+    assert_eq!(auth.setup_code().unwrap(), Some(setup_code.as_str()));
+    let response = send(
+        "PUT",
+        "/api/v1/auth/users/admin",
+        "",
+        r#"{"password":"correct horse"}"#,
+    );
+    assert!(response.starts_with("HTTP/1.1 401 "));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn system() {
+    let path = config_path("system");
+    let config = ConfigStore::open(FileStorage::open(&path).unwrap(), DEFAULTS).unwrap();
+    let config = Arc::new(Mutex::new(config));
+    let build_config = serde_json::json!({
+        "hostname": "esp-rs",
+        "sensor_sda": 10,
+    });
+    let server = server(|router| {
+        let device = Arc::new(HostDevice::new());
+        system::register(router, device, config.clone(), build_config)
+    });
+    config.lock().unwrap().set_hostname("changed").unwrap();
+
+    let response = request(
+        server.local_addr(),
+        "GET /?foo=bar HTTP/1.1\r\nHost: localhost\r\n\r\n",
+    );
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains(&format!(
+        "<tr><th>Commit</th><td>{}</td></tr>",
+        system::FIRMWARE.git_hash
+    )));
+    assert!(response.contains("<tr><th>hostname</th><td>changed</td></tr>"));
+
+    let response = request(server.local_addr(), "GET /api/v1/system HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 "));
+    let body = &response[response.find("\r\n\r\n").unwrap() + 4..];
+    let info: serde_json::Value = serde_json::from_str(body).unwrap();
+    assert_eq!(info["firmware"]["version"], env!("CARGO_PKG_VERSION"));
+    assert!(info["firmware"]["build_time"]
+        .as_str()
+        .unwrap()
+        .ends_with('Z'));
+    assert_eq!(info["reset_reason"], "power_on");
+    assert_eq!(info["netif"]["ip"], "127.0.0.1");
+    assert_eq!(info["wifi"]["ssid"], "host");
+    assert_eq!(
+        info["config"],
+        serde_json::json!({
+            "hostname": "changed",
+            "sensor_sda": 10,
+            "sensor_interval_secs": 10,
+            "wifi_ap": false,
+            "wifi_psk_set": false,
+            "wifi_ssid": "default",
+        })
+    );
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// Compares `actual` with `tests/snapshots/{name}`, or overwrites the snapshot
+/// if `UPDATE_SNAPSHOTS` is set.
+fn assert_snapshot(name: &str, actual: &str) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/snapshots")
+        .join(name);
+    if std::env::var_os("UPDATE_SNAPSHOTS").is_some() {
+        std::fs::write(&path, actual).unwrap();
+    }
+
+    let expected = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(actual, expected, "snapshot {name} differs");
+}
+
+#[test]
+fn templates() {
+    let page = SettingsPage {
+        form: SettingsForm {
+            wifi_ap: false,
+            wifi_ssid: r#"<script>alert("hi")</script>"#.into(),
+            hostname: "esp-rs".into(),
+            sensor_interval: "10".into(),
+        },
+        feedback: Some(Feedback::Error("Bad & wrong.")),
+    };
+    let html = page.render().unwrap();
+
+    assert!(!html.contains("<script>"));
+    assert!(html.contains("<title>Settings · esp-rs web server</title>"));
+    assert_snapshot("settings.html", &html);
+}
+
+#[test]
+fn static_assets() {
+    let server = server(|_| ());
+    let get = |uri: &str, headers: &str| {
+        request(
+            server.local_addr(),
+            &format!("GET {uri} HTTP/1.1\r\n{headers}\r\n"),
+        )
+    };
+    let css = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/static/style.css"));
+
+    let response = get("/static/style.css", "");
+    assert!(response.starts_with("HTTP/1.1 200 "));
+    assert!(response.contains("Content-Type: text/css; charset=utf-8\r\n"));
+    assert!(response.contains("Cache-Control: no-cache\r\n"));
+    assert!(!response.contains("Content-Encoding"));
+    assert!(response.ends_with(&css.unwrap()));
+
+    let response = get("/static/style.css", "Accept-Encoding: gzip, deflate\r\n");
+    assert!(response.contains("Content-Encoding: gzip\r\n"));
+    let etag = response
+        .lines()
+        .find_map(|line| line.strip_prefix("ETag: "))
+        .unwrap()
+        .to_owned();
+    assert!(etag.ends_with("-gzip\""));
+
+    let response = get(
+        "/static/style.css",
+        &format!("Accept-Encoding: gzip\r\nIf-None-Match: W/\"stale\", {etag}\r\n"),
+    );
+    assert!(response.starts_with("HTTP/1.1 304 "));
+    assert!(response.ends_with("\r\n\r\n"));
+
+    let response = get(&http_server::assets::url("style.css"), "");
+    assert!(response.contains("Cache-Control: public, max-age=31536000, immutable\r\n"));
+
+    let response = get("/static/nope.css", "");
+    assert!(response.starts_with("HTTP/1.1 404 "));
+}
+
+/// Sends a masked WebSocket frame, as a client has to.
+fn send_frame(stream: &mut TcpStream, opcode: u8, payload: &[u8]) {
+    let mask = [1, 2, 3, 4];
+    let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    stream.write_all(&frame).unwrap();
+}
+
+/// Reads a short frame, returning its first byte and payload.
+fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut head = [0; 2];
+    stream.read_exact(&mut head).unwrap();
+    let mut payload = vec![0; head[1].into()];
+    stream.read_exact(&mut payload).unwrap();
+    (head[0], payload)
+}
+
+fn connect_ws(addr: SocketAddr) -> (TcpStream, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(
+            b"GET /ws HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .unwrap();
+
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    (stream, String::from_utf8(head).unwrap())
+}
+
+fn wait_for(condition: impl Fn() -> bool) {
+    // Long enough for a round of health checks, which are a second apart.
+    for _ in 0..300 {
+        if condition() {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    panic!("timed out");
+}
+
+#[test]
+fn websocket() {
+    let mut server = HostServer::new(&Configuration {
+        http_port: 0,
+        uri_match_wildcard: true,
+    })
+    .unwrap();
+    let live = Arc::new(LiveReadings::new(1));
+    live::register(&mut server, live.clone()).unwrap();
+
+    let (mut client, head) = connect_ws(server.local_addr());
+    assert!(head.starts_with("HTTP/1.1 101 "));
+    // The example from RFC 6455.
+    assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    wait_for(|| live.clients() == 1);
+
+    live.publish(&Reading {
+        temperature_celsius: 21.5,
+        humidity_percent: 40.0,
+    });
+    let (first, payload) = read_frame(&mut client);
+    assert_eq!(first, 0x81);
+    assert_eq!(
+        payload,
+        br#"{"temperature_celsius":21.5,"humidity_percent":40.0}"#
+    );
+
+    send_frame(&mut client, 0x9, b"hi");
+    assert_eq!(read_frame(&mut client), (0x8a, b"hi".to_vec()));
+
+    // Over the limit, a client is turned away with "try again later".
+    let (mut second, _) = connect_ws(server.local_addr());
+    assert_eq!(
+        read_frame(&mut second),
+        (0x88, 1013u16.to_be_bytes().to_vec())
+    );
+
+    send_frame(&mut client, 0x8, &1000u16.to_be_bytes());
+    assert_eq!(read_frame(&mut client).0, 0x88);
+    wait_for(|| live.clients() == 0);
+}
+
+/// Reads from `stream` until what was read ends with `end`.
+fn read_until(stream: &mut TcpStream, end: &str) -> String {
+    let mut read = Vec::new();
+    while !read.ends_with(end.as_bytes()) {
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        read.push(byte[0]);
+    }
+    String::from_utf8(read).unwrap()
+}
+
+#[test]
+fn server_sent_events() {
+    let mut server = HostServer::new(&Configuration {
+        http_port: 0,
+        uri_match_wildcard: true,
+    })
+    .unwrap();
+    let stream = Arc::new(EventStream::new(1, 2));
+    events::register(&mut server, stream.clone()).unwrap();
+    let subscribe = |headers: &str| {
+        let mut client = TcpStream::connect(server.local_addr()).unwrap();
+        write!(client, "GET /api/v1/events HTTP/1.1\r\n{headers}\r\n").unwrap();
+        client
+    };
+
+    stream.publish(&Event::Wifi { connected: true });
+
+    let mut client = subscribe("");
+    let start = read_until(&mut client, "retry: 3000\n\n");
+    assert!(start.starts_with("HTTP/1.1 200 "));
+    assert!(start.contains("Content-Type: text/event-stream\r\n"));
+    wait_for(|| stream.clients() == 1);
+
+    stream.publish(&Event::Wifi { connected: false });
+    assert_eq!(
+        read_until(&mut client, "\n\n"),
+        "id: 2\nevent: wifi\ndata: {\"connected\":false}\n\n"
+    );
+
+    let response = request(server.local_addr(), "GET /api/v1/events HTTP/1.1\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 503 "));
+
+    drop(client);
+    wait_for(|| stream.clients() == 0);
+
+    // Missed while disconnected.
+    stream.publish(&Event::Reading(Reading {
+        temperature_celsius: 21.5,
+        humidity_percent: 40.0,
+    }));
+
+    let mut client = subscribe("Last-Event-ID: 2\r\n");
+    let start = read_until(&mut client, "}\n\n");
+    assert!(!start.contains("id: 2\n"));
+    assert!(start.ends_with(
+        "retry: 3000\n\nid: 3\nevent: reading\ndata: {\"temperature_celsius\":21.5,\"humidity_percent\":40.0}\n\n"
+    ));
+}
+
+#[test]
+fn sampler() {
+    let reading = |temperature_celsius| {
+        Ok(Reading {
+            temperature_celsius,
+            humidity_percent: 50.0,
+        })
+    };
+    let sensor = ReplaySensor::new(vec![reading(1.0), Err(SensorError::Checksum), reading(3.0)]);
+    let (published, readings) = std::sync::mpsc::channel();
+    let sampler = Sampler::start(
+        sensor,
+        || Duration::from_millis(10),
+        move |reading| published.send(*reading).unwrap(),
+    )
+    .unwrap();
+    let snapshot = sampler.snapshot();
+
+    let timeout = Duration::from_secs(1);
+    assert_eq!(
+        readings.recv_timeout(timeout).unwrap().temperature_celsius,
+        1.0
+    );
+    // The failed measurement is skipped rather than ending the sampler.
+    assert_eq!(
+        readings.recv_timeout(timeout).unwrap().temperature_celsius,
+        3.0
+    );
+    // And the replay starts over.
+    assert_eq!(
+        readings.recv_timeout(timeout).unwrap().temperature_celsius,
+        1.0
+    );
+    let latest = snapshot.latest().unwrap();
+    assert!(latest.reading.value(Channel::Temperature) >= 1.0);
+    assert!(latest.age() < timeout);
+
+    drop(sampler);
+    while readings.recv().is_ok() {}
+    let last = snapshot.reading();
+    std::thread::sleep(Duration::from_millis(50));
+    assert_eq!(snapshot.reading(), last);
+}
+
+#[test]
+fn history() {
+    let tiers = [
+        Tier {
+            period_secs: 1,
+            span_secs: 10,
+        },
+        Tier {
+            period_secs: 10,
+            span_secs: 100,
+        },
+    ];
+    let mut history = History::new(&tiers, 1024);
+    for time in 1000..=1030 {
+        let reading = Reading {
+            temperature_celsius: (time - 1000) as f32,
+            humidity_percent: 50.0,
+        };
+        history.record(time, &reading);
+    }
+    let point = |time, temperature_celsius| Point {
+        time,
+        temperature_celsius,
+        humidity_percent: 50.0,
+    };
+
+    // The seconds only go back to 1020, so this has to come from the tens.
+    let series = history.query(0, u32::MAX, None);
+    assert_eq!(series.resolution_secs, 10);
+    assert_eq!(
+        series.points,
+        [
+            point(1000, 4.5),
+            point(1010, 14.5),
+            point(1020, 24.5),
+            point(1030, 30.0)
+        ]
+    );
+    let series = history.query(1025, 1027, None);
+    assert_eq!(series.resolution_secs, 1);
+    assert_eq!(
+        series.points,
+        [point(1025, 25.0), point(1026, 26.0), point(1027, 27.0)]
+    );
+    let series = history.query(0, u32::MAX, Some(20));
+    assert_eq!(series.points, [point(1000, 9.5), point(1020, 27.25)]);
+
+    let small = History::new(&history::DEFAULT_TIERS, 1200);
+    assert!(small.ram_usage() <= 1200);
+
+    // Nothing is recorded before SNTP has set the clock.
+    assert!(!history::clock_is_set(30));
+    assert!(!history::clock_is_set(history::MIN_TIME - 1));
+    assert!(history::clock_is_set(history::MIN_TIME));
+    assert!(history::clock_is_set(history::now()));
+
+    // Longer series are sent in pieces, adding up to the same.
+    {
+        let mut long = History::new(&history::DEFAULT_TIERS, 32 * 1024);
+        for time in 0..500 {
+            let reading = Reading {
+                temperature_celsius: time as f32 / 7.0,
+                humidity_percent: 50.0,
+            };
+            long.record(history::MIN_TIME + time, &reading);
+        }
+        let expected = serde_json::to_string(&long.query(0, u32::MAX, None)).unwrap();
+        let long = Arc::new(Mutex::new(long));
+        let server = server(|router| history::register(router, long));
+        let response = request(server.local_addr(), "GET /api/v1/history HTTP/1.1\r\n\r\n");
+        assert!(response.ends_with(&format!("\r\n\r\n{expected}")));
+    }
+
+    let history = Arc::new(Mutex::new(history));
+    let server = server(|router| history::register(router, history));
+    let get = |uri: &str| request(server.local_addr(), &format!("GET {uri} HTTP/1.1\r\n\r\n"));
+
+    let response = get("/api/v1/history?from=1030");
+    assert!(response.starts_with("HTTP/1.1 200 "));
+    assert!(response.ends_with(
+        r#"{"resolution_secs":1,"points":[{"time":1030,"temperature_celsius":30.0,"humidity_percent":50.0}]}"#
+    ));
+
+    let response = get("/api/v1/history.csv?resolution=20");
+    assert!(response.contains("Content-Type: text/csv; charset=utf-8\r\n"));
+    assert!(response.ends_with(
+        "\r\n\r\ntime,temperature_celsius,humidity_percent\r\n1000,9.5,50\r\n1020,27.25,50\r\n"
+    ));
+
+    let response = get("/api/v1/history?resolution=0");
+    assert!(response.starts_with("HTTP/1.1 400 "));
+    let response = get("/api/v1/history?from=2&to=1");
+    assert!(response.starts_with("HTTP/1.1 400 "));
+}
+
+#[test]
+fn history_persistence() {
+    let dir = std::env::temp_dir().join(format!("http-server-history-{}", std::process::id()));
+    let new_history = || History::new(&history::DEFAULT_TIERS, 32 * 1024);
+    let record = |history: &Arc<Mutex<History>>, time| {
+        let reading = Reading {
+            temperature_celsius: 20.0,
+            humidity_percent: 50.0,
+        };
+        history.lock().unwrap().record(time, &reading);
+    };
+    let points = |history: &History| history.query(0, u32::MAX, Some(60)).points.len();
+
+    let mut history = new_history();
+    let mut store = HistoryStore::open(FileBlobs::open(&dir).unwrap(), &mut history).unwrap();
+    let history = Arc::new(Mutex::new(history));
+    assert!(!store.save(&history).unwrap());
+
+    // The minute in progress isn't saved.
+    for minute in 0..3 {
+        record(&history, minute * 60);
+    }
+    assert!(store.save(&history).unwrap());
+    assert!(!store.save(&history).unwrap());
+    record(&history, 3 * 60);
+    assert!(store.save(&history).unwrap());
+
+    let mut restored = new_history();
+    HistoryStore::open(FileBlobs::open(&dir).unwrap(), &mut restored).unwrap();
+    assert_eq!(points(&restored), 3);
+
+    // A save cut short falls back to the one before, and gets overwritten
+    // next.
+    let blobs = FileBlobs::open(&dir).unwrap();
+    let newest = blobs.path("history_a");
+    let saved = std::fs::read(&newest).unwrap();
+    std::fs::write(&newest, &saved[..saved.len() - 7]).unwrap();
+    let mut restored = new_history();
+    let mut store = HistoryStore::open(blobs, &mut restored).unwrap();
+    assert_eq!(points(&restored), 2);
+
+    let restored = Arc::new(Mutex::new(restored));
+    record(&restored, 4 * 60);
+    record(&restored, 5 * 60);
+    assert!(store.save(&restored).unwrap());
+    let mut reloaded = new_history();
+    HistoryStore::open(FileBlobs::open(&dir).unwrap(), &mut reloaded).unwrap();
+    assert_eq!(points(&reloaded), 3);
+
+    // So does a corrupted one.
+    let mut saved = std::fs::read(&newest).unwrap();
+    saved[20] ^= 1;
+    std::fs::write(&newest, saved).unwrap();
+    let mut reloaded = new_history();
+    HistoryStore::open(FileBlobs::open(&dir).unwrap(), &mut reloaded).unwrap();
+    assert_eq!(points(&reloaded), 2);
+
+    // And one that can't be read at all.
+    std::fs::remove_file(&newest).unwrap();
+    std::fs::create_dir(&newest).unwrap();
+    let mut reloaded = new_history();
+    HistoryStore::open(FileBlobs::open(&dir).unwrap(), &mut reloaded).unwrap();
+    assert_eq!(points(&reloaded), 2);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn alerts() {
+    let rules: Vec<Rule> = serde_json::from_str(
+        r#"[
+            {"name":"hot","channel":"temperature","condition":"above","threshold":30.0,"hysteresis":2.0},
+            {"name":"dry","channel":"humidity","condition":"below","threshold":20.0,"min_duration_secs":60}
+        ]"#,
+    )
+    .unwrap();
+    let mut alerts = Alerts::new(rules);
+    let notified = Arc::new(Mutex::new(Vec::new()));
+    {
+        let notified = notified.clone();
+        alerts.add_sink(move |notification: &Notification| {
+            notified
+                .lock()
+                .unwrap()
+                .push((notification.rule.clone(), notification.transition))
+        });
+    }
+    let mut evaluate = |time, temperature_celsius, humidity_percent| {
+        alerts.evaluate(
+            time,
+            &Reading {
+                temperature_celsius,
+                humidity_percent,
+            },
+        );
+        std::mem::take(&mut *notified.lock().unwrap())
+    };
+    let hot = |transition| vec![("hot".to_owned(), transition)];
+    let dry = |transition| vec![("dry".to_owned(), transition)];
+
+    assert_eq!(evaluate(0, 25.0, 50.0), []);
+    assert_eq!(evaluate(10, 30.5, 50.0), hot(Transition::Activated));
+    // Within the hysteresis, the alert stays active.
+    assert_eq!(evaluate(20, 29.0, 50.0), []);
+    assert_eq!(evaluate(30, 30.5, 50.0), []);
+    assert_eq!(evaluate(40, 28.0, 50.0), hot(Transition::Cleared));
+
+    // Dry spells shorter than the minimum duration don't count.
+    assert_eq!(evaluate(100, 25.0, 15.0), []);
+    assert_eq!(evaluate(130, 25.0, 25.0), []);
+    assert_eq!(evaluate(140, 25.0, 15.0), []);
+    assert_eq!(evaluate(190, 25.0, 15.0), []);
+    assert_eq!(evaluate(200, 25.0, 15.0), dry(Transition::Activated));
+    assert_eq!(evaluate(210, 25.0, 20.0), dry(Transition::Cleared));
+
+    let path = config_path("alerts");
+    let config = ConfigStore::open(FileStorage::open(&path).unwrap(), DEFAULTS).unwrap();
+    let config = Arc::new(Mutex::new(config));
+    let alerts = Arc::new(Mutex::new(Alerts::new(Vec::new())));
+    let server = server(|router| alerts::register(router, alerts.clone(), config.clone()));
+    let put = |body: &str| {
+        request(
+            server.local_addr(),
+            &format!(
+                "PUT /api/v1/alerts HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            ),
+        )
+    };
+
+    let response = put(r#"[{"name":"hot","channel":"temperature","condition":"above"}]"#);
+    assert!(response.starts_with("HTTP/1.1 400 "));
+    let response = put(
+        r#"[{"name":"hot","channel":"temperature","condition":"above","threshold":30.0,"hysteresis":-1.0}]"#,
+    );
+    assert!(response.starts_with("HTTP/1.1 400 "));
+    let response =
+        put(r#"[{"name":"hot","channel":"temperature","condition":"above","threshold":30.0}]"#);
+    assert!(response.starts_with("HTTP/1.1 200 "));
+    assert_eq!(config.lock().unwrap().alert_rules().unwrap().len(), 1);
+
+    alerts.lock().unwrap().evaluate(
+        1000,
+        &Reading {
+            temperature_celsius: 31.0,
+            humidity_percent: 50.0,
+        },
+    );
+    let response = request(server.local_addr(), "GET /api/v1/alerts HTTP/1.1\r\n\r\n");
+    assert!(response.ends_with(concat!(
+        r#"{"rules":[{"name":"hot","channel":"temperature","condition":"above","threshold":30.0,"hysteresis":0.0,"min_duration_secs":0,"state":"active","since":1000}],"#,
+        r#""recent":[{"rule":"hot","transition":"activated","channel":"temperature","value":31.0,"threshold":30.0,"time":1000}]}"#
+    )));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn ota() {
+    let dir = std::env::temp_dir().join(format!("http-server-ota-{}", std::process::id()));
+    let updater = FileUpdater::open(&dir).unwrap();
+    let firmware = updater.firmware_path();
+    let statuses = Arc::new(Mutex::new(Vec::new()));
+    let ota = {
+        let statuses = statuses.clone();
+        Arc::new(Ota::new(updater, move |status: &OtaStatus| {
+            statuses.lock().unwrap().push(status.clone())
+        }))
+    };
+    let path = config_path("ota");
+    let config = ConfigStore::open(FileStorage::open(&path).unwrap(), DEFAULTS).unwrap();
+    let auth = Arc::new(Auth::new(Arc::new(Mutex::new(config)), Lockout::default()).unwrap());
+    let server = server(|router| {
+        ota::register(router, ota.clone());
+        router.protect(
+            &[Method::Post],
+            "/api/v1/ota",
+            Arc::new(Strict(auth.clone())),
+        );
+    });
+    let upload = |headers: &str, image: &[u8]| {
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        let head = format!(
+            "POST /api/v1/ota HTTP/1.1\r\nContent-Length: {}\r\n{headers}\r\n",
+            image.len()
+        );
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(image).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    let mut image = vec![0xe9];
+    image.extend((0..100_000u32).map(|i| i as u8));
+    let sha256 = format!("{:x}", Sha256::digest(&image));
+
+    // Closed until there are credentials, unlike other protected routes.
+    let response = upload(&format!("X-Firmware-SHA256: {sha256}\r\n"), &image);
+    assert!(response.starts_with("HTTP/1.1 401 "));
+    assert!(response.contains("WWW-Authenticate: Bearer realm=\"esp-rs\"\r\n"));
+    let token = auth.create_token("ci").unwrap();
+    let auth = format!("Authorization: Bearer {token}\r\n");
+    let auth = auth.as_str();
+    let response = upload(&format!("X-Firmware-SHA256: {sha256}\r\n"), &image);
+    assert!(response.starts_with("HTTP/1.1 401 "));
+    let response = upload(
+        &format!("Authorization: Bearer {token}0\r\nX-Firmware-SHA256: {sha256}\r\n"),
+        &image,
+    );
+    assert!(response.starts_with("HTTP/1.1 401 "));
+
+    // A corrupted upload is thrown away.
+    let mut corrupted = image.clone();
+    corrupted[5000] ^= 1;
+    let response = upload(
+        &format!("{auth}X-Firmware-SHA256: {sha256}\r\n"),
+        &corrupted,
+    );
+    assert!(response.starts_with("HTTP/1.1 400 "));
+    assert!(response.contains("SHA-256"));
+    assert!(!firmware.exists());
+    let response = upload(&format!("{auth}X-Firmware-SHA256: {sha256}\r\n"), b"MZ");
+    assert!(response.starts_with("HTTP/1.1 400 "));
+    let response = upload(auth, &image);
+    assert!(response.starts_with("HTTP/1.1 400 "));
+
+    statuses.lock().unwrap().clear();
+    let response = upload(&format!("{auth}X-Firmware-SHA256: {sha256}\r\n"), &image);
+    assert!(response.starts_with("HTTP/1.1 200 "));
+    assert!(response.ends_with(r#"{"state":"rebooting"}"#));
+    assert_eq!(std::fs::read(&firmware).unwrap(), image);
+
+    // Nothing else is written while the restart is pending.
+    let response = upload(&format!("{auth}X-Firmware-SHA256: {sha256}\r\n"), &image);
+    assert!(response.starts_with("HTTP/1.1 409 "));
+
+    let statuses = statuses.lock().unwrap();
+    assert_eq!(
+        statuses.first(),
+        Some(&OtaStatus::Receiving {
+            received: 0,
+            size: image.len()
+        })
+    );
+    assert!(statuses.contains(&OtaStatus::Receiving {
+        received: image.len(),
+        size: image.len()
+    }));
+    assert_eq!(statuses.last(), Some(&OtaStatus::Rebooting));
+    assert!(statuses.len() > 10);
+
+    let response = request(server.local_addr(), "GET /api/v1/ota HTTP/1.1\r\n\r\n");
+    assert!(response.ends_with(r#"{"state":"rebooting"}"#));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn ota_rollback() {
+    let dir = std::env::temp_dir().join(format!("http-server-rollback-{}", std::process::id()));
+    let mut image = vec![0xe9];
+    image.extend_from_slice(b"firmware");
+    let sha256: [u8; 32] = Sha256::digest(&image).into();
+    let install = |ota: &Ota<FileUpdater>| {
+        let mut rest = &image[..];
+        ota.install(image.len(), &sha256, |buf| {
+            Ok(std::io::Read::read(&mut rest, buf)?)
+        })
+    };
+    let open = || {
+        let updater = FileUpdater::open(&dir).unwrap();
+        Arc::new(Ota::new(updater, |_: &OtaStatus| ()))
+    };
+    let states = |health: Health| {
+        let checks: Vec<_> = health.checks.iter().map(|c| (c.name, c.passed)).collect();
+        (health.state, checks, health.rolled_back)
+    };
+
+    // Without an update, there is nothing to check.
+    let ota = open();
+    ota.verify(vec![HealthCheck::new("never", || false)], Duration::ZERO)
+        .unwrap();
+    assert_eq!(states(ota.health()), (ImageState::Valid, vec![], false));
+    install(&ota).unwrap();
+
+    // The new image is kept once all of its checks passed, and no other one
+    // can be installed before.
+    let ota = open();
+    let device = Arc::new(HostDevice::new());
+    let server = server(|router| ota::register(router, ota.clone()));
+    let mut checks = ota::health_checks(device.clone(), server.local_addr().port());
+    let ready = Arc::new(AtomicBool::new(false));
+    {
+        let ready = ready.clone();
+        checks.push(HealthCheck::new("ready", move || {
+            ready.load(Ordering::Relaxed)
+        }));
+    }
+    ota.verify(checks, Duration::from_secs(60)).unwrap();
+    let health = ota.health();
+    assert_eq!(health.state, ImageState::PendingVerify);
+    assert!(matches!(install(&ota), Err(UpdateError::Unverified)));
+
+    device.set_reading(Reading {
+        temperature_celsius: 21.0,
+        humidity_percent: 40.0,
+    });
+    ready.store(true, Ordering::Relaxed);
+    wait_for(|| ota.health().state == ImageState::Valid);
+    let response = request(
+        server.local_addr(),
+        "GET /api/v1/ota/health HTTP/1.1\r\n\r\n",
+    );
+    assert!(response.ends_with(concat!(
+        r#"{"state":"valid","checks":[{"name":"wifi","passed":true},{"name":"http","passed":true},"#,
+        r#"{"name":"sensor","passed":true},{"name":"ready","passed":true}],"rolled_back":false}"#
+    )));
+
+    // One that doesn't make it in time is rolled back.
+    install(&ota).unwrap();
+    let ota = open();
+    let checks = vec![
+        HealthCheck::new("fine", || true),
+        HealthCheck::new("broken", || false),
+    ];
+    ota.verify(checks, Duration::ZERO).unwrap();
+    wait_for(|| ota.health().state == ImageState::Failed);
+    assert_eq!(
+        states(ota.health()),
+        (
+            ImageState::Failed,
+            vec![("fine", true), ("broken", false)],
+            false
+        )
+    );
+    assert!(!FileUpdater::open(&dir).unwrap().firmware_path().exists());
+
+    let ota = open();
+    ota.verify(Vec::new(), Duration::ZERO).unwrap();
+    assert_eq!(states(ota.health()), (ImageState::Valid, vec![], true));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn ota_manifest() {
+    let dir = std::env::temp_dir().join(format!("http-server-manifest-{}", std::process::id()));
+    let updater = FileUpdater::open(&dir).unwrap();
+    let firmware = updater.firmware_path();
+    let ota = Arc::new(Ota::new(updater, |_: &OtaStatus| ()));
+
+    let mut image = vec![0xe9];
+    image.extend((0..50_000u32).map(|i| i as u8));
+    let sha256 = format!("{:x}", Sha256::digest(&image));
+    let manifest = Arc::new(Mutex::new(String::new()));
+    let server = {
+        let manifest = manifest.clone();
+        let image = image.clone();
+        server(move |router| {
+            router.get("/manifest.json", move |request, _| {
+                let mut response = request.into_ok_response()?;
+                response.write_all(manifest.lock().unwrap().as_bytes())?;
+                Ok(())
+            });
+            router.get("/firmware.bin", move |request, _| {
+                let len = image.len().to_string();
+                let mut response = request.into_response(200, None, &[("Content-Length", &len)])?;
+                response.write_all(&image)?;
+                Ok(())
+            });
+        })
+    };
+    let base = format!("http://{}", server.local_addr());
+    let set_manifest = |version: &str, sha256: &str, min_hw_revision: u32| {
+        *manifest.lock().unwrap() = format!(
+            r#"{{"version":"{version}","url":"{base}/firmware.bin","sha256":"{sha256}","min_hw_revision":{min_hw_revision}}}"#
+        );
+    };
+    let mut updates = Updates::new(HostFetcher, ota.clone(), format!("{base}/manifest.json"), 2);
+
+    set_manifest("0.0.1", &sha256, 0);
+    assert_eq!(updates.check().unwrap(), CheckOutcome::UpToDate);
+    set_manifest("99.0.0", &sha256, 3);
+    assert_eq!(
+        updates.check().unwrap(),
+        CheckOutcome::Unsupported { min_hw_revision: 3 }
+    );
+    set_manifest("99.0.0", &"0".repeat(64), 2);
+    assert!(updates.check().is_err());
+    assert!(!firmware.exists());
+    let mut missing = Updates::new(HostFetcher, ota.clone(), format!("{base}/missing.json"), 2);
+    assert!(missing.check().is_err());
+
+    set_manifest("99.0.0", &sha256, 2);
+    assert_eq!(
+        updates.check().unwrap(),
+        CheckOutcome::Installed {
+            version: "99.0.0".into()
+        }
+    );
+    assert_eq!(std::fs::read(&firmware).unwrap(), image);
+    assert_eq!(ota.status(), OtaStatus::Rebooting);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -2,7 +2,7 @@ use anyhow::{bail, Result};
 use esp_idf_hal::prelude::Peripherals;
 use esp_idf_svc::eventloop::EspSystemEventLoop;
 use log::info;
-use wifi::wifi;
+use wifi::{WifiBuilder, WifiError, WifiMode};
 // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
 use esp_idf_sys as _;
 
@@ -25,19 +25,22 @@ fn main() -> Result<()> {
 
     let app_config = CONFIG;
     // Connect to the Wi-Fi network
-    let _wifi = match wifi(
+    let _wifi = match WifiBuilder::new(WifiMode::station(
         app_config.wifi_ssid,
         app_config.wifi_psk,
-        peripherals.modem,
-        sysloop,
-    ) {
+    ))
+    .start(peripherals.modem, sysloop)
+    {
         Ok(inner) => {
             println!("Connected to Wi-Fi network!");
             inner
         }
+        Err(WifiError::AuthFailed) => {
+            bail!("Wrong password for Wi-Fi network {}", app_config.wifi_ssid)
+        }
         Err(err) => {
             // Red!
-            bail!("Could not connect to Wi-Fi network: {:?}", err)
+            bail!("Could not connect to Wi-Fi network: {}", err)
         }
     };
     Ok(())
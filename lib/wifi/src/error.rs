@@ -0,0 +1,76 @@
+use core::fmt;
+
+use esp_idf_sys::{
+    wifi_err_reason_t_WIFI_REASON_4WAY_HANDSHAKE_TIMEOUT,
+    wifi_err_reason_t_WIFI_REASON_802_1X_AUTH_FAILED, wifi_err_reason_t_WIFI_REASON_AUTH_EXPIRE,
+    wifi_err_reason_t_WIFI_REASON_AUTH_FAIL, wifi_err_reason_t_WIFI_REASON_HANDSHAKE_TIMEOUT,
+    wifi_err_reason_t_WIFI_REASON_NO_AP_FOUND, EspError, ESP_ERR_TIMEOUT,
+};
+
+#[derive(Debug)]
+pub enum WifiError {
+    /// No SSID was configured.
+    MissingSsid,
+    /// The access point with this SSID could not be found.
+    ApNotFound(String),
+    /// The access point rejected the password.
+    AuthFailed,
+    /// Connected to the access point, but no DHCP lease arrived in time.
+    DhcpTimeout,
+    /// Any other error reported by the ESP-IDF Wi-Fi driver.
+    Driver(EspError),
+}
+
+impl WifiError {
+    /// Classifies a failed connection attempt by the reason code of the last
+    /// `WIFI_EVENT_STA_DISCONNECTED` event, if any.
+    pub(crate) fn from_disconnect(err: EspError, reason: Option<u8>, ssid: &str) -> Self {
+        #[allow(non_upper_case_globals)]
+        match reason.map(u32::from) {
+            Some(
+                wifi_err_reason_t_WIFI_REASON_AUTH_EXPIRE
+                | wifi_err_reason_t_WIFI_REASON_AUTH_FAIL
+                | wifi_err_reason_t_WIFI_REASON_4WAY_HANDSHAKE_TIMEOUT
+                | wifi_err_reason_t_WIFI_REASON_HANDSHAKE_TIMEOUT
+                | wifi_err_reason_t_WIFI_REASON_802_1X_AUTH_FAILED,
+            ) => Self::AuthFailed,
+            Some(wifi_err_reason_t_WIFI_REASON_NO_AP_FOUND) => Self::ApNotFound(ssid.into()),
+            _ => Self::Driver(err),
+        }
+    }
+
+    pub(crate) fn from_dhcp(err: EspError) -> Self {
+        if err.code() == ESP_ERR_TIMEOUT as i32 {
+            Self::DhcpTimeout
+        } else {
+            Self::Driver(err)
+        }
+    }
+}
+
+impl fmt::Display for WifiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingSsid => write!(f, "Missing WiFi name"),
+            Self::ApNotFound(ssid) => write!(f, "Access point {ssid} not found"),
+            Self::AuthFailed => write!(f, "Authentication with the access point failed"),
+            Self::DhcpTimeout => write!(f, "Timed out waiting for a DHCP lease"),
+            Self::Driver(err) => write!(f, "WiFi driver error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for WifiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Driver(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<EspError> for WifiError {
+    fn from(err: EspError) -> Self {
+        Self::Driver(err)
+    }
+}
@@ -0,0 +1,41 @@
+use core::ffi;
+
+use esp_idf_svc::eventloop::{EspEventFetchData, EspTypedEventDeserializer, EspTypedEventSource};
+use esp_idf_sys::{
+    wifi_event_sta_disconnected_t, wifi_event_t_WIFI_EVENT_STA_DISCONNECTED, WIFI_EVENT,
+};
+
+/// `WIFI_EVENT_STA_DISCONNECTED` including the reason code, which
+/// `esp_idf_svc::wifi::WifiEvent` doesn't carry.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct StaDisconnected {
+    /// One of the `wifi_err_reason_t_WIFI_REASON_*` constants.
+    pub reason: u8,
+}
+
+impl EspTypedEventSource for StaDisconnected {
+    fn source() -> *const ffi::c_char {
+        unsafe { WIFI_EVENT }
+    }
+
+    fn event_id() -> Option<i32> {
+        Some(wifi_event_t_WIFI_EVENT_STA_DISCONNECTED as _)
+    }
+}
+
+impl EspTypedEventDeserializer<StaDisconnected> for StaDisconnected {
+    fn deserialize<R>(
+        data: &EspEventFetchData,
+        f: &mut impl for<'a> FnMut(&'a StaDisconnected) -> R,
+    ) -> R {
+        let event = unsafe {
+            (data.payload as *const wifi_event_sta_disconnected_t)
+                .as_ref()
+                .unwrap()
+        };
+
+        f(&StaDisconnected {
+            reason: event.reason,
+        })
+    }
+}
@@ -1,94 +1,215 @@
-use anyhow::{bail, Result};
+use core::sync::atomic::{AtomicU8, Ordering};
+use std::{ffi::CString, sync::Arc};
+
 use embedded_svc::wifi::{
     AccessPointConfiguration, AuthMethod, ClientConfiguration, Configuration,
 };
 use esp_idf_hal::peripheral;
-use esp_idf_svc::{eventloop::EspSystemEventLoop, wifi::BlockingWifi, wifi::EspWifi};
+use esp_idf_svc::{
+    eventloop::EspSystemEventLoop, handle::RawHandle, wifi::BlockingWifi, wifi::EspWifi,
+};
+use esp_idf_sys::{esp, esp_netif_set_hostname, EspError, ESP_ERR_INVALID_ARG};
 use log::info;
 
-pub fn wifi(
-    ssid: &str,
-    pass: &str,
-    modem: impl peripheral::Peripheral<P = esp_idf_hal::modem::Modem> + 'static,
-    sysloop: EspSystemEventLoop,
-    create: bool,
-) -> Result<Box<EspWifi<'static>>> {
+mod error;
+pub mod event;
+mod networks;
+mod provisioning;
+mod supervisor;
+
+pub use error::WifiError;
+pub use networks::KnownNetwork;
+pub use provisioning::start_provisioning;
+pub use supervisor::{Backoff, LinkState, WifiSupervisor};
+use event::StaDisconnected;
+
+/// What the radio should do. The station and access point are configured
+/// independently, so e.g. the AP can use a different SSID and channel than
+/// the network the station joins.
+#[derive(Clone, Debug)]
+pub enum WifiMode {
+    /// Join the best of the known networks that is in range. If a network has
+    /// no channel set, it is found by scanning.
+    Station(Vec<KnownNetwork>),
+    /// Open a network of our own.
+    AccessPoint(AccessPointConfiguration),
+    /// Both at once.
+    Mixed {
+        sta: Vec<KnownNetwork>,
+        ap: AccessPointConfiguration,
+    },
+}
 
-    let mut auth_method = AuthMethod::WPA2Personal;
+impl WifiMode {
+    /// Joins `ssid`, using WPA2 unless `pass` is empty.
+    pub fn station(ssid: &str, pass: &str) -> Self {
+        Self::Station(vec![KnownNetwork::new(ssid, pass, 0)])
+    }
 
-    if ssid.is_empty() {
-        bail!("Missing WiFi name")
+    /// Opens `ssid` on channel 1, using WPA2 unless `pass` is empty.
+    pub fn access_point(ssid: &str, pass: &str) -> Self {
+        Self::AccessPoint(AccessPointConfiguration {
+            ssid: ssid.into(),
+            password: pass.into(),
+            channel: 1,
+            auth_method: auth_method(pass),
+            ..Default::default()
+        })
     }
+}
+
+fn auth_method(pass: &str) -> AuthMethod {
     if pass.is_empty() {
-        auth_method = AuthMethod::None;
-        info!("Wifi password is empty");
+        AuthMethod::None
+    } else {
+        AuthMethod::WPA2Personal
     }
+}
 
-    let mut esp_wifi = EspWifi::new(modem, sysloop.clone(), None)?;
-    let mut wifi = BlockingWifi::wrap(&mut esp_wifi, sysloop)?;
-
-    let ap_config = AccessPointConfiguration {
-        ssid: ssid.into(),
-        password: pass.into(),
-        channel: 1,
-        auth_method,
-        ..Default::default()
-    };
+pub struct WifiBuilder {
+    mode: WifiMode,
+    hostname: Option<String>,
+}
 
-    wifi.set_configuration(&
-        if create {
-            Configuration::AccessPoint(ap_config.clone())
-        } else {
-            Configuration::Client(ClientConfiguration::default())
+impl WifiBuilder {
+    pub fn new(mode: WifiMode) -> Self {
+        Self {
+            mode,
+            hostname: None,
         }
-    )?;
+    }
 
-    info!("Starting wifi...");
-    wifi.start()?;
+    /// Name the station announces when requesting a DHCP lease, instead of
+    /// the ESP-IDF default `espressif`.
+    pub fn hostname(mut self, hostname: &str) -> Self {
+        self.hostname = Some(hostname.into());
+        self
+    }
 
-    if !create {
+    /// Starts the radio and, if there is a station, waits until it is connected
+    /// and has a DHCP lease.
+    pub fn start(
+        self,
+        modem: impl peripheral::Peripheral<P = esp_idf_hal::modem::Modem> + 'static,
+        sysloop: EspSystemEventLoop,
+    ) -> Result<Box<EspWifi<'static>>, WifiError> {
+        let mut esp_wifi = Box::new(EspWifi::new(modem, sysloop.clone(), None)?);
+        self.configure(&mut esp_wifi, sysloop)?;
+
+        Ok(esp_wifi)
+    }
 
-        info!("Scanning...");
-        let ap_infos = wifi.scan()?;
+    /// As [`WifiBuilder::start`], but (re)configures an existing driver, which
+    /// can then e.g. be handed to [`start_provisioning`] if connecting failed.
+    pub fn configure(
+        self,
+        esp_wifi: &mut EspWifi<'static>,
+        sysloop: EspSystemEventLoop,
+    ) -> Result<(), WifiError> {
+        let (sta, ap) = match self.mode {
+            WifiMode::Station(sta) => (Some(sta), None),
+            WifiMode::AccessPoint(ap) => (None, Some(ap)),
+            WifiMode::Mixed { sta, ap } => (Some(sta), Some(ap)),
+        };
 
-        let ours = ap_infos.into_iter().find(|a| a.ssid == ssid);
-        let channel = if let Some(ours) = ours {
-            info!(
-                "Found configured access point {} on channel {}",
-                ssid, ours.channel
-            );
-            Some(ours.channel)
+        if sta.iter().any(|sta| sta.is_empty())
+            || sta.iter().flatten().any(|network| network.client.ssid.is_empty())
+            || ap.iter().any(|ap| ap.ssid.is_empty())
+        {
+            return Err(WifiError::MissingSsid);
+        }
 
-        } else {
-            info!(
-                "Configured access point {} not found during scanning, will go with unknown channel",
-                ssid
-            );
-            None
+        // Remember why the station was last disconnected, so a failed connection
+        // can be reported as something more useful than a timeout.
+        let disconnect_reason = Arc::new(AtomicU8::new(0));
+        let _subscription = {
+            let disconnect_reason = disconnect_reason.clone();
+            sysloop.subscribe(move |event: &StaDisconnected| {
+                disconnect_reason.store(event.reason, Ordering::Relaxed);
+            })?
         };
 
-        wifi.set_configuration(
-            &Configuration::Mixed(
-                ClientConfiguration {
-                    ssid: ssid.into(),
-                    password: pass.into(),
-                    channel,
-                    auth_method,
-                    ..Default::default()
-                },
-                ap_config,
-            )
-        )?;
-
-        info!("Connecting wifi...");
-        wifi.connect()?;
-    }
+        let mut wifi = BlockingWifi::wrap(esp_wifi, sysloop)?;
+        if wifi.is_started()? {
+            wifi.stop()?;
+        }
+
+        if let Some(hostname) = self.hostname.filter(|_| sta.is_some()) {
+            let hostname = CString::new(hostname)
+                .map_err(|_| EspError::from_infallible::<ESP_ERR_INVALID_ARG>())?;
+            let netif = wifi.wifi().sta_netif().handle();
+            esp!(unsafe { esp_netif_set_hostname(netif, hostname.as_ptr()) })?;
+        }
+
+        // The station can only scan once the driver is started, so it starts
+        // out unconfigured.
+        wifi.set_configuration(&configuration(
+            sta.as_ref().map(|_| ClientConfiguration::default()),
+            ap.clone(),
+        ))?;
+
+        info!("Starting wifi...");
+        wifi.start()?;
+
+        if let Some(sta) = sta {
+            info!("Scanning...");
+            let ap_infos = wifi.scan()?;
+
+            let candidates = networks::rank(&sta, &ap_infos);
+            networks::connect_first(candidates, |client| {
+                let ssid = client.ssid.clone();
+
+                if client.auth_method == AuthMethod::None {
+                    info!("Wifi password for {} is empty", ssid);
+                }
+                match client.channel {
+                    Some(channel) => info!("Using access point {} on channel {}", ssid, channel),
+                    None => info!(
+                        "Access point {} not found during scanning, will go with unknown channel",
+                        ssid
+                    ),
+                }
+
+                wifi.set_configuration(&configuration(Some(client), ap.clone()))?;
+
+                info!("Connecting wifi...");
+                disconnect_reason.store(0, Ordering::Relaxed);
+                let Err(err) = wifi.connect() else {
+                    return Ok(());
+                };
+                let reason = disconnect_reason.load(Ordering::Relaxed);
+                let _ = wifi.disconnect();
+                Err(WifiError::from_disconnect(
+                    err,
+                    (reason != 0).then_some(reason),
+                    &ssid,
+                ))
+            })?;
+        }
 
-    info!("Waiting for DHCP lease...");
-    wifi.wait_netif_up()?;
+        info!("Waiting for DHCP lease...");
+        wifi.wait_netif_up().map_err(WifiError::from_dhcp)?;
 
-    let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
-    info!("Wifi DHCP info: {:?}", ip_info);
+        if wifi.wifi().driver().is_sta_enabled()? {
+            let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
+            info!("Wifi DHCP info: {:?}", ip_info);
+        } else {
+            let ip_info = wifi.wifi().ap_netif().get_ip_info()?;
+            info!("Wifi access point info: {:?}", ip_info);
+        }
 
-    Ok(Box::new(esp_wifi))
+        Ok(())
+    }
+}
+
+fn configuration(
+    sta: Option<ClientConfiguration>,
+    ap: Option<AccessPointConfiguration>,
+) -> Configuration {
+    match (sta, ap) {
+        (Some(sta), Some(ap)) => Configuration::Mixed(sta, ap),
+        (Some(sta), None) => Configuration::Client(sta),
+        (None, Some(ap)) => Configuration::AccessPoint(ap),
+        (None, None) => Configuration::None,
+    }
 }
@@ -0,0 +1,188 @@
+use core::cmp::Reverse;
+
+use embedded_svc::wifi::{AccessPointInfo, ClientConfiguration};
+use log::warn;
+
+use crate::{auth_method, WifiError};
+
+/// A network the station may join.
+#[derive(Clone, Debug)]
+pub struct KnownNetwork {
+    pub client: ClientConfiguration,
+    /// Networks with a higher priority are preferred, regardless of signal
+    /// strength.
+    pub priority: u8,
+}
+
+impl KnownNetwork {
+    /// Joins `ssid`, using WPA2 unless `pass` is empty.
+    pub fn new(ssid: &str, pass: &str, priority: u8) -> Self {
+        Self {
+            client: ClientConfiguration {
+                ssid: ssid.into(),
+                password: pass.into(),
+                auth_method: auth_method(pass),
+                ..Default::default()
+            },
+            priority,
+        }
+    }
+}
+
+/// Orders `known` networks by priority, then by the strength they were seen
+/// with in `scanned`, and fills in the channel if it isn't configured.
+///
+/// Networks that weren't seen at all go last, since they might just be hidden.
+pub(crate) fn rank(
+    known: &[KnownNetwork],
+    scanned: &[AccessPointInfo],
+) -> Vec<ClientConfiguration> {
+    let mut candidates: Vec<_> = known
+        .iter()
+        .map(|network| {
+            let strongest = scanned
+                .iter()
+                .filter(|ap| ap.ssid == network.client.ssid)
+                .max_by_key(|ap| ap.signal_strength);
+
+            let mut client = network.client.clone();
+            if client.channel.is_none() {
+                client.channel = strongest.map(|ap| ap.channel);
+            }
+
+            (
+                strongest.map(|ap| ap.signal_strength),
+                network.priority,
+                client,
+            )
+        })
+        .collect();
+
+    candidates.sort_by_key(|(signal_strength, priority, _)| {
+        (
+            Reverse(signal_strength.is_some()),
+            Reverse(*priority),
+            Reverse(*signal_strength),
+        )
+    });
+
+    candidates.into_iter().map(|(.., client)| client).collect()
+}
+
+/// Tries `candidates` in turn until `connect` succeeds. Only failures specific
+/// to a network, a rejected password or an access point that isn't there, are
+/// worth trying the next one for; any other error, or the last network
+/// failing, is returned.
+pub(crate) fn connect_first(
+    candidates: Vec<ClientConfiguration>,
+    mut connect: impl FnMut(ClientConfiguration) -> Result<(), WifiError>,
+) -> Result<(), WifiError> {
+    let count = candidates.len();
+    for (i, client) in candidates.into_iter().enumerate() {
+        let ssid = client.ssid.clone();
+        match connect(client) {
+            Ok(()) => return Ok(()),
+            Err(err @ (WifiError::AuthFailed | WifiError::ApNotFound(_))) if i + 1 < count => {
+                warn!(
+                    "Could not connect to {}: {}, trying the next network",
+                    ssid, err
+                );
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_svc::wifi::AuthMethod;
+    use esp_idf_sys::{EspError, ESP_FAIL};
+
+    use super::*;
+
+    fn seen(ssid: &str, channel: u8, signal_strength: i8) -> AccessPointInfo {
+        AccessPointInfo {
+            ssid: ssid.into(),
+            channel,
+            signal_strength,
+            ..Default::default()
+        }
+    }
+
+    fn ssids(clients: &[ClientConfiguration]) -> Vec<&str> {
+        clients.iter().map(|client| client.ssid.as_str()).collect()
+    }
+
+    #[test]
+    fn rank_prefers_seen_then_priority_then_signal() {
+        let known = [
+            KnownNetwork::new("hidden", "", 9),
+            KnownNetwork::new("weak", "password", 1),
+            KnownNetwork::new("strong", "password", 1),
+            KnownNetwork::new("preferred", "password", 2),
+        ];
+        let scanned = [
+            seen("weak", 1, -80),
+            seen("strong", 6, -70),
+            seen("strong", 11, -40),
+            seen("preferred", 3, -90),
+            seen("unknown", 1, -30),
+        ];
+
+        let ranked = rank(&known, &scanned);
+        assert_eq!(ssids(&ranked), ["preferred", "strong", "weak", "hidden"]);
+        // On the channel it was seen strongest on.
+        assert_eq!(ranked[1].channel, Some(11));
+        assert_eq!(ranked[3].channel, None);
+        assert_eq!(ranked[3].auth_method, AuthMethod::None);
+    }
+
+    #[test]
+    fn rank_keeps_a_configured_channel() {
+        let mut known = KnownNetwork::new("home", "password", 0);
+        known.client.channel = Some(13);
+
+        let ranked = rank(&[known], &[seen("home", 1, -50)]);
+        assert_eq!(ranked[0].channel, Some(13));
+    }
+
+    #[test]
+    fn connect_first_fails_over_on_network_errors() {
+        let known = [
+            KnownNetwork::new("first", "wrong", 2),
+            KnownNetwork::new("second", "password", 1),
+            KnownNetwork::new("third", "password", 0),
+        ];
+        let mut tried = Vec::new();
+        let result = connect_first(rank(&known, &[]), |client| {
+            tried.push(client.ssid.to_string());
+            match client.ssid.as_str() {
+                "first" => Err(WifiError::AuthFailed),
+                _ => Ok(()),
+            }
+        });
+
+        assert!(result.is_ok());
+        assert_eq!(tried, ["first", "second"]);
+    }
+
+    #[test]
+    fn connect_first_returns_other_errors_and_the_last_failure() {
+        let known = [
+            KnownNetwork::new("first", "password", 1),
+            KnownNetwork::new("second", "password", 0),
+        ];
+
+        let mut tried = 0;
+        let result = connect_first(rank(&known, &[]), |_| {
+            tried += 1;
+            Err(EspError::from_infallible::<ESP_FAIL>().into())
+        });
+        assert!(matches!(result, Err(WifiError::Driver(_))));
+        assert_eq!(tried, 1);
+
+        let result = connect_first(rank(&known, &[]), |_| Err(WifiError::AuthFailed));
+        assert!(matches!(result, Err(WifiError::AuthFailed)));
+    }
+}
@@ -0,0 +1,35 @@
+use embedded_svc::wifi::{AccessPointConfiguration, ClientConfiguration, Configuration};
+use esp_idf_svc::{
+    eventloop::EspSystemEventLoop,
+    wifi::{BlockingWifi, EspWifi},
+};
+use log::info;
+
+use crate::WifiError;
+
+/// Opens `ap` so the station can be set up from a phone or laptop. The
+/// station is enabled but doesn't connect, so networks can still be scanned.
+pub fn start_provisioning(
+    esp_wifi: &mut EspWifi<'static>,
+    sysloop: EspSystemEventLoop,
+    ap: AccessPointConfiguration,
+) -> Result<(), WifiError> {
+    if ap.ssid.is_empty() {
+        return Err(WifiError::MissingSsid);
+    }
+
+    let mut wifi = BlockingWifi::wrap(esp_wifi, sysloop)?;
+    if wifi.is_started()? {
+        wifi.stop()?;
+    }
+
+    wifi.set_configuration(&Configuration::Mixed(ClientConfiguration::default(), ap))?;
+
+    info!("Starting wifi provisioning...");
+    wifi.start()?;
+
+    let ip_info = wifi.wifi().ap_netif().get_ip_info()?;
+    info!("Wifi provisioning access point info: {:?}", ip_info);
+
+    Ok(())
+}
@@ -0,0 +1,336 @@
+use core::time::Duration;
+use std::{
+    sync::{mpsc, Arc, Mutex, MutexGuard},
+    thread,
+    time::Instant,
+};
+
+use embedded_svc::wifi::{ClientConfiguration, Configuration};
+use esp_idf_svc::{
+    eventloop::{EspSystemEventLoop, EspSystemSubscription},
+    netif::IpEvent,
+    wifi::{EspWifi, WifiEvent},
+};
+use esp_idf_sys::{EspError, ESP_ERR_NO_MEM, ESP_ERR_TIMEOUT, ESP_FAIL};
+use log::{info, warn};
+
+use crate::{
+    event::StaDisconnected,
+    networks::{self, KnownNetwork},
+    WifiError,
+};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LinkState {
+    /// Associated with the access point and holding a DHCP lease.
+    Up,
+    /// Lost the connection, `reason` is one of the
+    /// `wifi_err_reason_t_WIFI_REASON_*` constants.
+    Down { reason: u8 },
+    /// Waiting out the backoff of reconnection attempt number `attempt`.
+    Reconnecting { attempt: u32 },
+}
+
+/// Delay between reconnection attempts, doubling from `initial` up to `max`.
+#[derive(Copy, Clone, Debug)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Backoff {
+    /// Delay before attempt number `attempt` (starting at 1), with up to half
+    /// of it randomized so a fleet of devices doesn't reconnect in lockstep
+    /// after the access point reboots.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .initial
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+            .min(self.max);
+        let jitter = delay.as_millis() as u32 / 2;
+        let random = unsafe { esp_idf_sys::esp_random() };
+
+        delay - Duration::from_millis(u64::from(random % (jitter + 1)))
+    }
+}
+
+/// Longest a scan for the access point may take.
+const SCAN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest the access point may take to accept or reject us.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+enum Event {
+    Up,
+    /// With the time it arrived.
+    Disconnected {
+        reason: u8,
+        at: Instant,
+    },
+}
+
+/// What the driver reports while [`reconnect`] waits on it.
+enum Radio {
+    ScanDone,
+    Connected,
+    Disconnected { reason: u8 },
+}
+
+/// Keeps the station connected: reconnects with [`Backoff`] whenever the
+/// access point goes away, rescanning first to join the best of the known
+/// networks in range, as [`WifiBuilder`](crate::WifiBuilder) does, or the
+/// same one on another channel.
+pub struct WifiSupervisor {
+    wifi: Arc<Mutex<Box<EspWifi<'static>>>>,
+    state: Arc<Mutex<LinkState>>,
+    _disconnect_subscription: EspSystemSubscription,
+    _ip_subscription: EspSystemSubscription,
+    _radio_subscription: EspSystemSubscription,
+}
+
+impl WifiSupervisor {
+    /// Without `networks`, the station keeps to the one it was configured
+    /// with.
+    pub fn start(
+        wifi: Box<EspWifi<'static>>,
+        sysloop: &EspSystemEventLoop,
+        networks: Vec<KnownNetwork>,
+        backoff: Backoff,
+    ) -> Result<Self, WifiError> {
+        let (tx, rx) = mpsc::channel();
+        let (radio_tx, radio) = mpsc::channel();
+
+        let disconnect_subscription = {
+            let (tx, radio_tx) = (tx.clone(), radio_tx.clone());
+            sysloop.subscribe(move |event: &StaDisconnected| {
+                let _ = tx.send(Event::Disconnected {
+                    reason: event.reason,
+                    at: Instant::now(),
+                });
+                let _ = radio_tx.send(Radio::Disconnected {
+                    reason: event.reason,
+                });
+            })?
+        };
+        let ip_subscription = {
+            let tx = tx.clone();
+            sysloop.subscribe(move |event: &IpEvent| {
+                if let IpEvent::DhcpIpAssigned(_) = event {
+                    let _ = tx.send(Event::Up);
+                }
+            })?
+        };
+        let radio_subscription = sysloop.subscribe(move |event: &WifiEvent| {
+            let _ = match event {
+                WifiEvent::ScanDone => radio_tx.send(Radio::ScanDone),
+                WifiEvent::StaConnected => radio_tx.send(Radio::Connected),
+                _ => Ok(()),
+            };
+        })?;
+
+        // The link may have dropped before we subscribed.
+        if wifi.driver().is_sta_enabled()? && !wifi.sta_netif().is_up()? {
+            let _ = tx.send(Event::Disconnected {
+                reason: 0,
+                at: Instant::now(),
+            });
+        }
+
+        let wifi = Arc::new(Mutex::new(wifi));
+        let state = Arc::new(Mutex::new(LinkState::Up));
+
+        {
+            let wifi = wifi.clone();
+            let state = state.clone();
+            thread::Builder::new()
+                .name("wifi-supervisor".into())
+                .stack_size(4096)
+                .spawn(move || supervise(&wifi, &state, &networks, &rx, &radio, backoff))
+                .map_err(|_| EspError::from_infallible::<{ ESP_ERR_NO_MEM as i32 }>())?;
+        }
+
+        Ok(Self {
+            wifi,
+            state,
+            _disconnect_subscription: disconnect_subscription,
+            _ip_subscription: ip_subscription,
+            _radio_subscription: radio_subscription,
+        })
+    }
+
+    pub fn state(&self) -> LinkState {
+        *self.state.lock().unwrap()
+    }
+
+    pub fn wifi(&self) -> MutexGuard<'_, Box<EspWifi<'static>>> {
+        self.wifi.lock().unwrap()
+    }
+}
+
+/// Runs until the supervisor is dropped, which closes the channel.
+fn supervise(
+    wifi: &Mutex<Box<EspWifi<'static>>>,
+    state: &Mutex<LinkState>,
+    networks: &[KnownNetwork],
+    events: &mpsc::Receiver<Event>,
+    radio: &mpsc::Receiver<Radio>,
+    backoff: Backoff,
+) {
+    let mut attempt = 0;
+    // When the connection attempt in progress was started.
+    let mut started = None;
+
+    while let Ok(event) = events.recv() {
+        match event {
+            Event::Up => {
+                info!("Wifi link is up");
+                attempt = 0;
+                *state.lock().unwrap() = LinkState::Up;
+            }
+            // Queued up while we were waiting to reconnect, superseded by the
+            // attempt in progress, whose outcome is yet to arrive.
+            Event::Disconnected { at, .. } if started.is_some_and(|started| at < started) => (),
+            Event::Disconnected { reason, .. } => {
+                warn!("Wifi disconnected (reason {reason})");
+                *state.lock().unwrap() = LinkState::Down { reason };
+
+                loop {
+                    attempt += 1;
+                    *state.lock().unwrap() = LinkState::Reconnecting { attempt };
+
+                    let delay = backoff.delay(attempt);
+                    info!("Reconnecting wifi in {delay:?} (attempt {attempt})");
+                    thread::sleep(delay);
+
+                    match reconnect(wifi, networks, radio) {
+                        Ok(at) => {
+                            started = Some(at);
+                            break;
+                        }
+                        Err(err) => warn!("Reconnecting wifi failed: {err}"),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Rescans and connects to the best of `networks` in range, or if there are
+/// none, to the configured access point, so a changed channel is picked up.
+/// Networks that turn out to be gone or to reject the password are skipped
+/// for the next one. Returns when the successful attempt was started; the
+/// DHCP lease arrives as an event.
+///
+/// `wifi` is only locked around the calls into the driver, not while waiting
+/// for the scan or the access point, so others can still look at the link
+/// meanwhile.
+fn reconnect(
+    wifi: &Mutex<Box<EspWifi<'static>>>,
+    networks: &[KnownNetwork],
+    radio: &mpsc::Receiver<Radio>,
+) -> Result<Instant, WifiError> {
+    let configuration = wifi.lock().unwrap().get_configuration()?;
+    let configured = match &configuration {
+        Configuration::Client(client) | Configuration::Mixed(client, _) => client.clone(),
+        _ => {
+            let started = Instant::now();
+            wifi.lock().unwrap().connect()?;
+            return Ok(started);
+        }
+    };
+    let configured = [KnownNetwork {
+        client: ClientConfiguration {
+            channel: None,
+            ..configured
+        },
+        priority: 0,
+    }];
+    let networks = if networks.is_empty() {
+        &configured[..]
+    } else {
+        networks
+    };
+
+    // Left over from scans and attempts by others.
+    for _ in radio.try_iter() {}
+    wifi.lock()
+        .unwrap()
+        .start_scan(&Default::default(), false)?;
+    let scanned = wait_for(radio, SCAN_TIMEOUT, |event| {
+        matches!(event, Radio::ScanDone).then_some(())
+    });
+    if scanned.is_none() {
+        let _ = wifi.lock().unwrap().stop_scan();
+        return Err(EspError::from_infallible::<{ ESP_ERR_TIMEOUT as i32 }>().into());
+    }
+    let ap_infos = wifi.lock().unwrap().get_scan_result()?;
+
+    let mut started = Instant::now();
+    networks::connect_first(networks::rank(networks, &ap_infos), |client| {
+        let ssid = client.ssid.clone();
+        info!(
+            "Connecting to access point {} on channel {:?}",
+            ssid, client.channel
+        );
+        {
+            let mut wifi = wifi.lock().unwrap();
+            wifi.set_configuration(&with_client(&configuration, client))?;
+            for _ in radio.try_iter() {}
+            started = Instant::now();
+            wifi.connect()?;
+        }
+
+        let outcome = wait_for(radio, CONNECT_TIMEOUT, |event| match event {
+            Radio::Connected => Some(None),
+            Radio::Disconnected { reason } => Some(Some(reason)),
+            Radio::ScanDone => None,
+        });
+        match outcome {
+            Some(None) => Ok(()),
+            Some(Some(reason)) => Err(WifiError::from_disconnect(
+                EspError::from_infallible::<ESP_FAIL>(),
+                Some(reason),
+                &ssid,
+            )),
+            None => {
+                let _ = wifi.lock().unwrap().disconnect();
+                Err(EspError::from_infallible::<{ ESP_ERR_TIMEOUT as i32 }>().into())
+            }
+        }
+    })?;
+
+    Ok(started)
+}
+
+/// `configuration` with the station joining `client` instead.
+fn with_client(configuration: &Configuration, client: ClientConfiguration) -> Configuration {
+    match configuration {
+        Configuration::Mixed(_, ap) => Configuration::Mixed(client, ap.clone()),
+        _ => Configuration::Client(client),
+    }
+}
+
+/// Waits up to `timeout` for the first event that `pick` takes something
+/// from, dropping the others.
+fn wait_for<T>(
+    radio: &mpsc::Receiver<Radio>,
+    timeout: Duration,
+    mut pick: impl FnMut(Radio) -> Option<T>,
+) -> Option<T> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let left = deadline.checked_duration_since(Instant::now())?;
+        if let Some(picked) = pick(radio.recv_timeout(left).ok()?) {
+            return Some(picked);
+        }
+    }
+}